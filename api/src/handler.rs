@@ -1,4 +1,4 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use async_trait::async_trait;
 use axum::{
@@ -7,10 +7,21 @@ use axum::{
 };
 use log::{error, info};
 use pubg::{
-    input::kafka::{KafkaSourceConfig, KafkaSourceMeta},
-    sink::kafka::{check_dst_cfg, DstConfigReq, KafkaDstConfig, KafkaDstMeta},
-    task::{dispatch_tasking, task_running},
-    CloseTask,
+    core::{redact_secrets, restore_secrets},
+    input::encoding::{default_max_decoded_bytes, ContentEncoding},
+    input::kafka::{
+        reset_offsets as reset_kafka_offsets, KafkaSourceConfig, KafkaSourceMeta, StartFrom,
+    },
+    kafka::KafkaSecurity,
+    metrics::{task_metrics, TaskMetrics},
+    plugin::PluginInfo,
+    sink::kafka::{check_dst_cfg, KafkaDstConfig, KafkaDstMeta},
+    task::{dispatch_tasking, task_running, TaskDst},
+    transform::computed::Computed,
+    transform::record::RecordMode,
+    transform::redact::{pii_report, PiiReport},
+    transform::script::{Script, ScriptConfig},
+    CloseTask, DST_PLUGIN, SRC_PLUGIN,
 };
 use schema::{
     task::{get_running_task, update_task_status, Task, TaskStatus},
@@ -56,12 +67,17 @@ pub struct NewTaskRequest {
     pub src_type: String,
     // src config json format
     pub src_cfg: serde_json::Value,
-    // dst type like kafka
+    // dst type like kafka, empty when dsts is set
+    #[serde(default)]
     pub dst_type: String,
     /// dst cfg json format
+    #[serde(default)]
     pub dst_cfg: serde_json::Value,
     // json format
     pub tasking_cfg: serde_json::Value,
+    // sinks fed with the same flattened stream
+    #[serde(default)]
+    pub dsts: Vec<TaskDst>,
 }
 
 /// create task
@@ -75,9 +91,13 @@ pub async fn create_task(
     state: State<AppState>,
     Json(req): Json<NewTaskRequest>,
 ) -> Whortleberry<Option<Task>> {
-    info!("create task req {:?}", req);
+    info!("create task req {}", redact_secrets(&serde_json::json!(req)));
     // check  src type is  kafka
-    if req.src_type != "kafka" {
+    if !SRC_PLUGIN
+        .lock()
+        .unwrap()
+        .contains_key(req.src_type.as_str())
+    {
         error!("not support src type {}", req.src_type);
         return Whortleberry {
             err_msg: format!("not support src type  {}", req.src_type),
@@ -86,18 +106,35 @@ pub async fn create_task(
         };
     }
 
-    // check dst _ type is kafka
-    if req.dst_type != "kafka" {
-        error!("not support dst type {}", req.dst_type);
-        return Whortleberry {
-            err_msg: format!("not support dst type  {}", req.dst_type),
-            err_no: 10_006,
-            data: None,
-        };
+    let dsts = match request_dsts(&req.dst_type, &req.dst_cfg, &req.dsts) {
+        Ok(v) => v,
+        Err(err) => {
+            return Whortleberry {
+                err_msg: err,
+                err_no: 400,
+                data: None,
+            }
+        }
+    };
+
+    // check dst _ type is registered
+    for dst in &dsts {
+        if !DST_PLUGIN
+            .lock()
+            .unwrap()
+            .contains_key(dst.dst_type.as_str())
+        {
+            error!("not support dst type {}", dst.dst_type);
+            return Whortleberry {
+                err_msg: format!("not support dst type  {}", dst.dst_type),
+                err_no: 10_006,
+                data: None,
+            };
+        }
     }
 
     // src cfg
-    if let Err(err) = serde_json::from_value::<KafkaSrcCfg>(req.src_cfg.clone()) {
+    if let Err(err) = check_task_src_cfg(&req.src_type, &req.src_cfg) {
         error!("invalid src cfg expected json config {:?}", err);
         return Whortleberry {
             err_no: 400,
@@ -107,16 +144,10 @@ pub async fn create_task(
     }
 
     // dst config
-    if let Err(err) = serde_json::from_value::<DstConfigReq>(req.dst_cfg.clone()) {
-        error!(
-            "invalid dst cfg expected {:?} json format {:?}",
-            req.dst_cfg, err
-        );
+    if let Err(err) = check_task_dsts(&dsts) {
+        error!("invalid dst cfg {:?}", err);
         return Whortleberry {
-            err_msg: format!(
-                "invalid dst cfg expected json format but get {:?} error{:?}",
-                req.dst_cfg, err
-            ),
+            err_msg: format!("invalid dst cfg error{:?}", err),
             err_no: 400,
             data: None,
         };
@@ -135,6 +166,24 @@ pub async fn create_task(
         };
     }
 
+    if let Err(err) = pubg::dead_letter::check_dead_letter_cfg(&req.tasking_cfg) {
+        error!("invalid dead letter for tasking {:?}", err);
+        return Whortleberry {
+            err_msg: err,
+            err_no: 400,
+            data: None,
+        };
+    }
+
+    if let Err(err) = pubg::transform::check_transforms(&req.tasking_cfg) {
+        error!("invalid transforms for tasking {:?}", err);
+        return Whortleberry {
+            err_msg: err,
+            err_no: 400,
+            data: None,
+        };
+    }
+
     let mut task = schema::task::Task::from_task_detail(
         &req.name,
         &req.src_type,
@@ -142,9 +191,10 @@ pub async fn create_task(
         &req.src_cfg,
         &req.dst_cfg,
         &req.tasking_cfg,
+        &serde_json::json!(req.dsts),
     );
 
-    info!("task is {:?}", task);
+    info!("task is {:?}", redacted_task(&task));
 
     match schema::task::create_task(&state.conn, &mut task).await {
         Ok(_) => {}
@@ -161,7 +211,7 @@ pub async fn create_task(
     Whortleberry {
         err_msg: "success".to_owned(),
         err_no: 10_000,
-        data: Some(task),
+        data: Some(redacted_task(&task)),
     }
 }
 
@@ -173,7 +223,11 @@ pub struct ConnectTestingRequest {
 
 // connect
 pub async fn connect_testing(Json(req): Json<ConnectTestingRequest>) -> Whortleberry<String> {
-    info!("testing..... connect {:?}", req);
+    info!(
+        "testing..... connect {} {}",
+        req.detect_type,
+        redact_secrets(&req.cfg)
+    );
     if req.detect_type != "kafka" {
         return Whortleberry {
             err_msg: format!("not support {}", req.detect_type),
@@ -235,7 +289,7 @@ pub async fn fetch_task_list(
     Whortleberry {
         err_msg: "".to_owned(),
         err_no: 10000,
-        data: res,
+        data: res.iter().map(redacted_task).collect(),
     }
 }
 
@@ -257,6 +311,51 @@ pub async fn fetch_task(
             }
         }
         Ok(res) => Whortleberry {
+            err_no: 10_000,
+            err_msg: "success".to_owned(),
+            data: Some(redacted_task(&res)),
+        },
+    }
+}
+
+/// every src and dst, built in or loaded from the plugin dir
+pub async fn fetch_plugin_list() -> Whortleberry<Vec<PluginInfo>> {
+    Whortleberry {
+        err_no: 10_000,
+        err_msg: "success".to_owned(),
+        data: pubg::plugin::plugin_list(),
+    }
+}
+
+/// pii the redact transform found in a task, per flattened path and kind
+pub async fn fetch_pii_report(
+    Path(req): Path<FetchTaskRequest>,
+) -> Whortleberry<Option<PiiReport>> {
+    match pii_report(&req.task_id) {
+        None => Whortleberry {
+            err_msg: "no pii found since startup".to_owned(),
+            err_no: 10_200,
+            data: None,
+        },
+        Some(res) => Whortleberry {
+            err_no: 10_000,
+            err_msg: "success".to_owned(),
+            data: Some(res),
+        },
+    }
+}
+
+// delivery counters of a task since it was last started
+pub async fn fetch_task_metrics(
+    Path(req): Path<FetchTaskRequest>,
+) -> Whortleberry<Option<TaskMetrics>> {
+    match task_metrics(&req.task_id) {
+        None => Whortleberry {
+            err_msg: "task has not run since startup".to_owned(),
+            err_no: 10_200,
+            data: None,
+        },
+        Some(res) => Whortleberry {
             err_no: 10_000,
             err_msg: "success".to_owned(),
             data: Some(res),
@@ -303,15 +402,33 @@ pub struct UpdateTaskRequest {
     pub src_type: String,
     // src config json format
     pub src_cfg: serde_json::Value,
-    // dst type like kafka
+    // dst type like kafka, empty when dsts is set
+    #[serde(default)]
     pub dst_type: String,
     /// dst cfg json format
+    #[serde(default)]
     pub dst_cfg: serde_json::Value,
     /// tasking cfg json format
     pub tasking_cfg: serde_json::Value,
+    /// sinks fed with the same flattened stream
+    #[serde(default)]
+    pub dsts: Vec<TaskDst>,
 }
 
 impl UpdateTaskRequest {
+    // put back the credentials of saved the request still has masked
+    fn restore_secrets(&mut self, saved: &Task) {
+        let parse = |v: &String| serde_json::from_str::<serde_json::Value>(v).unwrap_or_default();
+        restore_secrets(&mut self.src_cfg, &parse(&saved.src_cfg));
+        restore_secrets(&mut self.dst_cfg, &parse(&saved.dst_cfg));
+        restore_secrets(&mut self.tasking_cfg, &parse(&saved.tasking_cfg));
+        let mut dsts = serde_json::json!(self.dsts);
+        restore_secrets(&mut dsts, &parse(&saved.dsts));
+        if let Ok(v) = serde_json::from_value(dsts) {
+            self.dsts = v;
+        }
+    }
+
     pub fn to_task(&self) -> Task {
         let mut task = Task::default();
         task.id = self.id.clone();
@@ -321,6 +438,7 @@ impl UpdateTaskRequest {
         task.src_type = self.src_type.to_string();
         task.dst_type = self.dst_type.to_string();
         task.tasking_cfg = self.tasking_cfg.to_string();
+        task.dsts = serde_json::json!(self.dsts).to_string();
         return task;
     }
 }
@@ -328,7 +446,7 @@ impl UpdateTaskRequest {
 // update task
 pub async fn update_task(
     state: State<AppState>,
-    Json(req): Json<UpdateTaskRequest>,
+    Json(mut req): Json<UpdateTaskRequest>,
 ) -> Whortleberry<Option<String>> {
     // check task is running....
     if pubg::task::task_running(&req.id).await {
@@ -340,8 +458,14 @@ pub async fn update_task(
         };
     }
 
+    // credentials the api handed out masked are kept as saved
+    let saved = schema::task::fetch_task(&state.conn, &req.id).await.ok();
+    if let Some(saved) = &saved {
+        req.restore_secrets(saved);
+    }
+
     // src cfg error
-    if let Err(err) = serde_json::from_value::<KafkaSrcCfg>(req.src_cfg.clone()) {
+    if let Err(err) = check_task_src_cfg(&req.src_type, &req.src_cfg) {
         error!("invalid src cfg expected json config {:?}", err);
         return Whortleberry {
             err_no: 400,
@@ -351,12 +475,14 @@ pub async fn update_task(
     }
 
     // check dst config
-    match pubg::sink::kafka::check_dst_cfg(&req.dst_cfg) {
+    match request_dsts(&req.dst_type, &req.dst_cfg, &req.dsts)
+        .and_then(|dsts| check_task_dsts(&dsts))
+    {
         Ok(_) => (),
         Err(err) => {
-            error!("update task dst cfg {} error{:?}", &req.src_cfg, err);
+            error!("update task {} dst cfg error{:?}", req.id, err);
             return Whortleberry {
-                err_msg: format!("invalid dst cfg  {} error:{:?}", req.src_cfg, err),
+                err_msg: format!("invalid dst cfg error:{:?}", err),
                 err_no: 400,
                 data: None,
             };
@@ -367,15 +493,35 @@ pub async fn update_task(
     match check_chrysaetos_bit_cfg(&req.tasking_cfg) {
         Ok(_) => (),
         Err(err) => {
-            error!("update task tasking cfg {} error{:?}", &req.src_cfg, err);
+            error!("update task {} tasking cfg error{:?}", req.id, err);
             return Whortleberry {
-                err_msg: format!("invalid tasking cfg  {} error:{:?}", req.src_cfg, err),
+                err_msg: format!("invalid tasking cfg error:{:?}", err),
                 err_no: 400,
                 data: None,
             };
         }
     }
+    if let Err(err) = pubg::dead_letter::check_dead_letter_cfg(&req.tasking_cfg) {
+        error!("update task dead letter error {:?}", err);
+        return Whortleberry {
+            err_msg: err,
+            err_no: 400,
+            data: None,
+        };
+    }
+    if let Err(err) = pubg::transform::check_transforms(&req.tasking_cfg) {
+        error!("update task transforms error {:?}", err);
+        return Whortleberry {
+            err_msg: err,
+            err_no: 400,
+            data: None,
+        };
+    }
     let mut task = req.to_task();
+    // a task saved before dsts stays one until it is given dsts, see task_dsts
+    if req.dsts.is_empty() && saved.is_some_and(|v| v.dsts.is_empty()) {
+        task.dsts = String::new();
+    }
     match schema::task::update_task(&state.conn, &mut task).await {
         Err(err) => {
             return Whortleberry {
@@ -388,7 +534,7 @@ pub async fn update_task(
     }
     // update task
     // check task is ok
-    info!("req ...{:?}", req);
+    info!("update task {}", req.id);
     Whortleberry {
         err_msg: "success".to_owned(),
         err_no: 10_000,
@@ -431,8 +577,8 @@ pub async fn start_tasking(
         }
     };
 
-    info!("data is {:?}", task);
-    let src_cfg: KafkaSrcCfg = match serde_json::from_str::<KafkaSrcCfg>(&task.src_cfg.as_str()) {
+    info!("data is {:?}", redacted_task(&task));
+    let src_cfg = match task_src_cfg(&task) {
         Ok(v) => v,
         Err(err) => {
             error!(
@@ -447,17 +593,7 @@ pub async fn start_tasking(
         }
     };
 
-    let kafka_src_cfg = KafkaSourceConfig {
-        broker: src_cfg.broker.clone(),
-        group_id: format!("verb-{}", task.id.to_owned()),
-        decoder: src_cfg.decoder.to_owned(),
-        topic: src_cfg.topic.to_owned(),
-        meta: KafkaSourceMeta {
-            task_id: task.id.to_owned(),
-        },
-    };
-
-    let dst_cfg = match check_dst_cfg(&serde_json::from_str(&task.dst_cfg).unwrap()) {
+    let dsts = match task_dsts(&task) {
         Ok(v) => v,
         Err(err) => {
             error!(
@@ -471,25 +607,19 @@ pub async fn start_tasking(
             };
         }
     };
-    let kafka_sink_cfg = KafkaDstConfig {
-        broker: dst_cfg.broker.to_owned(),
-        topic: dst_cfg.topic.to_owned(),
-        encoder: "json".to_owned(),
-        meta: KafkaDstMeta {
-            task_id: task.id.to_owned(),
-        },
-    };
 
     info!(
-        "task {} src_cfg {:#?} dst_cfg {:#?}",
-        task.id, kafka_src_cfg, kafka_sink_cfg
+        "task {} src_cfg {} dsts {}",
+        task.id,
+        redact_secrets(&src_cfg),
+        redact_secrets(&serde_json::json!(dsts))
     );
     dispatch_tasking(
         task.id.to_owned(),
         task.src_type.to_owned(),
-        &serde_json::json!(&kafka_src_cfg),
-        task.dst_type.to_owned(),
-        &serde_json::json!(&kafka_sink_cfg),
+        &src_cfg,
+        &dsts,
+        &task_tasking_cfg(&task),
         Box::new(CloseTaskImpl {}),
     )
     .await;
@@ -524,29 +654,18 @@ pub async fn continue_running_task() {
     if let Ok(task_list) = get_running_task().await {
         for task in &task_list {
             info!("continue running task {}", task.id.clone());
-            let src_cfg: KafkaSrcCfg =
-                match serde_json::from_str::<KafkaSrcCfg>(&task.src_cfg.as_str()) {
-                    Ok(v) => v,
-                    Err(err) => {
-                        error!(
-                            "failed to un marshal src cfg {:?} error{:?}",
-                            task.src_cfg, err
-                        );
-                        continue;
-                    }
-                };
-
-            let kafka_src_cfg = KafkaSourceConfig {
-                broker: src_cfg.broker.clone(),
-                group_id: format!("verb-{}", task.id.to_owned()),
-                decoder: src_cfg.decoder.to_owned(),
-                topic: src_cfg.topic.to_owned(),
-                meta: KafkaSourceMeta {
-                    task_id: task.id.to_owned(),
-                },
+            let src_cfg = match task_src_cfg(task) {
+                Ok(v) => v,
+                Err(err) => {
+                    error!(
+                        "failed to un marshal src cfg {:?} error{:?}",
+                        task.src_cfg, err
+                    );
+                    continue;
+                }
             };
 
-            let dst_cfg = match check_dst_cfg(&serde_json::from_str(&task.dst_cfg).unwrap()) {
+            let dsts = match task_dsts(task) {
                 Ok(v) => v,
                 Err(err) => {
                     error!(
@@ -556,31 +675,131 @@ pub async fn continue_running_task() {
                     continue;
                 }
             };
-            let kafka_sink_cfg = KafkaDstConfig {
-                broker: dst_cfg.broker.to_owned(),
-                topic: dst_cfg.topic.to_owned(),
-                encoder: "json".to_owned(),
-                meta: KafkaDstMeta {
-                    task_id: task.id.to_owned(),
-                },
-            };
 
             info!(
-                "task {} src_cfg {:#?} dst_cfg {:#?}",
-                task.id, kafka_src_cfg, kafka_sink_cfg
+                "task {} src_cfg {} dsts {}",
+                task.id,
+                redact_secrets(&src_cfg),
+                redact_secrets(&serde_json::json!(dsts))
             );
             dispatch_tasking(
                 task.id.to_owned(),
                 task.src_type.to_owned(),
-                &serde_json::json!(&kafka_src_cfg),
-                task.dst_type.to_owned(),
-                &serde_json::json!(&kafka_sink_cfg),
+                &src_cfg,
+                &dsts,
+                &task_tasking_cfg(task),
                 Box::new(CloseTaskImpl {}),
             )
             .await;
         }
     }
 }
+
+// sinks of a task request, dsts when set otherwise the single dst_type sink
+fn request_dsts(
+    dst_type: &String,
+    dst_cfg: &serde_json::Value,
+    dsts: &[TaskDst],
+) -> Result<Vec<TaskDst>, String> {
+    if dsts.is_empty() {
+        return Ok(vec![TaskDst {
+            dst_type: dst_type.to_owned(),
+            dst_cfg: dst_cfg.clone(),
+            tasking_cfg: None,
+        }]);
+    }
+    if !dst_type.is_empty() {
+        return Err("set either dst_type or dsts".to_owned());
+    }
+    Ok(dsts.to_vec())
+}
+
+// check the cfg and own tasking cfg of each sink
+fn check_task_dsts(dsts: &[TaskDst]) -> Result<(), String> {
+    for dst in dsts {
+        pubg::sink::check_dst_cfg(&dst.dst_type, &dst.dst_cfg)
+            .map_err(|err| format!("dst {} {}", dst.dst_type, err))?;
+        if let Some(tasking_cfg) = &dst.tasking_cfg {
+            check_chrysaetos_bit_cfg(tasking_cfg)
+                .and_then(|_| pubg::transform::check_transforms(tasking_cfg))
+                .map_err(|err| format!("dst {} tasking cfg {}", dst.dst_type, err))?;
+        }
+    }
+    Ok(())
+}
+
+// runtime sinks of a task, kafka ones get their meta filled in
+fn task_dsts(task: &Task) -> Result<Vec<TaskDst>, String> {
+    // tasks saved before dsts have only dst_type
+    let mut dsts = match task.dsts.is_empty() {
+        true => vec![],
+        false => match serde_json::from_str::<Vec<TaskDst>>(&task.dsts) {
+            Ok(v) => v,
+            Err(err) => return Err(format!("invalid dsts error {:?}", err)),
+        },
+    };
+    if dsts.is_empty() {
+        let conf = match serde_json::from_str::<serde_json::Value>(&task.dst_cfg) {
+            Ok(v) => v,
+            Err(err) => return Err(format!("{:?}", err)),
+        };
+        // kafka tasks saved before dsts keep the flatten defaults they ran with
+        let tasking_cfg = match task.dsts.is_empty() && task.dst_type == "kafka" {
+            true => Some(pubg::sink::kafka::legacy_tasking_cfg(&task_tasking_cfg(task))),
+            false => None,
+        };
+        dsts.push(TaskDst {
+            dst_type: task.dst_type.to_owned(),
+            dst_cfg: conf,
+            tasking_cfg,
+        });
+    }
+    for dst in dsts.iter_mut() {
+        if dst.dst_type == "kafka" {
+            dst.dst_cfg = kafka_dst_cfg(&task.id, &dst.dst_cfg)?;
+        }
+    }
+    Ok(dsts)
+}
+
+// kafka dst cfg with the task meta filled in
+fn kafka_dst_cfg(task_id: &String, conf: &serde_json::Value) -> Result<serde_json::Value, String> {
+    let dst_cfg = check_dst_cfg(conf)?;
+    let kafka_sink_cfg = KafkaDstConfig {
+        broker: dst_cfg.broker.to_owned(),
+        topic: dst_cfg.topic.to_owned(),
+        encoder: dst_cfg.encoder.to_owned(),
+        avro: dst_cfg.avro.clone(),
+        meta: KafkaDstMeta {
+            task_id: task_id.to_owned(),
+        },
+        properties: dst_cfg.properties.clone(),
+        security: dst_cfg.security.clone(),
+        key: dst_cfg.key.clone(),
+        producer: dst_cfg.producer.clone(),
+    };
+    Ok(serde_json::json!(&kafka_sink_cfg))
+}
+
+// task with the credentials of its cfgs masked, for logs and api output
+fn redacted_task(task: &Task) -> Task {
+    let redact = |v: &String| match serde_json::from_str::<serde_json::Value>(v) {
+        Ok(v) => redact_secrets(&v).to_string(),
+        Err(_) => v.to_owned(),
+    };
+    let mut task = task.clone();
+    task.src_cfg = redact(&task.src_cfg);
+    task.dst_cfg = redact(&task.dst_cfg);
+    task.tasking_cfg = redact(&task.tasking_cfg);
+    task.dsts = redact(&task.dsts);
+    task
+}
+
+// tasking cfg of a task, null if it is not json
+fn task_tasking_cfg(task: &Task) -> serde_json::Value {
+    serde_json::from_str::<serde_json::Value>(&task.tasking_cfg).unwrap_or_default()
+}
+
 struct CloseTaskImpl {}
 
 #[async_trait]
@@ -606,7 +825,164 @@ struct KafkaSrcCfg {
     /// broker
     pub broker: String, // broker
     /// topic
+    #[serde(default)]
     pub topic: String, // topic
+    /// topic list or ^regex subscriptions
+    #[serde(default)]
+    pub topics: Vec<String>,
+    /// start position of a new task
+    #[serde(default)]
+    pub start_from: StartFrom,
+    /// field name of the originating topic
+    #[serde(default)]
+    pub topic_field: String,
+    /// pass-through librdkafka properties
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+    /// sasl/ssl settings
+    #[serde(default)]
+    pub security: KafkaSecurity,
+    /// payload encodings like ["base64", "gzip"]
+    #[serde(default)]
+    pub content_encoding: Vec<ContentEncoding>,
+    /// limit of the decoded payload
+    #[serde(default = "default_max_decoded_bytes")]
+    pub max_decoded_bytes: usize,
+}
+
+impl KafkaSrcCfg {
+    fn to_source_config(&self, task_id: &String) -> KafkaSourceConfig {
+        KafkaSourceConfig {
+            broker: self.broker.clone(),
+            group_id: kafka_group_id(task_id),
+            decoder: self.decoder.to_owned(),
+            topic: self.topic.to_owned(),
+            topics: self.topics.clone(),
+            meta: KafkaSourceMeta {
+                task_id: task_id.to_owned(),
+            },
+            start_from: self.start_from.clone(),
+            topic_field: self.topic_field.to_owned(),
+            properties: self.properties.clone(),
+            security: self.security.clone(),
+            content_encoding: self.content_encoding.clone(),
+            max_decoded_bytes: self.max_decoded_bytes,
+        }
+    }
+}
+
+// check src cfg of a task, kafka or a plugin src
+fn check_task_src_cfg(src_type: &String, cfg: &serde_json::Value) -> Result<(), String> {
+    if src_type == "kafka" {
+        return check_kafka_src_cfg(cfg).map(|_| ());
+    }
+    pubg::input::check_src_cfg(src_type, cfg)
+}
+
+// src cfg a task is dispatched with, kafka gets its meta filled in
+fn task_src_cfg(task: &Task) -> Result<serde_json::Value, String> {
+    if task.src_type != "kafka" {
+        return serde_json::from_str(&task.src_cfg).map_err(|err| format!("{:?}", err));
+    }
+    match serde_json::from_str::<KafkaSrcCfg>(&task.src_cfg) {
+        Ok(v) => Ok(serde_json::json!(v.to_source_config(&task.id))),
+        Err(err) => Err(format!("{:?}", err)),
+    }
+}
+
+// check src cfg of a kafka task
+fn check_kafka_src_cfg(cfg: &serde_json::Value) -> Result<KafkaSrcCfg, String> {
+    let src_cfg = match serde_json::from_value::<KafkaSrcCfg>(cfg.clone()) {
+        Ok(v) => v,
+        Err(err) => return Err(format!("{:?}", err)),
+    };
+    src_cfg.to_source_config(&"".to_owned()).check()?;
+    Ok(src_cfg)
+}
+
+// consumer group of a kafka task
+fn kafka_group_id(task_id: &String) -> String {
+    format!("verb-{}", task_id)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetOffsetsRequest {
+    pub task_id: String,
+    pub start_from: StartFrom,
+}
+
+/// move the consumer group of a stopped task to start_from,
+/// used to replay or skip data
+pub async fn reset_offsets(
+    state: State<AppState>,
+    Json(req): Json<ResetOffsetsRequest>,
+) -> Whortleberry<Option<String>> {
+    info!("reset offsets {:?}", req);
+    if task_running(&req.task_id).await {
+        error!("task is running {}", req.task_id);
+        return Whortleberry {
+            err_msg: format!("task {} is running, cancel it first", req.task_id),
+            err_no: 10_008,
+            data: None,
+        };
+    }
+
+    let task = match schema::task::fetch_task(&state.conn, &req.task_id).await {
+        Ok(v) => v,
+        Err(err) => {
+            error!("failed to find task {:?}", err);
+            return Whortleberry {
+                err_msg: "failed to find task".to_owned(),
+                err_no: 10_200,
+                data: None,
+            };
+        }
+    };
+    if task.src_type != "kafka" {
+        return Whortleberry {
+            err_msg: format!("not support src type {}", task.src_type),
+            err_no: 10_006,
+            data: None,
+        };
+    }
+
+    let src_cfg = match serde_json::from_str::<KafkaSrcCfg>(&task.src_cfg.as_str()) {
+        Ok(v) => v,
+        Err(err) => {
+            error!(
+                "failed to un marshal src cfg {:?} error{:?}",
+                task.src_cfg, err
+            );
+            return Whortleberry {
+                err_msg: format!("invalid src cfg of task {}, error:{:?}", req.task_id, err),
+                err_no: 400,
+                data: None,
+            };
+        }
+    };
+
+    // metadata, offset lookups and the commit block on the brokers
+    let cfg = src_cfg.to_source_config(&task.id);
+    let res = tokio::task::spawn_blocking(move || {
+        reset_kafka_offsets(&cfg, &req.start_from, false).map(|tpl| format!("{:?}", tpl))
+    })
+    .await
+    .unwrap_or_else(|err| Err(err.to_string()));
+    match res {
+        Ok(tpl) => Whortleberry {
+            err_msg: "success".to_owned(),
+            err_no: 10_000,
+            data: Some(tpl),
+        },
+        Err(err) => {
+            error!("reset task {} offsets error {}", task.id, err);
+            Whortleberry {
+                err_msg: format!("reset offsets error {}", err),
+                err_no: 10_001,
+                data: None,
+            }
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -634,6 +1010,12 @@ pub struct TaskDebugPreviewRequest {
     pub ignore: HashSet<String>,
     pub fold: HashSet<String>,
     pub max_depth: i32,
+    // computed columns of the tasking cfg
+    #[serde(default)]
+    pub computed: BTreeMap<String, String>,
+    // rhai scripts in chain order, message ones run before flatten
+    #[serde(default)]
+    pub scripts: Vec<ScriptConfig>,
 }
 pub async fn task_debug_preview(
     Json(req): Json<TaskDebugPreviewRequest>,
@@ -646,10 +1028,71 @@ pub async fn task_debug_preview(
         req.ignore,
     );
 
-    let res = parser.parse(&"debug_preview".to_owned(), &req.debug);
-    Whortleberry {
-        err_msg: "success".to_owned(),
-        err_no: 10_000,
-        data: res,
+    let computed = match Computed::parse(&req.computed) {
+        Ok(v) => v,
+        Err(err) => {
+            return Whortleberry {
+                err_msg: err,
+                err_no: 400,
+                data: vec![],
+            }
+        }
+    };
+
+    // scripts run on the blocking pool, not on a worker of the api
+    let rows = tokio::task::spawn_blocking(move || {
+        debug_preview_rows(&parser, &computed, &req.scripts, req.debug)
+    })
+    .await
+    .unwrap_or_else(|err| Err(format!("debug preview error {}", err)));
+    match rows {
+        Ok(res) => Whortleberry {
+            err_msg: "success".to_owned(),
+            err_no: 10_000,
+            data: res,
+        },
+        Err(err) => Whortleberry {
+            err_msg: err,
+            err_no: 400,
+            data: vec![],
+        },
+    }
+}
+
+// rows of a debug value the way the transform chain would build them
+fn debug_preview_rows(
+    parser: &service::task::json::ChrysaetosBit,
+    computed: &Computed,
+    scripts: &[ScriptConfig],
+    debug: serde_json::Value,
+) -> Result<Vec<HashMap<String, serde_json::Value>>, String> {
+    let mut scripts = scripts
+        .iter()
+        .map(Script::compile)
+        .collect::<Result<Vec<_>, String>>()?;
+    let at = scripts
+        .iter()
+        .position(|v| v.mode != RecordMode::Message)
+        .unwrap_or(scripts.len());
+    let row_scripts = scripts.split_off(at);
+    if row_scripts.iter().any(|v| v.mode == RecordMode::Message) {
+        return Err("message scripts have to run before row scripts".to_owned());
+    }
+    let mut values = vec![debug];
+    for script in &scripts {
+        let mut out = vec![];
+        for value in values {
+            out.extend(script.run(value)?);
+        }
+        values = out;
+    }
+    let mut res = vec![];
+    for value in &values {
+        res.extend(parser.parse(&"debug_preview".to_owned(), value));
+    }
+    res.iter_mut().for_each(|row| computed.apply(row));
+    for script in &row_scripts {
+        res = script.run_rows(res)?;
     }
+    Ok(res)
 }
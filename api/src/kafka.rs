@@ -1,12 +1,13 @@
+use std::collections::HashMap;
 use std::time::Duration;
 
 use log::info;
 
+use pubg::input::kafka::{resolve_topics, topic_subscriptions};
+use pubg::kafka::{client_config, KafkaSecurity};
 use rdkafka::consumer::{
     BaseConsumer, Consumer,
 };
-
-use rdkafka::ClientConfig;
 use serde::Deserialize;
 
 pub fn kafka_test_connect(cfg: &serde_json::Value) -> anyhow::Result<(), String> {
@@ -17,9 +18,9 @@ pub fn kafka_test_connect(cfg: &serde_json::Value) -> anyhow::Result<(), String>
         }
     };
 
-    info!("kcc is config {:?}", kcc);
-    let consumer = match ClientConfig::new()
-        .set("bootstrap.servers", kcc.broker)
+    info!("kcc is config {}", pubg::core::redact_secrets(cfg));
+    let subscriptions = topic_subscriptions(&kcc.topic, &kcc.topics)?;
+    let consumer = match client_config(&kcc.broker, &kcc.properties, &kcc.security)?
         .create::<BaseConsumer>()
     {
         Ok(v) => v,
@@ -28,16 +29,28 @@ pub fn kafka_test_connect(cfg: &serde_json::Value) -> anyhow::Result<(), String>
         }
     };
 
-    let metadata = match consumer.fetch_metadata(Some(kcc.topic.as_str()), Duration::from_secs(10))
-    {
+    let metadata = match consumer.fetch_metadata(None, Duration::from_secs(10)) {
         Ok(v) => v,
         Err(err) => {
-            return Err(format!("fetch topic {} error {:?}", kcc.topic, err));
+            return Err(format!("fetch topics {:?} error {:?}", subscriptions, err));
         }
     };
 
-    if metadata.topics().len() == 1 && metadata.topics()[0].error().is_some() {
-        return Err(format!("not found topic:{}", kcc.topic));
+    let existing: Vec<String> = metadata
+        .topics()
+        .iter()
+        .filter(|t| t.error().is_none())
+        .map(|t| t.name().to_owned())
+        .collect();
+    // every topic must exist, every regex must match at least one topic
+    for sub in &subscriptions {
+        if sub.starts_with('^') {
+            if resolve_topics(&vec![sub.to_owned()], &existing).is_empty() {
+                return Err(format!("no topic matches:{}", sub));
+            }
+        } else if !existing.contains(sub) {
+            return Err(format!("not found topic:{}", sub));
+        }
     }
 
     return Ok(());
@@ -46,5 +59,12 @@ pub fn kafka_test_connect(cfg: &serde_json::Value) -> anyhow::Result<(), String>
 #[derive(Debug, Deserialize)]
 struct KafkaConnectReqCfg {
     broker: String,
+    #[serde(default)]
     topic: String,
+    #[serde(default)]
+    topics: Vec<String>,
+    #[serde(default)]
+    properties: HashMap<String, String>,
+    #[serde(default)]
+    security: KafkaSecurity,
 }
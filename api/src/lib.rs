@@ -26,12 +26,15 @@ use tower_http::{
 use crate::handler::{
     cancel_task, connect_testing, create_task, fetch_count, fetch_task_list, start_tasking,
     task_debug, task_debug_preview, update_task, AppState, continue_running_task, fetch_task,
+    reset_offsets, fetch_task_metrics, fetch_plugin_list, fetch_pii_report,
 };
 
 pub async fn start(app_conf: conf::app::AppConfig) -> anyhow::Result<()> {
     let state: AppState = AppState {
         conn: init_database(app_conf.data.db.clone()).await,
     };
+    pubg::transform::wasm::set_module_dir(&app_conf.transform.wasm_dir);
+    pubg::plugin::load_plugins(&app_conf.plugin.dir);
     continue_running_task().await;
 
     let cors: CorsLayer = CorsLayer::new()
@@ -66,6 +69,10 @@ pub async fn start(app_conf: conf::app::AppConfig) -> anyhow::Result<()> {
         .route("/task/count", get(fetch_count).layer(cors.clone()))
         .route("/task/update", put(update_task))
         .route("/task/start", get(start_tasking))
+        .route("/task/offset/reset", post(reset_offsets))
+        .route("/task/metrics/:task_id", get(fetch_task_metrics).layer(cors.clone()))
+        .route("/task/pii/:task_id", get(fetch_pii_report).layer(cors.clone()))
+        .route("/plugin/list", get(fetch_plugin_list).layer(cors.clone()))
         .route("/task/debug", post(task_debug))
         .route("/task/debug/preview", post(task_debug_preview))
         .fallback(handler_404)
@@ -6,6 +6,22 @@ pub struct AppConfig {
     pub name: String,
     pub http: HttpConfig,
     pub data: Data,
+    #[serde(default)]
+    pub transform: TransformConfig,
+    #[serde(default)]
+    pub plugin: PluginConfig,
+}
+
+#[derive(Deserialize, Serialize, Debug,Clone,Default)]
+pub struct PluginConfig {
+    #[serde(default)]
+    pub dir: String, // dir of src and dst plugin libraries
+}
+
+#[derive(Deserialize, Serialize, Debug,Clone,Default)]
+pub struct TransformConfig {
+    #[serde(default)]
+    pub wasm_dir: String, // dir of wasm transform modules
 }
 
 #[derive(Deserialize, Serialize, Debug,Clone)]
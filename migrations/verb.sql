@@ -9,8 +9,24 @@ CREATE TABLE IF NOT EXISTS verb.task (
     src_cfg TEXT DEFAULT NULL COMMENT '',
     dst_cfg TEXT DEFAULT NULL COMMENT '',
     tasking_cfg TEXT DEFAULT NULL COMMENT '',
+    dsts TEXT DEFAULT NULL COMMENT 'json list of sinks, empty for the single dst_type sink',
     status tinyint NOT NULL DEFAULT '0' COMMENT "task status has 0:",
     created_at bigint NOT NULL DEFAULT '0' COMMENT 'created timestamp',
     updated_at bigint NOT NULL DEFAULT '0' COMMENT 'updated  timestamp',
     deleted_at bigint NOT NULL DEFAULT '0' COMMENT 'deleted  timestamp'
-) engine = innodb COMMENT 'task info';
\ No newline at end of file
+) engine = innodb COMMENT 'task info';
+
+-- tables created before the dsts column get it, safe to run again
+SET @add_dsts = (
+    SELECT IF(COUNT(*) = 0,
+        'ALTER TABLE verb.task ADD COLUMN dsts TEXT DEFAULT NULL COMMENT ''json list of sinks, empty for the single dst_type sink'' AFTER tasking_cfg',
+        'DO 0')
+    FROM information_schema.columns
+    WHERE table_schema = 'verb' AND table_name = 'task' AND column_name = 'dsts'
+);
+PREPARE add_dsts FROM @add_dsts;
+EXECUTE add_dsts;
+DEALLOCATE PREPARE add_dsts;
+
+-- tasks saved before dsts keep the single dst_type sink, null can not be read as a string
+UPDATE verb.task SET dsts = '' WHERE dsts IS NULL;
@@ -0,0 +1,380 @@
+/// per-task dead-letter destination for msgs that failed to decode, flatten or deliver.
+/// a routed msg is acked once its dead letter is written
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use base64::Engine;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::util::Timeout;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::core::{Ack, Msg};
+use crate::kafka::{check_properties, client_config, KafkaSecurity};
+use crate::metrics;
+use crate::sink::file::{FileDstConfig, RollingFile};
+
+// key of the dead letter cfg in a task tasking cfg
+pub const DEAD_LETTER_KEY: &str = "dead_letter";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DeadLetterConfig {
+    Kafka {
+        broker: String,
+        topic: String,
+        #[serde(default)]
+        properties: HashMap<String, String>,
+        #[serde(default)]
+        security: KafkaSecurity,
+    },
+    // json-lines files like the file dst
+    File {
+        dir: String,
+        // dead letters are acked when their file is closed
+        #[serde(default = "default_max_secs")]
+        max_secs: u64,
+    },
+}
+
+fn default_max_secs() -> u64 {
+    60
+}
+
+/// dead letter cfg of a tasking cfg, none when it is not set
+pub fn check_dead_letter_cfg(
+    tasking_cfg: &serde_json::Value,
+) -> Result<Option<DeadLetterConfig>, String> {
+    let conf = match tasking_cfg.get(DEAD_LETTER_KEY) {
+        None | Some(serde_json::Value::Null) => return Ok(None),
+        Some(v) => v,
+    };
+    let cfg = match serde_json::from_value::<DeadLetterConfig>(conf.clone()) {
+        Ok(v) => v,
+        Err(err) => {
+            return Err(format!(
+                "invalid dead letter config {} error {:?}",
+                conf, err
+            ))
+        }
+    };
+    match &cfg {
+        DeadLetterConfig::Kafka {
+            broker,
+            topic,
+            properties,
+            security,
+        } => {
+            if broker.is_empty() || topic.is_empty() {
+                return Err("dead letter broker and topic are required".to_owned());
+            }
+            check_properties(properties)?;
+            security.check()?;
+        }
+        DeadLetterConfig::File { dir, max_secs } => {
+            if dir.is_empty() || *max_secs == 0 {
+                return Err("dead letter dir is required and max_secs must be over 0".to_owned());
+            }
+        }
+    }
+    Ok(Some(cfg))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Decode,
+    Flatten,
+    Transform,
+    Delivery,
+}
+
+impl Stage {
+    fn name(&self) -> &'static str {
+        match self {
+            Stage::Decode => "decode",
+            Stage::Flatten => "flatten",
+            Stage::Transform => "transform",
+            Stage::Delivery => "delivery",
+        }
+    }
+}
+
+// serialized as its name, which also keys the metrics
+impl Serialize for Stage {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DeadLetter {
+    pub task_id: String,
+    pub g_id: String,
+    pub stage: Stage,
+    // short failure kind like invalid_json, counted per task
+    pub reason: String,
+    pub error: String,
+    // original payload, base64 when it is not utf8
+    pub payload: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub payload_base64: bool,
+    // unix ms
+    pub ts: i64,
+}
+
+impl DeadLetter {
+    pub fn new(
+        task_id: &String,
+        g_id: &String,
+        stage: Stage,
+        reason: &str,
+        error: String,
+        payload: &[u8],
+    ) -> Self {
+        let (payload, payload_base64) = match std::str::from_utf8(payload) {
+            Ok(v) => (v.to_owned(), false),
+            Err(_) => (
+                base64::engine::general_purpose::STANDARD.encode(payload),
+                true,
+            ),
+        };
+        DeadLetter {
+            task_id: task_id.to_owned(),
+            g_id: g_id.to_owned(),
+            stage,
+            reason: reason.to_owned(),
+            error,
+            payload,
+            payload_base64,
+            ts: chrono::Utc::now().timestamp_millis(),
+        }
+    }
+}
+
+type Letter = (DeadLetter, Option<Ack>);
+
+lazy_static! {
+    static ref DEAD_LETTER_SINKS: Arc<Mutex<HashMap<String, mpsc::Sender<Letter>>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+}
+
+/// count the failure and send it to the task dead letter destination.
+/// without a destination the ack is handed back to the caller
+pub async fn route(letter: DeadLetter, ack: Option<Ack>) -> Option<Ack> {
+    warn!(
+        "task_id {} g_id {} {} failure {} {}",
+        letter.task_id,
+        letter.g_id,
+        letter.stage.name(),
+        letter.reason,
+        letter.error
+    );
+    metrics::record_failure_reason(
+        &letter.task_id,
+        &format!("{}.{}", letter.stage.name(), letter.reason),
+    );
+    let sender = DEAD_LETTER_SINKS
+        .lock()
+        .unwrap()
+        .get(&letter.task_id)
+        .cloned();
+    match sender {
+        None => ack,
+        Some(sender) => match sender.send((letter, ack)).await {
+            Ok(_) => None,
+            Err(err) => (err.0).1,
+        },
+    }
+}
+
+/// start the dead letter writer of a task when its tasking cfg has one,
+/// true when it was started
+pub fn start_dead_letter(
+    task_id: &String,
+    tasking_cfg: &serde_json::Value,
+) -> Result<bool, String> {
+    let cfg = match check_dead_letter_cfg(tasking_cfg)? {
+        Some(v) => v,
+        None => return Ok(false),
+    };
+    let (sender, receive) = mpsc::channel::<Letter>(100);
+    match cfg {
+        DeadLetterConfig::Kafka {
+            broker,
+            topic,
+            properties,
+            security,
+        } => {
+            let mut client_config = client_config(&broker, &properties, &security)?;
+            client_config.set("enable.idempotence", "true");
+            let producer = match client_config.create::<FutureProducer>() {
+                Ok(v) => v,
+                Err(err) => return Err(format!("create dead letter producer error {:?}", err)),
+            };
+            tokio::spawn(to_kafka(task_id.to_owned(), producer, topic, receive));
+        }
+        DeadLetterConfig::File { dir, max_secs } => {
+            let cfg = FileDstConfig {
+                dir,
+                max_bytes: 0,
+                max_secs,
+                gzip: false,
+            };
+            let rolling = RollingFile::new(task_id.to_owned(), cfg, "jsonl");
+            tokio::spawn(to_file(task_id.to_owned(), rolling, receive));
+        }
+    }
+    // the writer exits with the dropped sender
+    match DEAD_LETTER_SINKS.lock().unwrap().entry(task_id.to_owned()) {
+        Entry::Occupied(_) => return Err("dead letter is running".to_owned()),
+        Entry::Vacant(v) => v.insert(sender),
+    };
+    info!("task_id {} dead letter started", task_id);
+    Ok(true)
+}
+
+/// the writer exits once the letters routed before are written
+pub fn stop_dead_letter(task_id: &String) {
+    DEAD_LETTER_SINKS.lock().unwrap().remove(task_id);
+}
+
+async fn to_kafka(
+    task_id: String,
+    producer: FutureProducer,
+    topic: String,
+    mut receive: mpsc::Receiver<Letter>,
+) {
+    while let Some((letter, ack)) = receive.recv().await {
+        let payload = serde_json::json!(letter).to_string();
+        let record = FutureRecord::to(topic.as_str())
+            .key(&letter.g_id)
+            .payload(&payload);
+        match producer.send(record, Timeout::Never).await {
+            Ok(_) => {
+                if let Some(ack) = ack {
+                    ack.ack();
+                }
+            }
+            // not acked, the msg is read again after restart
+            Err((err, _)) => error!(
+                "task_id {} g_id {} send dead letter error {:?}",
+                task_id, letter.g_id, err
+            ),
+        }
+    }
+    info!("task_id {} dead letter exit", task_id);
+}
+
+async fn to_file(task_id: String, mut rolling: RollingFile, mut receive: mpsc::Receiver<Letter>) {
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            res = receive.recv() => {
+                let (letter, ack) = match res {
+                    Some(v) => v,
+                    None => break,
+                };
+                match rolling.write_line(&serde_json::json!(letter).to_string()) {
+                    Ok(_) => rolling.pending.extend(
+                        ack.map(|ack| Msg::with_ack(letter.g_id, serde_json::Value::Null, ack)),
+                    ),
+                    // the letters of a dropped file stay uncommitted
+                    Err(err) => {
+                        rolling.abandon();
+                        error!(
+                            "task_id {} g_id {} write dead letter error {}",
+                            task_id, letter.g_id, err
+                        )
+                    }
+                }
+            }
+            _ = tick.tick() => {
+                if let Err(err) = rolling.rotate_if_needed() {
+                    rolling.abandon();
+                    error!("task_id {} dead letter rotate error {}", task_id, err);
+                }
+            }
+        }
+    }
+    if let Err(err) = rolling.close() {
+        rolling.abandon();
+        error!("task_id {} dead letter close error {}", task_id, err);
+    }
+    info!("task_id {} dead letter exit", task_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::core::Offset;
+
+    use super::*;
+
+    #[test]
+    fn test_check_dead_letter_cfg() {
+        let cfg =
+            serde_json::json!({"sep": "_", "dead_letter": {"type": "file", "dir": "/tmp/dl"}});
+        assert_eq!(
+            check_dead_letter_cfg(&cfg).unwrap(),
+            Some(DeadLetterConfig::File {
+                dir: "/tmp/dl".to_owned(),
+                max_secs: 60
+            })
+        );
+        assert_eq!(check_dead_letter_cfg(&serde_json::json!({})).unwrap(), None);
+        let cfg = serde_json::json!({"dead_letter": {"type": "kafka", "broker": "b:9092"}});
+        assert!(check_dead_letter_cfg(&cfg).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_route_to_file() {
+        let task_id = "dead-letter-task".to_owned();
+        let dir = std::env::temp_dir().join(format!("varbit-dead-letter-{}", uuid::Uuid::new_v4()));
+        let (ack_tx, mut ack_rx) = mpsc::unbounded_channel::<Offset>();
+        let letter = DeadLetter::new(
+            &task_id,
+            &"g1".to_owned(),
+            Stage::Decode,
+            "invalid_utf8",
+            "bad bytes".to_owned(),
+            &[0xff, 0xfe],
+        );
+
+        // without a destination the ack comes back
+        let ack = Ack::new(Offset::new("t".to_owned(), 0, 1), ack_tx.clone());
+        assert!(route(letter.clone(), Some(ack)).await.is_some());
+
+        let cfg =
+            serde_json::json!({"dead_letter": {"type": "file", "dir": dir.to_str().unwrap()}});
+        assert!(!start_dead_letter(&task_id, &serde_json::json!({})).unwrap());
+        assert!(start_dead_letter(&task_id, &cfg).unwrap());
+        assert!(start_dead_letter(&task_id, &cfg).is_err());
+        let ack = Ack::new(Offset::new("t".to_owned(), 0, 2), ack_tx.clone());
+        assert!(route(letter, Some(ack)).await.is_none());
+        stop_dead_letter(&task_id);
+
+        assert_eq!(ack_rx.recv().await.unwrap().offset, 2);
+        let name = fs::read_dir(&dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .file_name();
+        let line: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.join(name)).unwrap()).unwrap();
+        assert_eq!(line["stage"], "decode");
+        assert_eq!(line["payload"], "//4=");
+        assert_eq!(line["payload_base64"], true);
+        assert_eq!(
+            metrics::task_metrics(&task_id).unwrap().failure_reasons["decode.invalid_utf8"],
+            2
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
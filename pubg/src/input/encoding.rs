@@ -0,0 +1,170 @@
+/// payload content encoding, undone before the decoder runs.
+/// independent of the kafka transport compression
+use std::io::Read;
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+// default limit of a decoded payload
+pub const DEFAULT_MAX_DECODED_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Deserialize, Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentEncoding {
+    Gzip,
+    Zstd,
+    // raw snappy block
+    Snappy,
+    // lz4 frame
+    Lz4,
+    // standard alphabet with padding
+    Base64,
+}
+
+pub fn default_max_decoded_bytes() -> usize {
+    DEFAULT_MAX_DECODED_BYTES
+}
+
+/// undo every encoding of chain in order, e.g. [base64, gzip] is base64 decode then gunzip.
+/// every step output is limited to max_bytes to protect against decompression bombs
+pub fn decode_content(
+    payload: &[u8],
+    chain: &Vec<ContentEncoding>,
+    max_bytes: usize,
+) -> Result<Vec<u8>, String> {
+    let mut data = payload.to_vec();
+    for encoding in chain {
+        data = decode_step(&data, encoding, max_bytes)?;
+    }
+    Ok(data)
+}
+
+fn decode_step(
+    data: &[u8],
+    encoding: &ContentEncoding,
+    max_bytes: usize,
+) -> Result<Vec<u8>, String> {
+    match encoding {
+        ContentEncoding::Gzip => {
+            read_limited(flate2::read::GzDecoder::new(data), encoding, max_bytes)
+        }
+        ContentEncoding::Zstd => match zstd::stream::read::Decoder::new(data) {
+            Ok(v) => read_limited(v, encoding, max_bytes),
+            Err(err) => Err(format!("{:?} decode error {:?}", encoding, err)),
+        },
+        ContentEncoding::Lz4 => read_limited(
+            lz4_flex::frame::FrameDecoder::new(data),
+            encoding,
+            max_bytes,
+        ),
+        ContentEncoding::Snappy => {
+            // the block header tells the decoded length, check it before allocating
+            let len = match snap::raw::decompress_len(data) {
+                Ok(v) => v,
+                Err(err) => return Err(format!("{:?} decode error {:?}", encoding, err)),
+            };
+            if len > max_bytes {
+                return Err(format!(
+                    "{:?} decoded size {} is over limit {}",
+                    encoding, len, max_bytes
+                ));
+            }
+            match snap::raw::Decoder::new().decompress_vec(data) {
+                Ok(v) => Ok(v),
+                Err(err) => Err(format!("{:?} decode error {:?}", encoding, err)),
+            }
+        }
+        ContentEncoding::Base64 => {
+            // line wrapped base64 is accepted
+            let trimmed: Vec<u8> = data
+                .iter()
+                .filter(|c| !c.is_ascii_whitespace())
+                .copied()
+                .collect();
+            // base64 never grows, the input length is an upper bound
+            if trimmed.len() / 4 * 3 > max_bytes {
+                return Err(format!(
+                    "{:?} decoded size is over limit {}",
+                    encoding, max_bytes
+                ));
+            }
+            match base64::engine::general_purpose::STANDARD.decode(&trimmed) {
+                Ok(v) => Ok(v),
+                Err(err) => Err(format!("{:?} decode error {:?}", encoding, err)),
+            }
+        }
+    }
+}
+
+fn read_limited<R: Read>(
+    reader: R,
+    encoding: &ContentEncoding,
+    max_bytes: usize,
+) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    // one byte more than the limit tells an exact fit from an overflow
+    match reader.take(max_bytes as u64 + 1).read_to_end(&mut out) {
+        Ok(_) => (),
+        Err(err) => return Err(format!("{:?} decode error {:?}", encoding, err)),
+    }
+    if out.len() > max_bytes {
+        return Err(format!(
+            "{:?} decoded size is over limit {}",
+            encoding, max_bytes
+        ));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_decode_chain() {
+        let payload = br#"{"name":"ace"}"#;
+        let encoded = base64::engine::general_purpose::STANDARD.encode(gzip(payload));
+        let res = decode_content(
+            encoded.as_bytes(),
+            &vec![ContentEncoding::Base64, ContentEncoding::Gzip],
+            DEFAULT_MAX_DECODED_BYTES,
+        )
+        .unwrap();
+        assert_eq!(res, payload.to_vec());
+
+        let encoded = zstd::encode_all(&payload[..], 3).unwrap();
+        let res = decode_content(&encoded, &vec![ContentEncoding::Zstd], 1024).unwrap();
+        assert_eq!(res, payload.to_vec());
+
+        let encoded = snap::raw::Encoder::new().compress_vec(payload).unwrap();
+        let res = decode_content(&encoded, &vec![ContentEncoding::Snappy], 1024).unwrap();
+        assert_eq!(res, payload.to_vec());
+
+        let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+        encoder.write_all(payload).unwrap();
+        let encoded = encoder.finish().unwrap();
+        let res = decode_content(&encoded, &vec![ContentEncoding::Lz4], 1024).unwrap();
+        assert_eq!(res, payload.to_vec());
+    }
+
+    #[test]
+    fn test_decode_limit() {
+        // 1MiB of zeros compresses to about 1KiB
+        let bomb = gzip(&vec![0u8; 1024 * 1024]);
+        assert!(decode_content(&bomb, &vec![ContentEncoding::Gzip], 64 * 1024).is_err());
+        assert!(decode_content(&bomb, &vec![ContentEncoding::Gzip], 1024 * 1024).is_ok());
+
+        let bomb = snap::raw::Encoder::new()
+            .compress_vec(&vec![0u8; 1024 * 1024])
+            .unwrap();
+        assert!(decode_content(&bomb, &vec![ContentEncoding::Snappy], 64 * 1024).is_err());
+    }
+}
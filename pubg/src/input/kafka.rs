@@ -1,26 +1,41 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::vec;
 
 use async_trait::async_trait;
 use log::{debug, error, info, warn};
-use schema::task::update_task_heartbeat;
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 
 use rdkafka::client::ClientContext;
 use rdkafka::config::RDKafkaLogLevel;
-use rdkafka::consumer::{CommitMode, Consumer, ConsumerContext, Rebalance, StreamConsumer};
+use rdkafka::consumer::{
+    BaseConsumer, CommitMode, Consumer, ConsumerContext, Rebalance, StreamConsumer,
+};
 use rdkafka::error::KafkaResult;
 use rdkafka::message::{Headers, Message};
 use rdkafka::topic_partition_list::TopicPartitionList;
 use rdkafka::ClientConfig;
+use regex::Regex;
 use uuid::Uuid;
 
-use crate::core::Msg;
+use crate::core::{redact_secrets, Ack, Msg, Offset};
+use crate::dead_letter::{self, DeadLetter, Stage};
+use crate::kafka::{check_properties, client_config, KafkaSecurity};
+use crate::metrics;
+
+use super::encoding::{decode_content, default_max_decoded_bytes, ContentEncoding};
 
 use super::Src;
 
+// a msg not acked for this long holds back the commits of its partition
+const STUCK_AFTER: Duration = Duration::from_secs(300);
+const STUCK_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
 struct CustomContext {
     pub task_id: String,
+    pub tracker: Arc<Mutex<OffsetTracker>>,
 }
 
 impl ClientContext for CustomContext {}
@@ -33,6 +48,13 @@ impl ConsumerContext for CustomContext {
             self.task_id.to_owned(),
             rebalance
         );
+        // revoked partitions are re-read from the last commit by the next owner
+        if let Rebalance::Revoke(tpl) = rebalance {
+            let mut tracker = self.tracker.lock().unwrap();
+            for elem in tpl.elements() {
+                tracker.revoke(elem.topic(), elem.partition());
+            }
+        }
     }
 
     fn post_rebalance(&self, rebalance: &Rebalance) {
@@ -54,58 +76,462 @@ impl ConsumerContext for CustomContext {
 // A type alias with your custom consumer can be created for convenience.
 type LoggingConsumer = StreamConsumer<CustomContext>;
 
-#[derive(Deserialize, Debug, Serialize, Default)]
+#[derive(Deserialize, Debug, Serialize, Default, Clone)]
 pub struct KafkaSourceConfig {
     pub broker: String,
+    // single topic, kept for old task config
+    #[serde(default)]
     pub topic: String,
+    // topic list, an item starting with ^ is a regex subscription like ^events\..*
+    #[serde(default)]
+    pub topics: Vec<String>,
     pub group_id: String,
 
     // json
     pub decoder: String,
     pub meta: KafkaSourceMeta,
+
+    // where to start when the group has no committed offset
+    #[serde(default)]
+    pub start_from: StartFrom,
+
+    // put the originating topic into the msg under this field, empty is off
+    #[serde(default)]
+    pub topic_field: String,
+
+    // pass-through librdkafka properties
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+    #[serde(default)]
+    pub security: KafkaSecurity,
+
+    // payload encodings undone in order before the decoder, like ["base64", "gzip"]
+    #[serde(default)]
+    pub content_encoding: Vec<ContentEncoding>,
+    // limit of the decoded payload
+    #[serde(default = "default_max_decoded_bytes")]
+    pub max_decoded_bytes: usize,
+}
+
+impl KafkaSourceConfig {
+    pub fn subscriptions(&self) -> Result<Vec<String>, String> {
+        topic_subscriptions(&self.topic, &self.topics)
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        self.subscriptions()?;
+        if self.max_decoded_bytes == 0 {
+            return Err("max_decoded_bytes must be positive".to_owned());
+        }
+        check_properties(&self.properties)?;
+        self.security.check()
+    }
+
+    fn client_config(&self) -> Result<ClientConfig, String> {
+        let mut cfg = client_config(&self.broker, &self.properties, &self.security)?;
+        cfg.set("group.id", &self.group_id)
+            .set("enable.auto.commit", "false");
+        Ok(cfg)
+    }
+}
+
+/// merge topic and topics into the subscription list and check the regex items
+pub fn topic_subscriptions(topic: &String, topics: &Vec<String>) -> Result<Vec<String>, String> {
+    let mut subscriptions: Vec<String> = vec![];
+    if !topic.is_empty() {
+        subscriptions.push(topic.to_owned());
+    }
+    for t in topics {
+        if t.is_empty() {
+            return Err("empty topic in topics".to_owned());
+        }
+        if !subscriptions.contains(t) {
+            subscriptions.push(t.to_owned());
+        }
+    }
+    if subscriptions.is_empty() {
+        return Err("topic or topics is required".to_owned());
+    }
+    for t in &subscriptions {
+        if t.starts_with('^') {
+            if let Err(err) = Regex::new(t) {
+                return Err(format!("invalid topic regex {} error {:?}", t, err));
+            }
+        }
+    }
+    Ok(subscriptions)
+}
+
+/// expand subscriptions to the existing topics they match, regex items may match nothing
+pub fn resolve_topics(subscriptions: &Vec<String>, existing: &Vec<String>) -> Vec<String> {
+    let mut resolved: Vec<String> = vec![];
+    for sub in subscriptions {
+        if sub.starts_with('^') {
+            let re = match Regex::new(sub) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            for t in existing {
+                if re.is_match(t) && !resolved.contains(t) {
+                    resolved.push(t.to_owned());
+                }
+            }
+        } else if !resolved.contains(sub) {
+            resolved.push(sub.to_owned());
+        }
+    }
+    resolved
+}
+
+/// start position of a kafka task like "earliest", {"timestamp": 1700000000000}
+/// or {"offsets": [{"topic": "events", "partition": 0, "offset": 42}]}
+#[derive(Deserialize, Debug, Serialize, Default, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StartFrom {
+    Earliest,
+    #[default]
+    Latest,
+    // unix timestamp in milliseconds
+    Timestamp(i64),
+    Offsets(Vec<PartitionOffset>),
+}
+
+#[derive(Deserialize, Debug, Serialize, Clone, PartialEq)]
+pub struct PartitionOffset {
+    // None matches every subscribed topic
+    #[serde(default)]
+    pub topic: Option<String>,
+    pub partition: i32,
+    pub offset: i64,
+}
+
+impl StartFrom {
+    // librdkafka auto.offset.reset
+    fn offset_reset(&self) -> &str {
+        match self {
+            StartFrom::Earliest => "earliest",
+            _ => "latest",
+        }
+    }
+}
+
+/// commit the start position for every partition of the subscribed topics in the group of sfc,
+/// only_uncommitted keeps partitions the group already has an offset for.
+/// the group must not be consuming, this is a blocking call
+pub fn reset_offsets(
+    sfc: &KafkaSourceConfig,
+    start_from: &StartFrom,
+    only_uncommitted: bool,
+) -> Result<TopicPartitionList, String> {
+    let timeout = Duration::from_secs(10);
+    let subscriptions = sfc.subscriptions()?;
+    let consumer = match sfc.client_config()?.create::<BaseConsumer>() {
+        Ok(v) => v,
+        Err(err) => return Err(format!("connect to broker {} error {:?}", sfc.broker, err)),
+    };
+
+    let metadata = match consumer.fetch_metadata(None, timeout) {
+        Ok(v) => v,
+        Err(err) => return Err(format!("fetch metadata error {:?}", err)),
+    };
+    let existing: Vec<String> = metadata
+        .topics()
+        .iter()
+        .map(|t| t.name().to_owned())
+        .collect();
+    let topics = resolve_topics(&subscriptions, &existing);
+    let mut partitions = TopicPartitionList::new();
+    for topic in &topics {
+        let t = match metadata.topics().iter().find(|t| t.name() == topic) {
+            Some(v) => v,
+            None => return Err(format!("not found topic:{}", topic)),
+        };
+        if t.error().is_some() {
+            return Err(format!("not found topic:{} {:?}", topic, t.error()));
+        }
+        for p in t.partitions() {
+            partitions.add_partition(topic, p.id());
+        }
+    }
+
+    if only_uncommitted && partitions.count() > 0 {
+        let committed = match consumer.committed_offsets(partitions, timeout) {
+            Ok(v) => v,
+            Err(err) => return Err(format!("fetch committed offsets error {:?}", err)),
+        };
+        partitions = TopicPartitionList::new();
+        for elem in committed.elements() {
+            if elem.offset() == rdkafka::Offset::Invalid {
+                partitions.add_partition(elem.topic(), elem.partition());
+            }
+        }
+    }
+
+    let mut tpl = TopicPartitionList::new();
+    match start_from {
+        StartFrom::Earliest | StartFrom::Latest => {
+            for elem in partitions.elements() {
+                let (low, high) =
+                    match consumer.fetch_watermarks(elem.topic(), elem.partition(), timeout) {
+                        Ok(v) => v,
+                        Err(err) => {
+                            return Err(format!(
+                                "fetch watermarks {}/{} error {:?}",
+                                elem.topic(),
+                                elem.partition(),
+                                err
+                            ))
+                        }
+                    };
+                let offset = if *start_from == StartFrom::Earliest {
+                    low
+                } else {
+                    high
+                };
+                let _ = tpl.add_partition_offset(
+                    elem.topic(),
+                    elem.partition(),
+                    rdkafka::Offset::Offset(offset),
+                );
+            }
+        }
+        StartFrom::Timestamp(ts) => {
+            if partitions.count() == 0 {
+                return Ok(tpl);
+            }
+            let mut search = TopicPartitionList::new();
+            for elem in partitions.elements() {
+                let _ = search.add_partition_offset(
+                    elem.topic(),
+                    elem.partition(),
+                    rdkafka::Offset::Offset(*ts),
+                );
+            }
+            let found = match consumer.offsets_for_times(search, timeout) {
+                Ok(v) => v,
+                Err(err) => return Err(format!("offsets for times {} error {:?}", ts, err)),
+            };
+            for elem in found.elements() {
+                // no msg after ts, start from the end
+                let offset = match elem.offset() {
+                    rdkafka::Offset::Offset(v) => v,
+                    _ => match consumer.fetch_watermarks(elem.topic(), elem.partition(), timeout) {
+                        Ok((_, high)) => high,
+                        Err(err) => {
+                            return Err(format!(
+                                "fetch watermarks {}/{} error {:?}",
+                                elem.topic(),
+                                elem.partition(),
+                                err
+                            ))
+                        }
+                    },
+                };
+                let _ = tpl.add_partition_offset(
+                    elem.topic(),
+                    elem.partition(),
+                    rdkafka::Offset::Offset(offset),
+                );
+            }
+        }
+        StartFrom::Offsets(offsets) => {
+            for po in offsets {
+                for topic in &topics {
+                    if po.topic.is_some() && po.topic.as_ref() != Some(topic) {
+                        continue;
+                    }
+                    if partitions.find_partition(topic, po.partition).is_none() {
+                        continue;
+                    }
+                    let _ = tpl.add_partition_offset(
+                        topic,
+                        po.partition,
+                        rdkafka::Offset::Offset(po.offset),
+                    );
+                }
+            }
+        }
+    }
+
+    if tpl.count() == 0 {
+        return Ok(tpl);
+    }
+    match consumer.commit(&tpl, CommitMode::Sync) {
+        Ok(_) => Ok(tpl),
+        Err(err) => Err(format!(
+            "commit offsets {:?} for group {} error {:?}",
+            tpl, sfc.group_id, err
+        )),
+    }
 }
 
 pub fn check_cfg(cfg: &serde_json::Value) -> Result<(), String> {
     match serde_json::from_value::<KafkaSourceConfig>(cfg.clone()) {
-        Ok(_) => Ok(()),
+        Ok(v) => match v.check() {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("cfg {} is invalid {}", cfg, err)),
+        },
         Err(err) => Err(format!("cfg {} is invalid {:?}", cfg, err)),
     }
 }
 
-#[derive(Deserialize, Debug, Serialize, Default)]
+#[derive(Deserialize, Debug, Serialize, Default, Clone)]
 pub struct KafkaSourceMeta {
     pub task_id: String,
 }
 
+/// tracks in-flight offsets per partition and hands out the position that is safe to commit,
+/// the committed position never passes a msg that the dst has not acked
+#[derive(Debug, Default)]
+pub(crate) struct OffsetTracker {
+    partitions: HashMap<(String, i32), PartitionOffsets>,
+    // bumped on every revoke, a reassigned partition starts a new generation
+    generation: u64,
+}
+
+#[derive(Debug, Default)]
+struct PartitionOffsets {
+    pending: BTreeMap<i64, Instant>, // received but not delivered, with when it was received
+    high: i64,                       // highest received offset
+    committed: i64,                  // latest committed position
+    generation: u64,                 // assignment the offsets were read in
+}
+
+impl OffsetTracker {
+    /// track a received offset, returns the generation its ack has to carry
+    pub(crate) fn track(&mut self, topic: &str, partition: i32, offset: i64) -> u64 {
+        let generation = self.generation;
+        let p = self
+            .partitions
+            .entry((topic.to_owned(), partition))
+            .or_insert_with(|| PartitionOffsets {
+                pending: BTreeMap::new(),
+                high: offset - 1,
+                committed: offset,
+                generation,
+            });
+        p.pending.insert(offset, Instant::now());
+        if offset > p.high {
+            p.high = offset;
+        }
+        p.generation
+    }
+
+    /// mark offset delivered, return the new commit position if it moved forward.
+    /// acks from an earlier assignment of the partition are ignored
+    pub(crate) fn ack(&mut self, offset: &Offset) -> Option<i64> {
+        let p = self
+            .partitions
+            .get_mut(&(offset.topic.to_owned(), offset.partition))?;
+        if p.generation != offset.generation || p.pending.remove(&offset.offset).is_none() {
+            return None;
+        }
+        let position = match p.pending.keys().next() {
+            Some(v) => *v,
+            None => p.high + 1,
+        };
+        if position <= p.committed {
+            return None;
+        }
+        p.committed = position;
+        Some(position)
+    }
+
+    pub(crate) fn revoke(&mut self, topic: &str, partition: i32) {
+        self.partitions.remove(&(topic.to_owned(), partition));
+        self.generation += 1;
+    }
+
+    /// oldest offset of every partition that has waited for its ack longer than age,
+    /// the commits of those partitions are held back by it
+    pub(crate) fn stuck(&self, age: Duration) -> Vec<Offset> {
+        let mut stuck = vec![];
+        for ((topic, partition), p) in &self.partitions {
+            if let Some((offset, at)) = p.pending.iter().next() {
+                if at.elapsed() >= age {
+                    let mut v = Offset::new(topic.to_owned(), *partition, *offset);
+                    v.generation = p.generation;
+                    stuck.push(v);
+                }
+            }
+        }
+        stuck
+    }
+}
+
 pub struct KafkaSrc {}
 #[async_trait]
 impl Src for KafkaSrc {
     async fn from_src(&self, task_id: String, conf: &serde_json::Value, sender: mpsc::Sender<Msg>) {
-        info!("task id [{}] conf:{:?}", task_id, conf.to_string());
+        info!("task id [{}] conf:{}", task_id, redact_secrets(conf));
         let sfc = match serde_json::from_value::<KafkaSourceConfig>(conf.clone()) {
             Ok(v) => v,
             Err(err) => {
                 error!(
-                    "task_id: {:?} cfg {:?} un_marshal as KafkaSourceConfig error {:?}",
+                    "task_id: {:?} cfg {} un_marshal as KafkaSourceConfig error {:?}",
                     task_id,
-                    conf.clone(),
+                    redact_secrets(conf),
                     err
                 );
                 return;
             }
         };
-        info!("task_id:{:?} sfc {:?}", task_id, sfc);
+        let subscriptions = match sfc.subscriptions() {
+            Ok(v) => v,
+            Err(err) => {
+                error!("task_id: {:?} invalid topics {}", task_id, err);
+                return;
+            }
+        };
 
+        let tracker = Arc::new(Mutex::new(OffsetTracker::default()));
         let context = CustomContext {
             task_id: task_id.to_owned(),
+            tracker: tracker.clone(),
         };
 
-        let consumer = match ClientConfig::new()
-            .set("group.id", sfc.group_id.to_owned().as_str())
-            .set("bootstrap.servers", sfc.broker.to_owned().as_str())
-            .set("enable.partition.eof", "false")
-            .set("session.timeout.ms", "6000")
-            .set("enable.auto.commit", "true")
+        // earliest/latest are handled by auto.offset.reset, other positions are committed
+        // before the first subscribe of the group
+        match sfc.start_from {
+            StartFrom::Timestamp(_) | StartFrom::Offsets(_) => {
+                let cfg = sfc.clone();
+                match tokio::task::spawn_blocking(move || {
+                    reset_offsets(&cfg, &cfg.start_from, true)
+                })
+                .await
+                {
+                    Ok(Ok(tpl)) => info!("task_id:{task_id} start from {:?}", tpl),
+                    Ok(Err(err)) => {
+                        error!("task_id:{task_id} set start offsets error {}", err);
+                        return;
+                    }
+                    Err(err) => {
+                        error!("task_id:{task_id} set start offsets error {:?}", err);
+                        return;
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        let mut client_config = match sfc.client_config() {
+            Ok(v) => v,
+            Err(err) => {
+                error!("task_id {task_id} invalid kafka client config {}", err);
+                return;
+            }
+        };
+        // defaults that properties may override
+        for (key, value) in [
+            ("enable.partition.eof", "false"),
+            ("session.timeout.ms", "6000"),
+        ] {
+            if client_config.get(key).is_none() {
+                client_config.set(key, value);
+            }
+        }
+        let consumer = match client_config
+            .set("auto.offset.reset", sfc.start_from.offset_reset())
             .set_log_level(RDKafkaLogLevel::Debug)
             .create_with_context::<CustomContext, LoggingConsumer>(context)
         {
@@ -119,83 +545,89 @@ impl Src for KafkaSrc {
             }
         };
 
-        match consumer.subscribe(&vec![sfc.topic.to_owned().as_str()]) {
+        let topics: Vec<&str> = subscriptions.iter().map(|t| t.as_str()).collect();
+        match consumer.subscribe(&topics) {
             Ok(_) => (),
             Err(err) => {
                 error!(
                     "task_id {task_id} subscribe to specified topic{:?} failed {:?}",
-                    sfc.topic.to_owned(),
-                    err
+                    subscriptions, err
                 );
                 return;
             }
         }
+        // offsets come back here once the dst delivered every row of the msg
+        let (ack_tx, mut ack_rx) = mpsc::unbounded_channel::<Offset>();
+        let mut stuck_check = tokio::time::interval(STUCK_CHECK_INTERVAL);
         loop {
-            match consumer.recv().await {
-                Err(e) => warn!("Kafka error: {}", e),
-                Ok(m) => {
-                    let payload = match m.payload_view::<str>() {
-                        None => {
-                            warn!(
-                                "task_id:{task_id} topic:{:?} receive payload",
-                                sfc.topic.to_owned()
-                            );
-                            ""
-                        }
-
-                        Some(Ok(s)) => s,
-                        Some(Err(e)) => {
-                            warn!(" task_id:{task_id} topic{:?} Error while deserializing message payload: {:?}",sfc.topic.to_owned(), e);
-                            ""
-                        }
-                    };
-
-                    if payload == "" {
+            tokio::select! {
+                Some(offset) = ack_rx.recv() => {
+                    commit_offset(&task_id, &consumer, &tracker, &offset);
+                }
+                _ = stuck_check.tick() => {
+                    let stuck = tracker.lock().unwrap().stuck(STUCK_AFTER);
+                    for offset in &stuck {
                         warn!(
-                            "task_id:{task_id} topic:{:?} receive payload",
-                            sfc.topic.to_owned()
+                            "task_id:{task_id} topic:{} partition:{} offset {} not acked for {:?}, commits wait on it",
+                            offset.topic, offset.partition, offset.offset, STUCK_AFTER
                         );
-                        continue;
                     }
+                    metrics::record_stuck(&task_id, stuck.len() as u64);
+                }
+                res = consumer.recv() => {
+                    let m = match res {
+                        Err(e) => {
+                            warn!("Kafka error: {}", e);
+                            continue;
+                        }
+                        Ok(m) => m,
+                    };
+                    let mut offset = Offset::new(m.topic().to_owned(), m.partition(), m.offset());
+                    offset.generation = tracker
+                        .lock()
+                        .unwrap()
+                        .track(&offset.topic, offset.partition, offset.offset);
 
                     // get key id
-                    let g_id = if !m.key_len() == 0 {
-                        format!("{:?}", m.key())
-                    } else {
-                        Uuid::new_v4().to_string()
+                    let g_id = match m.key() {
+                        Some(v) if !v.is_empty() => String::from_utf8_lossy(v).into_owned(),
+                        _ => Uuid::new_v4().to_string(),
                     };
-                    debug!("key: '{:?}', payload: '{}', topic: {}, partition: {}, offset: {}, timestamp: {:?} {:?}",
-                              m.key(), payload, m.topic(), m.partition(), m.offset(), m.timestamp(),payload);
 
-                    if let Some(headers) = m.headers() {
-                        for i in 0..headers.count() {
-                            let header = headers.get(i).unwrap();
-                            info!("  Header {:#?}: {:?}", header.0, header.1);
+                    let mut value = match decode_message(&task_id, &sfc, &m) {
+                        Ok(Some(v)) => v,
+                        Ok(None) => {
+                            // nothing to deliver, let the commit move past it
+                            commit_offset(&task_id, &consumer, &tracker, &offset);
+                            continue;
                         }
-                    }
-
-                    let mut value: serde_json::Value = serde_json::Value::Null;
-                    if sfc.decoder == "json".to_owned() {
-                        let value_res = serde_json::from_str(payload);
-                        if value_res.is_err() {
-                            warn!(
-                                "task_id:{task_id} json payload{:?} decoder get error {:?}",
-                                payload,
-                                value_res.err()
+                        Err((reason, err)) => {
+                            let letter = DeadLetter::new(
+                                &task_id,
+                                &g_id,
+                                Stage::Decode,
+                                reason,
+                                err,
+                                m.payload().unwrap_or_default(),
                             );
+                            // without a dead letter dst the msg is skipped
+                            let ack = Ack::new(offset, ack_tx.clone());
+                            if let Some(ack) = dead_letter::route(letter, Some(ack)).await {
+                                ack.ack();
+                            }
                             continue;
                         }
-                        value = value_res.unwrap();
-                    }
-                    if value == serde_json::Value::Null {
-                        warn!("task_id:{task_id} null value continue",);
-                        continue;
-                    }
+                    };
 
-                    let msg = Msg::new(g_id, value);
+                    if !sfc.topic_field.is_empty() {
+                        put_topic(&mut value, &sfc.topic_field, m.topic());
+                    }
 
-                    let _ = sender.send(msg).await;
-                    consumer.commit_message(&m, CommitMode::Async).unwrap();
+                    let msg = Msg::with_ack(g_id, value, Ack::new(offset, ack_tx.clone()));
+                    if let Err(err) = sender.send(msg).await {
+                        error!("task_id:{task_id} dst is closed, stop consuming {:?}", err.to_string());
+                        return;
+                    }
                 }
             }
         }
@@ -210,3 +642,239 @@ impl Src for KafkaSrc {
         "kafka".to_owned()
     }
 }
+
+fn commit_offset(
+    task_id: &String,
+    consumer: &LoggingConsumer,
+    tracker: &Arc<Mutex<OffsetTracker>>,
+    offset: &Offset,
+) {
+    let position = match tracker.lock().unwrap().ack(offset) {
+        Some(v) => v,
+        None => return,
+    };
+    let mut tpl = TopicPartitionList::new();
+    if let Err(err) = tpl.add_partition_offset(
+        &offset.topic,
+        offset.partition,
+        rdkafka::Offset::Offset(position),
+    ) {
+        error!(
+            "task_id:{task_id} topic:{} partition:{} invalid offset {} error {:?}",
+            offset.topic, offset.partition, position, err
+        );
+        return;
+    }
+    if let Err(err) = consumer.commit(&tpl, CommitMode::Async) {
+        error!(
+            "task_id:{task_id} topic:{} partition:{} commit offset {} error {:?}",
+            offset.topic, offset.partition, position, err
+        );
+    }
+}
+
+// decode kafka payload as json value, None means the msg should be skipped,
+// an error is the failure reason and message for the dead letter
+fn decode_message<M: Message>(
+    task_id: &String,
+    sfc: &KafkaSourceConfig,
+    m: &M,
+) -> Result<Option<serde_json::Value>, (&'static str, String)> {
+    let raw = match m.payload() {
+        None => {
+            warn!("task_id:{task_id} topic:{:?} receive payload", m.topic());
+            return Ok(None);
+        }
+        Some(v) => v,
+    };
+    if raw.is_empty() {
+        warn!("task_id:{task_id} topic:{:?} receive payload", m.topic());
+        return Ok(None);
+    }
+
+    let decoded = if sfc.content_encoding.is_empty() {
+        None
+    } else {
+        match decode_content(raw, &sfc.content_encoding, sfc.max_decoded_bytes) {
+            Ok(v) => Some(v),
+            Err(err) => {
+                return Err((
+                    "content_encoding",
+                    format!("content encoding {:?} error {}", sfc.content_encoding, err),
+                ))
+            }
+        }
+    };
+
+    let payload = match std::str::from_utf8(decoded.as_deref().unwrap_or(raw)) {
+        Ok(s) => s,
+        Err(e) => {
+            return Err((
+                "invalid_utf8",
+                format!("Error while deserializing message payload: {:?}", e),
+            ))
+        }
+    };
+
+    if payload == "" {
+        warn!("task_id:{task_id} topic:{:?} receive payload", m.topic());
+        return Ok(None);
+    }
+
+    debug!(
+        "key: '{:?}', payload: '{}', topic: {}, partition: {}, offset: {}, timestamp: {:?} {:?}",
+        m.key(),
+        payload,
+        m.topic(),
+        m.partition(),
+        m.offset(),
+        m.timestamp(),
+        payload
+    );
+
+    if let Some(headers) = m.headers() {
+        for i in 0..headers.count() {
+            let header = headers.get(i).unwrap();
+            info!("  Header {:#?}: {:?}", header.0, header.1);
+        }
+    }
+
+    let mut value: serde_json::Value = serde_json::Value::Null;
+    if sfc.decoder == "json".to_owned() {
+        let value_res = serde_json::from_str(payload);
+        if let Err(err) = value_res {
+            return Err(("invalid_json", format!("json decoder get error {:?}", err)));
+        }
+        value = value_res.unwrap();
+    }
+    if value == serde_json::Value::Null {
+        warn!("task_id:{task_id} null value continue",);
+        return Ok(None);
+    }
+    Ok(Some(value))
+}
+
+// the flattener sees the topic as a root field, array payloads get it on every object item
+fn put_topic(value: &mut serde_json::Value, field: &String, topic: &str) {
+    match value {
+        serde_json::Value::Object(obj) => {
+            obj.insert(field.to_owned(), serde_json::Value::from(topic));
+        }
+        serde_json::Value::Array(list) => {
+            for item in list {
+                if let serde_json::Value::Object(obj) = item {
+                    obj.insert(field.to_owned(), serde_json::Value::from(topic));
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offset_tracker_commit_in_order() {
+        let mut tracker = OffsetTracker::default();
+        tracker.track("t", 0, 10);
+        tracker.track("t", 0, 11);
+        tracker.track("t", 0, 12);
+        let offset = |v| Offset::new("t".to_owned(), 0, v);
+
+        // 11 is delivered first, 10 is still in flight
+        assert_eq!(tracker.ack(&offset(11)), None);
+        assert_eq!(tracker.ack(&offset(10)), Some(12));
+        assert_eq!(tracker.ack(&offset(12)), Some(13));
+        // duplicate ack
+        assert_eq!(tracker.ack(&offset(12)), None);
+    }
+
+    #[test]
+    fn test_start_from_cfg() {
+        let parse = |v: serde_json::Value| serde_json::from_value::<StartFrom>(v).unwrap();
+        assert_eq!(parse(serde_json::json!("earliest")), StartFrom::Earliest);
+        assert_eq!(
+            parse(serde_json::json!({"timestamp": 1700000000000i64})),
+            StartFrom::Timestamp(1700000000000)
+        );
+        assert_eq!(
+            parse(serde_json::json!({"offsets": [{"partition": 1, "offset": 42}]})),
+            StartFrom::Offsets(vec![PartitionOffset {
+                topic: None,
+                partition: 1,
+                offset: 42
+            }])
+        );
+
+        // old task config without start_from
+        let cfg = serde_json::from_value::<KafkaSourceConfig>(serde_json::json!({
+            "broker": "localhost:9092",
+            "topic": "t",
+            "group_id": "verb-1",
+            "decoder": "json",
+            "meta": {"task_id": "1"},
+        }))
+        .unwrap();
+        assert_eq!(cfg.start_from, StartFrom::Latest);
+    }
+
+    #[test]
+    fn test_topic_subscriptions() {
+        let subs = topic_subscriptions(
+            &"orders".to_owned(),
+            &vec!["orders".to_owned(), "^events\\..*".to_owned()],
+        )
+        .unwrap();
+        assert_eq!(subs, vec!["orders".to_owned(), "^events\\..*".to_owned()]);
+        assert!(topic_subscriptions(&"".to_owned(), &vec![]).is_err());
+        assert!(topic_subscriptions(&"".to_owned(), &vec!["^events[".to_owned()]).is_err());
+
+        let existing = vec![
+            "orders".to_owned(),
+            "events.eu".to_owned(),
+            "events.us".to_owned(),
+            "eventsx".to_owned(),
+        ];
+        assert_eq!(
+            resolve_topics(&subs, &existing),
+            vec![
+                "orders".to_owned(),
+                "events.eu".to_owned(),
+                "events.us".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_put_topic() {
+        let mut value = serde_json::json!([{"a": 1}, 2]);
+        put_topic(&mut value, &"_topic".to_owned(), "events.eu");
+        assert_eq!(
+            value,
+            serde_json::json!([{"a": 1, "_topic": "events.eu"}, 2])
+        );
+    }
+
+    #[test]
+    fn test_offset_tracker_revoke() {
+        let mut tracker = OffsetTracker::default();
+        tracker.track("t", 0, 1);
+        tracker.track("t", 1, 5);
+        tracker.revoke("t", 0);
+
+        assert_eq!(tracker.ack(&Offset::new("t".to_owned(), 0, 1)), None);
+        assert_eq!(tracker.ack(&Offset::new("t".to_owned(), 1, 5)), Some(6));
+
+        // reassigned, the stale ack of the first read of offset 1 is ignored
+        let generation = tracker.track("t", 0, 1);
+        assert_eq!(generation, 1);
+        assert_eq!(tracker.ack(&Offset::new("t".to_owned(), 0, 1)), None);
+        assert_eq!(tracker.stuck(Duration::ZERO).len(), 1);
+        let mut offset = Offset::new("t".to_owned(), 0, 1);
+        offset.generation = generation;
+        assert_eq!(tracker.ack(&offset), Some(2));
+        assert!(tracker.stuck(Duration::ZERO).is_empty());
+    }
+}
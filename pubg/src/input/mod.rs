@@ -1,13 +1,28 @@
+pub mod encoding;
 pub mod kafka;
 
 use async_trait::async_trait;
 use tokio::sync::mpsc;
 
 use crate::core::Msg;
+use crate::SRC_PLUGIN;
 
 #[async_trait]
 pub trait Src: Send + Sync {
     async fn from_src(&self, task_id: String, conf: &serde_json::Value, sender: mpsc::Sender<Msg>);
     fn cfg(&self) -> serde_json::Value;
     fn src_name(&self) -> String;
+    // check src config of a task before saving it
+    fn check_cfg(&self, _conf: &serde_json::Value) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// check src_type is registered and conf is valid for it
+pub fn check_src_cfg(src_type: &String, conf: &serde_json::Value) -> Result<(), String> {
+    let src = match SRC_PLUGIN.lock().unwrap().get(src_type.as_str()) {
+        Some(v) => v.clone(),
+        None => return Err(format!("not support src type {}", src_type)),
+    };
+    src.check_cfg(conf)
 }
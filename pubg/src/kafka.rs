@@ -0,0 +1,188 @@
+/// kafka client settings shared by the kafka src, the kafka dst and connect testing
+use std::collections::HashMap;
+use std::fs;
+
+use rdkafka::ClientConfig;
+use serde::{Deserialize, Serialize};
+
+// keys varbit sets itself or that belong to KafkaSecurity
+const RESERVED_PROPERTIES: [&str; 6] = [
+    "bootstrap.servers",
+    "group.id",
+    "enable.auto.commit",
+    "auto.offset.reset",
+    "security.protocol",
+    "ssl.ca.location",
+];
+
+const SECURITY_PROTOCOLS: [&str; 4] = ["PLAINTEXT", "SSL", "SASL_PLAINTEXT", "SASL_SSL"];
+
+const SASL_MECHANISMS: [&str; 3] = ["PLAIN", "SCRAM-SHA-256", "SCRAM-SHA-512"];
+
+#[derive(Deserialize, Debug, Serialize, Default, Clone, PartialEq)]
+pub struct KafkaSecurity {
+    // PLAINTEXT, SSL, SASL_PLAINTEXT, SASL_SSL, empty is PLAINTEXT
+    #[serde(default)]
+    pub protocol: String,
+    // PLAIN, SCRAM-SHA-256, SCRAM-SHA-512
+    #[serde(default)]
+    pub sasl_mechanism: String,
+    #[serde(default)]
+    pub username: String,
+    // never the password itself: env:KAFKA_PASSWORD or file:/run/secrets/kafka
+    #[serde(default)]
+    pub password_ref: String,
+    // ca certificate file to verify the broker
+    #[serde(default)]
+    pub ca_file: String,
+}
+
+impl KafkaSecurity {
+    pub fn check(&self) -> Result<(), String> {
+        if !self.protocol.is_empty() && !SECURITY_PROTOCOLS.contains(&self.protocol.as_str()) {
+            return Err(format!(
+                "invalid security protocol {} expected one of {:?}",
+                self.protocol, SECURITY_PROTOCOLS
+            ));
+        }
+        if !self.protocol.starts_with("SASL_") {
+            if !self.sasl_mechanism.is_empty() || !self.username.is_empty() {
+                return Err(format!(
+                    "sasl settings need a SASL_ protocol, got {:?}",
+                    self.protocol
+                ));
+            }
+            return Ok(());
+        }
+        if !SASL_MECHANISMS.contains(&self.sasl_mechanism.as_str()) {
+            return Err(format!(
+                "invalid sasl mechanism {:?} expected one of {:?}",
+                self.sasl_mechanism, SASL_MECHANISMS
+            ));
+        }
+        if self.username.is_empty() {
+            return Err("sasl username is required".to_owned());
+        }
+        if !self.password_ref.starts_with("env:") && !self.password_ref.starts_with("file:") {
+            return Err(format!(
+                "invalid password_ref {:?} expected env:NAME or file:PATH",
+                self.password_ref
+            ));
+        }
+        Ok(())
+    }
+
+    // read the password the reference points to
+    fn password(&self) -> Result<String, String> {
+        if let Some(name) = self.password_ref.strip_prefix("env:") {
+            return match std::env::var(name) {
+                Ok(v) => Ok(v),
+                Err(err) => Err(format!("read password env {} error {:?}", name, err)),
+            };
+        }
+        if let Some(path) = self.password_ref.strip_prefix("file:") {
+            return match fs::read_to_string(path) {
+                Ok(v) => Ok(v.trim_end().to_owned()),
+                Err(err) => Err(format!("read password file {} error {:?}", path, err)),
+            };
+        }
+        Err(format!("invalid password_ref {:?}", self.password_ref))
+    }
+}
+
+/// pass-through librdkafka properties like fetch.min.bytes or linger.ms
+pub fn check_properties(properties: &HashMap<String, String>) -> Result<(), String> {
+    for (key, value) in properties {
+        let valid_key = !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '_');
+        if !valid_key {
+            return Err(format!("invalid kafka property {:?}", key));
+        }
+        if RESERVED_PROPERTIES.contains(&key.as_str())
+            || key.starts_with("sasl.")
+            || key.contains("password")
+        {
+            return Err(format!(
+                "kafka property {} is managed by varbit, use the config/security fields",
+                key
+            ));
+        }
+        if value.is_empty() {
+            return Err(format!("empty value of kafka property {}", key));
+        }
+    }
+    Ok(())
+}
+
+/// base client config of broker with properties and security applied,
+/// callers set their own managed keys afterwards
+pub fn client_config(
+    broker: &String,
+    properties: &HashMap<String, String>,
+    security: &KafkaSecurity,
+) -> Result<ClientConfig, String> {
+    check_properties(properties)?;
+    security.check()?;
+
+    let mut cfg = ClientConfig::new();
+    for (key, value) in properties {
+        cfg.set(key, value);
+    }
+    cfg.set("bootstrap.servers", broker);
+    if !security.protocol.is_empty() {
+        cfg.set("security.protocol", &security.protocol);
+    }
+    if !security.ca_file.is_empty() {
+        cfg.set("ssl.ca.location", &security.ca_file);
+    }
+    if security.protocol.starts_with("SASL_") {
+        cfg.set("sasl.mechanism", &security.sasl_mechanism)
+            .set("sasl.username", &security.username)
+            .set("sasl.password", security.password()?);
+    }
+    Ok(cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_properties() {
+        let mut properties = HashMap::new();
+        properties.insert("fetch.min.bytes".to_owned(), "1024".to_owned());
+        assert!(check_properties(&properties).is_ok());
+
+        properties.insert("sasl.password".to_owned(), "secret".to_owned());
+        assert!(check_properties(&properties).is_err());
+
+        let mut properties = HashMap::new();
+        properties.insert("group.id".to_owned(), "other".to_owned());
+        assert!(check_properties(&properties).is_err());
+    }
+
+    #[test]
+    fn test_security() {
+        std::env::set_var("VARBIT_TEST_KAFKA_PASSWORD", "secret");
+        let security = KafkaSecurity {
+            protocol: "SASL_SSL".to_owned(),
+            sasl_mechanism: "SCRAM-SHA-512".to_owned(),
+            username: "varbit".to_owned(),
+            password_ref: "env:VARBIT_TEST_KAFKA_PASSWORD".to_owned(),
+            ca_file: "/etc/ssl/ca.pem".to_owned(),
+        };
+        let cfg = client_config(&"localhost:9092".to_owned(), &HashMap::new(), &security).unwrap();
+        assert_eq!(cfg.get("sasl.password"), Some("secret"));
+        assert_eq!(cfg.get("ssl.ca.location"), Some("/etc/ssl/ca.pem"));
+
+        // plain password is not accepted
+        let security = KafkaSecurity {
+            password_ref: "secret".to_owned(),
+            ..security
+        };
+        assert!(security.check().is_err());
+        assert!(KafkaSecurity::default().check().is_ok());
+    }
+}
@@ -13,15 +13,28 @@ use std::{
 };
 
 pub mod core;
+pub mod dead_letter;
 pub mod input;
+pub mod kafka;
+pub mod metrics;
+pub mod plugin;
 pub mod sink;
 pub mod task;
+pub mod transform;
 
 use async_trait::async_trait;
 use input::kafka::KafkaSrc;
 use lazy_static::lazy_static;
 
-use crate::sink::Dst;
+use crate::sink::{
+    csv::CsvDst, file::FileDst, http::HttpDst, mysql::MySqlDst, parquet::ParquetDst,
+    stdout::StdoutDst, Dst,
+};
+use crate::transform::{
+    aggregate::AggregateTransform, computed::ComputedTransform, dedup::DedupTransform,
+    filter::FilterTransform, flatten::FlattenTransform, redact::RedactTransform,
+    script::ScriptTransform, wasm::WasmTransform, Transform,
+};
 use crate::{input::Src, sink::kafka::KafkaDst};
 
 lazy_static! {
@@ -36,6 +49,25 @@ lazy_static! {
     pub static ref DST_PLUGIN: Arc<Mutex<HashMap<String,Arc<Box<dyn Dst  +Send +Sync>>>>> =   {
         let mut plugin :HashMap<String,Arc<Box<dyn Dst  +Send +Sync>>>= HashMap::new();
         plugin.insert(String::from("kafka"), Arc::new(Box::new(KafkaDst{})));
+        plugin.insert(String::from("file"), Arc::new(Box::new(FileDst{})));
+        plugin.insert(String::from("csv"), Arc::new(Box::new(CsvDst{})));
+        plugin.insert(String::from("mysql"), Arc::new(Box::new(MySqlDst{})));
+        plugin.insert(String::from("parquet"), Arc::new(Box::new(ParquetDst{})));
+        plugin.insert(String::from("http"), Arc::new(Box::new(HttpDst{})));
+        plugin.insert(String::from("stdout"), Arc::new(Box::new(StdoutDst{})));
+        Arc::new(Mutex::new(plugin))
+    };
+
+    pub static ref TRANSFORM_PLUGIN: Arc<Mutex<HashMap<String,Arc<Box<dyn Transform  +Send +Sync>>>>> =   {
+        let mut plugin :HashMap<String,Arc<Box<dyn Transform  +Send +Sync>>>= HashMap::new();
+        plugin.insert(String::from("flatten"), Arc::new(Box::new(FlattenTransform{})));
+        plugin.insert(String::from("filter"), Arc::new(Box::new(FilterTransform{})));
+        plugin.insert(String::from("computed"), Arc::new(Box::new(ComputedTransform{})));
+        plugin.insert(String::from("rhai"), Arc::new(Box::new(ScriptTransform{})));
+        plugin.insert(String::from("wasm"), Arc::new(Box::new(WasmTransform{})));
+        plugin.insert(String::from("redact"), Arc::new(Box::new(RedactTransform{})));
+        plugin.insert(String::from("dedup"), Arc::new(Box::new(DedupTransform{})));
+        plugin.insert(String::from("aggregate"), Arc::new(Box::new(AggregateTransform{})));
         Arc::new(Mutex::new(plugin))
     };
 }
@@ -0,0 +1,123 @@
+/// delivery counters of running tasks, reset when a task is dispatched
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+// failures kept per task
+const MAX_RECENT_FAILURES: usize = 20;
+
+#[derive(Debug, Serialize, Default, Clone, PartialEq)]
+pub struct DeliveryFailure {
+    pub g_id: String,
+    pub error: String,
+    // unix ms
+    pub at: i64,
+}
+
+#[derive(Debug, Serialize, Default, Clone, PartialEq)]
+pub struct TaskMetrics {
+    // msgs with every row delivered
+    pub delivered: u64,
+    // msgs with a failed row, they stay uncommitted
+    pub failed: u64,
+    // latest failures, newest last
+    pub recent_failures: Vec<DeliveryFailure>,
+    // failed msgs per <stage>.<reason> like decode.invalid_json
+    pub failure_reasons: BTreeMap<String, u64>,
+    // rows dropped by the task filter
+    pub filtered: u64,
+    // rows or msgs dropped as duplicates by the dedup transform
+    pub deduped: u64,
+    // rows that came after their windows closed
+    pub late: u64,
+    // partitions whose commit waits on a msg not acked for minutes
+    pub stuck: u64,
+}
+
+lazy_static! {
+    pub static ref TASK_METRICS: Arc<Mutex<HashMap<String, TaskMetrics>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+}
+
+pub fn reset_task_metrics(task_id: &String) {
+    let mut lock = TASK_METRICS.lock().unwrap();
+    lock.insert(task_id.to_owned(), TaskMetrics::default());
+}
+
+pub fn record_delivered(task_id: &String, msgs: u64) {
+    let mut lock = TASK_METRICS.lock().unwrap();
+    lock.entry(task_id.to_owned()).or_default().delivered += msgs;
+}
+
+/// report a msg the dst failed to deliver
+pub fn record_failure(task_id: &String, g_id: &String, error: String) {
+    let mut lock = TASK_METRICS.lock().unwrap();
+    let metrics = lock.entry(task_id.to_owned()).or_default();
+    metrics.failed += 1;
+    if metrics.recent_failures.len() >= MAX_RECENT_FAILURES {
+        metrics.recent_failures.remove(0);
+    }
+    metrics.recent_failures.push(DeliveryFailure {
+        g_id: g_id.to_owned(),
+        error,
+        at: chrono::Utc::now().timestamp_millis(),
+    });
+}
+
+pub fn record_filtered(task_id: &String, rows: u64) {
+    let mut lock = TASK_METRICS.lock().unwrap();
+    lock.entry(task_id.to_owned()).or_default().filtered += rows;
+}
+
+pub fn record_deduped(task_id: &String, dropped: u64) {
+    let mut lock = TASK_METRICS.lock().unwrap();
+    lock.entry(task_id.to_owned()).or_default().deduped += dropped;
+}
+
+pub fn record_late(task_id: &String, rows: u64) {
+    let mut lock = TASK_METRICS.lock().unwrap();
+    lock.entry(task_id.to_owned()).or_default().late += rows;
+}
+
+pub fn record_stuck(task_id: &String, partitions: u64) {
+    let mut lock = TASK_METRICS.lock().unwrap();
+    lock.entry(task_id.to_owned()).or_default().stuck = partitions;
+}
+
+pub fn record_failure_reason(task_id: &String, reason: &String) {
+    let mut lock = TASK_METRICS.lock().unwrap();
+    let metrics = lock.entry(task_id.to_owned()).or_default();
+    *metrics
+        .failure_reasons
+        .entry(reason.to_owned())
+        .or_default() += 1;
+}
+
+pub fn task_metrics(task_id: &String) -> Option<TaskMetrics> {
+    let lock = TASK_METRICS.lock().unwrap();
+    lock.get(task_id).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_failure() {
+        let task_id = "metrics-task".to_owned();
+        reset_task_metrics(&task_id);
+        record_delivered(&task_id, 2);
+        for i in 0..MAX_RECENT_FAILURES + 1 {
+            record_failure(&task_id, &i.to_string(), "timeout".to_owned());
+        }
+        let metrics = task_metrics(&task_id).unwrap();
+        assert_eq!(metrics.delivered, 2);
+        assert_eq!(metrics.failed, MAX_RECENT_FAILURES as u64 + 1);
+        assert_eq!(metrics.recent_failures.len(), MAX_RECENT_FAILURES);
+        assert_eq!(metrics.recent_failures[0].g_id, "1");
+    }
+}
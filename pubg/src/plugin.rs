@@ -0,0 +1,591 @@
+//! src and dst plugins loaded from shared libraries.
+//!
+//! a plugin is a `cdylib` exporting `varbit_plugin_declare`, which returns a
+//! `PluginDeclare` with the abi version it was built against. only json and
+//! plain c types cross the boundary, so a plugin does not have to share a rustc
+//! or a tokio with varbit. a plugin runs its own threads.
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use libloading::Library;
+use log::{error, info};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::core::{Ack, Msg, Offset};
+use crate::input::Src;
+use crate::sink::delivered;
+use crate::sink::{msg_rows, Dst};
+use crate::{DST_PLUGIN, SRC_PLUGIN};
+
+/// bumped on any change to `PluginDeclare` or the functions in it
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+/// symbol a plugin library exports, `extern "C" fn() -> *const PluginDeclare`
+pub const PLUGIN_DECLARE_SYMBOL: &str = "varbit_plugin_declare";
+
+pub const PLUGIN_KIND_SRC: u32 = 0;
+pub const PLUGIN_KIND_DST: u32 = 1;
+
+/// hands a msg from a src plugin to varbit, blocks while the task is busy.
+/// seq comes back through `ack` once the msg is delivered.
+/// returns 0 when taken, 1 when the task is closed and 2 when data is not json
+pub type EmitFn = extern "C" fn(
+    ctx: *mut c_void,
+    g_id: *const c_char,
+    data: *const u8,
+    len: usize,
+    seq: u64,
+) -> i32;
+
+/// what a plugin declares, strings are nul terminated utf8.
+/// an error out param is set to a string the plugin allocated, varbit frees it with `free_string`
+#[repr(C)]
+pub struct PluginDeclare {
+    pub abi_version: u32,
+    pub kind: u32,
+    pub name: *const c_char,
+    // default cfg of the plugin as json
+    pub cfg: *const c_char,
+    // null when the json conf is valid, else the error
+    pub check_cfg: extern "C" fn(conf: *const c_char) -> *mut c_char,
+    // a handle for one task, null on error. emit is only given to a src
+    pub open: extern "C" fn(
+        task_id: *const c_char,
+        conf: *const c_char,
+        emit: Option<EmitFn>,
+        emit_ctx: *mut c_void,
+        err: *mut *mut c_char,
+    ) -> *mut c_void,
+    // dst only, write a json array of rows, 0 when delivered
+    pub write: Option<
+        extern "C" fn(
+            handle: *mut c_void,
+            data: *const u8,
+            len: usize,
+            err: *mut *mut c_char,
+        ) -> i32,
+    >,
+    // src only, the msg emitted with seq is delivered
+    pub ack: Option<extern "C" fn(handle: *mut c_void, seq: u64)>,
+    // stop the task, a src must not emit once it returns
+    pub close: extern "C" fn(handle: *mut c_void),
+    pub free_string: extern "C" fn(s: *mut c_char),
+}
+
+// the fn pointers live as long as the library, which is never unloaded
+#[derive(Clone, Copy)]
+struct Declare(&'static PluginDeclare);
+unsafe impl Send for Declare {}
+unsafe impl Sync for Declare {}
+
+struct Handle(*mut c_void);
+unsafe impl Send for Handle {}
+
+lazy_static! {
+    // loaded libraries are kept until exit
+    static ref LIBRARIES: Mutex<Vec<Library>> = Mutex::new(vec![]);
+    // names of the plugins loaded from libraries
+    static ref DYNAMIC_PLUGINS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// load every shared library in dir as a plugin, a bad one is logged and skipped
+pub fn load_plugins(dir: &str) -> Vec<String> {
+    if dir.is_empty() {
+        return vec![];
+    }
+    let entries = match std::fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(err) => {
+            error!("[plugin] read plugin dir {} error {}", dir, err);
+            return vec![];
+        }
+    };
+    // .so, .dylib or .dll
+    let ext = Some(std::env::consts::DLL_EXTENSION);
+    let mut paths: Vec<_> = entries
+        .filter_map(|v| v.ok().map(|v| v.path()))
+        .filter(|v| v.extension().and_then(|v| v.to_str()) == ext)
+        .collect();
+    paths.sort();
+    let mut names = vec![];
+    for path in paths {
+        match load_plugin(&path) {
+            Ok(name) => {
+                info!("[plugin] loaded {} from {:?}", name, path);
+                names.push(name);
+            }
+            Err(err) => error!("[plugin] load {:?} error {}", path, err),
+        }
+    }
+    names
+}
+
+/// load one plugin library and register it
+pub fn load_plugin(path: &Path) -> Result<String, String> {
+    let library = unsafe { Library::new(path) }.map_err(|err| err.to_string())?;
+    let declare = unsafe {
+        let symbol = library
+            .get::<extern "C" fn() -> *const PluginDeclare>(PLUGIN_DECLARE_SYMBOL.as_bytes())
+            .map_err(|err| err.to_string())?;
+        symbol()
+    };
+    if declare.is_null() {
+        return Err(format!("{} returned null", PLUGIN_DECLARE_SYMBOL));
+    }
+    // check the version before reading anything else of the declare
+    let version = unsafe { (*declare).abi_version };
+    if version != PLUGIN_ABI_VERSION {
+        return Err(format!(
+            "plugin abi version {} is not {}",
+            version, PLUGIN_ABI_VERSION
+        ));
+    }
+    // the declare lives in the library, which is kept loaded
+    let name = unsafe { register(&*declare) }?;
+    LIBRARIES.lock().unwrap().push(library);
+    Ok(name)
+}
+
+/// register a declared plugin as a src or dst, built in plugins are never replaced
+///
+/// # Safety
+///
+/// `name` and `cfg` of declare are null or nul terminated strings that outlive it,
+/// and its fn pointers follow the plugin abi and may be called from any thread
+/// for as long as the process runs.
+pub unsafe fn register(declare: &'static PluginDeclare) -> Result<String, String> {
+    if declare.abi_version != PLUGIN_ABI_VERSION {
+        return Err(format!(
+            "plugin abi version {} is not {}",
+            declare.abi_version, PLUGIN_ABI_VERSION
+        ));
+    }
+    let name = c_str(declare.name).ok_or("plugin has no name")?;
+    let cfg = c_str(declare.cfg).unwrap_or_default();
+    let cfg = match serde_json::from_str::<serde_json::Value>(&cfg) {
+        Ok(v) => v,
+        Err(err) => return Err(format!("plugin {} cfg is not json {}", name, err)),
+    };
+    let declare = Declare(declare);
+    match declare.0.kind {
+        PLUGIN_KIND_SRC => {
+            if declare.0.ack.is_none() {
+                return Err(format!("src plugin {} has no ack", name));
+            }
+            let mut plugins = SRC_PLUGIN.lock().unwrap();
+            if plugins.contains_key(&name) {
+                return Err(format!("src {} is registered", name));
+            }
+            let src = PluginSrc {
+                declare,
+                name: name.clone(),
+                cfg,
+            };
+            plugins.insert(name.clone(), Arc::new(Box::new(src)));
+        }
+        PLUGIN_KIND_DST => {
+            if declare.0.write.is_none() {
+                return Err(format!("dst plugin {} has no write", name));
+            }
+            let mut plugins = DST_PLUGIN.lock().unwrap();
+            if plugins.contains_key(&name) {
+                return Err(format!("dst {} is registered", name));
+            }
+            let dst = PluginDst {
+                declare,
+                name: name.clone(),
+                cfg,
+            };
+            plugins.insert(name.clone(), Arc::new(Box::new(dst)));
+        }
+        kind => return Err(format!("plugin {} kind {} is unknown", name, kind)),
+    }
+    DYNAMIC_PLUGINS.lock().unwrap().insert(name.clone());
+    Ok(name)
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PluginInfo {
+    pub name: String,
+    pub kind: String,
+    pub cfg: serde_json::Value,
+    // loaded from a library
+    pub dynamic: bool,
+}
+
+/// every registered src and dst
+pub fn plugin_list() -> Vec<PluginInfo> {
+    let dynamic = DYNAMIC_PLUGINS.lock().unwrap().clone();
+    let info = |name: &String, kind: &str, cfg| PluginInfo {
+        name: name.to_owned(),
+        kind: kind.to_owned(),
+        cfg,
+        dynamic: dynamic.contains(name),
+    };
+    let mut list = vec![];
+    // sorted so the list is stable between calls
+    let srcs: BTreeMap<_, _> = SRC_PLUGIN.lock().unwrap().clone().into_iter().collect();
+    list.extend(srcs.iter().map(|(name, v)| info(name, "src", v.cfg())));
+    let dsts: BTreeMap<_, _> = DST_PLUGIN.lock().unwrap().clone().into_iter().collect();
+    list.extend(dsts.iter().map(|(name, v)| info(name, "dst", v.cfg())));
+    list
+}
+
+fn c_str(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        return None;
+    }
+    unsafe { CStr::from_ptr(s) }
+        .to_str()
+        .ok()
+        .map(|v| v.to_owned())
+}
+
+// a string the plugin allocated
+fn plugin_error(declare: Declare, err: *mut c_char) -> String {
+    if err.is_null() {
+        return "unknown plugin error".to_owned();
+    }
+    let msg = c_str(err).unwrap_or_default();
+    (declare.0.free_string)(err);
+    msg
+}
+
+fn check_cfg(declare: Declare, conf: &serde_json::Value) -> Result<(), String> {
+    let conf = CString::new(conf.to_string()).map_err(|err| err.to_string())?;
+    match (declare.0.check_cfg)(conf.as_ptr()) {
+        err if err.is_null() => Ok(()),
+        err => Err(plugin_error(declare, err)),
+    }
+}
+
+fn open(
+    declare: Declare,
+    task_id: &str,
+    conf: &serde_json::Value,
+    emit: Option<EmitFn>,
+    emit_ctx: *mut c_void,
+) -> Result<Handle, String> {
+    let task_id = CString::new(task_id).map_err(|err| err.to_string())?;
+    let conf = CString::new(conf.to_string()).map_err(|err| err.to_string())?;
+    let mut err = std::ptr::null_mut();
+    let handle = (declare.0.open)(task_id.as_ptr(), conf.as_ptr(), emit, emit_ctx, &mut err);
+    match handle.is_null() {
+        true => Err(plugin_error(declare, err)),
+        false => Ok(Handle(handle)),
+    }
+}
+
+/// a dst in a plugin library, writes run on the blocking pool
+pub struct PluginDst {
+    declare: Declare,
+    name: String,
+    cfg: serde_json::Value,
+}
+#[async_trait]
+impl Dst for PluginDst {
+    async fn to_dst(
+        &self,
+        task_id: String,
+        conf: serde_json::Value,
+        mut receive: mpsc::Receiver<Msg>,
+    ) {
+        info!("[dst] plugin {} task_id {}", self.name, task_id);
+        let declare = self.declare;
+        let (id, cf) = (task_id.clone(), conf.clone());
+        let handle = tokio::task::spawn_blocking(move || {
+            open(declare, &id, &cf, None, std::ptr::null_mut())
+        })
+        .await;
+        let handle = match handle {
+            Ok(Ok(v)) => Arc::new(Mutex::new(v)),
+            Ok(Err(err)) => {
+                error!(
+                    "[dst] plugin {} task_id {} open error {}",
+                    self.name, task_id, err
+                );
+                return;
+            }
+            Err(err) => {
+                error!(
+                    "[dst] plugin {} task_id {} open error {}",
+                    self.name, task_id, err
+                );
+                return;
+            }
+        };
+        let _close = Close(declare, handle.clone());
+        while let Some(mut msg) = receive.recv().await {
+            let rows = match msg_rows(&task_id, &mut msg).await {
+                Some(v) => serde_json::json!(v).to_string(),
+                None => continue,
+            };
+            let h = handle.clone();
+            let res = tokio::task::spawn_blocking(move || {
+                let handle = h.lock().unwrap();
+                let write = declare.0.write.unwrap();
+                let mut err = std::ptr::null_mut();
+                match write(handle.0, rows.as_ptr(), rows.len(), &mut err) {
+                    0 => Ok(vec![()]),
+                    _ => Err(plugin_error(declare, err)),
+                }
+            })
+            .await
+            .unwrap_or_else(|err| Err(err.to_string()));
+            delivered(&task_id, msg, res, "send_failed").await;
+        }
+        info!("[dst] plugin {} task_id {} exit", self.name, task_id);
+    }
+
+    fn cfg(&self) -> serde_json::Value {
+        self.cfg.clone()
+    }
+
+    fn dst_name(&self) -> String {
+        self.name.to_owned()
+    }
+
+    fn check_cfg(&self, conf: &serde_json::Value) -> Result<(), String> {
+        check_cfg(self.declare, conf)
+    }
+}
+
+// closes the plugin handle of a task when its future ends or is aborted
+struct Close(Declare, Arc<Mutex<Handle>>);
+impl Drop for Close {
+    fn drop(&mut self) {
+        (self.0 .0.close)(self.1.lock().unwrap().0);
+    }
+}
+
+// what emit gets as ctx, freed after the src handle is closed
+struct EmitCtx {
+    name: String,
+    sender: mpsc::Sender<Msg>,
+    acks: mpsc::UnboundedSender<Offset>,
+    closed: AtomicBool,
+}
+
+extern "C" fn emit(
+    ctx: *mut c_void,
+    g_id: *const c_char,
+    data: *const u8,
+    len: usize,
+    seq: u64,
+) -> i32 {
+    if ctx.is_null() {
+        return 1;
+    }
+    // a null data is never valid json, even with a len of 0
+    if data.is_null() {
+        return 2;
+    }
+    let ctx = unsafe { &*(ctx as *const EmitCtx) };
+    let value = match serde_json::from_slice(unsafe { std::slice::from_raw_parts(data, len) }) {
+        Ok(v) => v,
+        Err(_) => return 2,
+    };
+    let offset = Offset::new(ctx.name.to_owned(), 0, seq as i64);
+    let mut msg = Msg::new(c_str(g_id).unwrap_or_default(), value);
+    msg.ack = Some(Ack::new(offset, ctx.acks.clone()));
+    // try_send so close never waits on a plugin thread stuck in a full channel
+    loop {
+        if ctx.closed.load(Ordering::Acquire) {
+            return 1;
+        }
+        match ctx.sender.try_send(msg) {
+            Ok(_) => return 0,
+            Err(mpsc::error::TrySendError::Closed(_)) => return 1,
+            Err(mpsc::error::TrySendError::Full(v)) => {
+                msg = v;
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        }
+    }
+}
+
+/// a src in a plugin library, the plugin emits msgs from its own threads
+pub struct PluginSrc {
+    declare: Declare,
+    name: String,
+    cfg: serde_json::Value,
+}
+#[async_trait]
+impl Src for PluginSrc {
+    async fn from_src(&self, task_id: String, conf: &serde_json::Value, sender: mpsc::Sender<Msg>) {
+        info!("[src] plugin {} task_id {}", self.name, task_id);
+        let (acks, mut delivered) = mpsc::unbounded_channel::<Offset>();
+        // an address, a raw pointer would keep the future from being send
+        let at = Box::into_raw(Box::new(EmitCtx {
+            name: self.name.to_owned(),
+            sender,
+            acks,
+            closed: AtomicBool::new(false),
+        })) as usize;
+        // open may block on the plugin, like the open of a dst
+        let declare = self.declare;
+        let (id, cf) = (task_id.clone(), conf.clone());
+        let handle = tokio::task::spawn_blocking(move || {
+            open(declare, &id, &cf, Some(emit), at as *mut c_void)
+        })
+        .await;
+        let ctx = at as *mut EmitCtx;
+        let handle = match handle {
+            Ok(Ok(v)) => v,
+            Ok(Err(err)) => {
+                drop(unsafe { Box::from_raw(ctx) });
+                error!(
+                    "[src] plugin {} task_id {} open error {}",
+                    self.name, task_id, err
+                );
+                return;
+            }
+            // the plugin may still hold ctx after a panic, so it is leaked
+            Err(err) => {
+                error!(
+                    "[src] plugin {} task_id {} open error {}",
+                    self.name, task_id, err
+                );
+                return;
+            }
+        };
+        let handle = SrcHandle {
+            declare: self.declare,
+            handle,
+            ctx,
+        };
+        let ack = self.declare.0.ack.unwrap();
+        // ctx holds an ack sender, this runs until the task aborts the src
+        while let Some(offset) = delivered.recv().await {
+            ack(handle.handle.0, offset.offset as u64);
+        }
+    }
+
+    fn cfg(&self) -> serde_json::Value {
+        self.cfg.clone()
+    }
+
+    fn src_name(&self) -> String {
+        self.name.to_owned()
+    }
+
+    fn check_cfg(&self, conf: &serde_json::Value) -> Result<(), String> {
+        check_cfg(self.declare, conf)
+    }
+}
+
+struct SrcHandle {
+    declare: Declare,
+    handle: Handle,
+    ctx: *mut EmitCtx,
+}
+unsafe impl Send for SrcHandle {}
+unsafe impl Sync for SrcHandle {}
+
+impl Drop for SrcHandle {
+    fn drop(&mut self) {
+        unsafe { &*self.ctx }.closed.store(true, Ordering::Release);
+        (self.declare.0.close)(self.handle.0);
+        drop(unsafe { Box::from_raw(self.ctx) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr::null_mut;
+
+    use crate::transform::flatten::{chrysaetos, flatten_msg};
+
+    lazy_static! {
+        static ref WRITTEN: Mutex<Vec<String>> = Mutex::new(vec![]);
+    }
+
+    extern "C" fn free_string(s: *mut c_char) {
+        drop(unsafe { CString::from_raw(s) });
+    }
+
+    extern "C" fn check(conf: *const c_char) -> *mut c_char {
+        match c_str(conf).unwrap_or_default().contains("bad") {
+            true => CString::new("bad conf").unwrap().into_raw(),
+            false => null_mut(),
+        }
+    }
+
+    extern "C" fn open_dst(
+        _: *const c_char,
+        _: *const c_char,
+        _: Option<EmitFn>,
+        _: *mut c_void,
+        _: *mut *mut c_char,
+    ) -> *mut c_void {
+        Box::into_raw(Box::new(0u8)) as *mut c_void
+    }
+
+    extern "C" fn write(_: *mut c_void, data: *const u8, len: usize, _: *mut *mut c_char) -> i32 {
+        let rows = unsafe { std::slice::from_raw_parts(data, len) };
+        WRITTEN
+            .lock()
+            .unwrap()
+            .push(String::from_utf8_lossy(rows).into_owned());
+        0
+    }
+
+    extern "C" fn close(handle: *mut c_void) {
+        drop(unsafe { Box::from_raw(handle as *mut u8) });
+    }
+
+    fn declare(version: u32, name: &'static CStr) -> &'static PluginDeclare {
+        Box::leak(Box::new(PluginDeclare {
+            abi_version: version,
+            kind: PLUGIN_KIND_DST,
+            name: name.as_ptr(),
+            cfg: c"{\"path\": \"\"}".as_ptr(),
+            check_cfg: check,
+            open: open_dst,
+            write: Some(write),
+            ack: None,
+            close,
+            free_string,
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_plugin_dst() {
+        assert!(unsafe { register(declare(PLUGIN_ABI_VERSION + 1, c"old_dst")) }.is_err());
+        assert_eq!(
+            unsafe { register(declare(PLUGIN_ABI_VERSION, c"test_dst")) }.unwrap(),
+            "test_dst"
+        );
+        // built in plugins keep their name
+        assert!(unsafe { register(declare(PLUGIN_ABI_VERSION, c"stdout")) }.is_err());
+        let info = plugin_list()
+            .into_iter()
+            .find(|v| v.name == "test_dst")
+            .unwrap();
+        assert!(info.dynamic && info.kind == "dst");
+        assert_eq!(info.cfg["path"], "");
+
+        let dst = DST_PLUGIN.lock().unwrap().get("test_dst").unwrap().clone();
+        assert_eq!(
+            dst.check_cfg(&serde_json::json!({"path": "bad"}))
+                .unwrap_err(),
+            "bad conf"
+        );
+        let (sender, receive) = mpsc::channel(10);
+        let value = serde_json::json!({"a": 1});
+        let cry = chrysaetos(&"plugin-task".to_owned(), &serde_json::json!({}));
+        let msg = flatten_msg(&cry, Msg::new("g".to_owned(), value));
+        sender.send(msg).await.unwrap();
+        drop(sender);
+        dst.to_dst("plugin-task".to_owned(), serde_json::json!({}), receive)
+            .await;
+        assert_eq!(WRITTEN.lock().unwrap().as_slice(), [r#"[{"a":1}]"#]);
+    }
+}
@@ -0,0 +1,364 @@
+use async_trait::async_trait;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::core::{redact_secrets, Msg};
+
+use super::file::{FileDstConfig, RollingFile};
+use super::{declared_columns, msg_rows, undelivered, Dst};
+
+/// writes flattened rows as csv files with a fixed header.
+/// the header is the declared tasking cfg columns, or the union of the keys of the first rows.
+/// files are rotated and acked like the file dst
+pub struct CsvDst {}
+#[async_trait]
+impl Dst for CsvDst {
+    async fn to_dst(
+        &self,
+        task_id: String,
+        conf: serde_json::Value,
+        mut receive: mpsc::Receiver<Msg>,
+    ) {
+        info!(
+            "[dst] {} task_id:{} conf {}",
+            self.dst_name(),
+            task_id,
+            redact_secrets(&conf)
+        );
+        let sfc = match check_csv_dst_cfg(&conf) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("[dst] csv task_id {} {}", task_id, err);
+                return;
+            }
+        };
+        let mut table = CsvTable::new(task_id.clone(), sfc, declared_columns(&task_id, &conf));
+
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                res = receive.recv() => {
+                    let mut msg = match res {
+                        Some(v) => v,
+                        None => break,
+                    };
+                    let rows = match msg_rows(&task_id, &mut msg).await {
+                        Some(v) => v,
+                        None => continue,
+                    };
+                    debug!(
+                        "[dst] csv task_id:{} g_id:{} rows {}",
+                        task_id,
+                        msg.g_id,
+                        rows.len()
+                    );
+                    // error policy stops the task, the msg stays uncommitted
+                    if let Err(err) = table.check_columns(&rows) {
+                        error!("[dst] csv task_id {}, g_id {} {}", task_id, msg.g_id, err);
+                        break;
+                    }
+                    if let Err(err) = table.write_msg(rows, msg) {
+                        undelivered(&task_id, table.rolling.abandon(), err, "csv_write").await;
+                    }
+                }
+                _ = tick.tick() => {
+                    if let Err(err) = table.tick() {
+                        undelivered(&task_id, table.rolling.abandon(), err, "csv_write").await;
+                    }
+                }
+            }
+        }
+
+        if let Err(err) = table.close() {
+            undelivered(&task_id, table.rolling.abandon(), err, "csv_write").await;
+        }
+        info!("[dst] csv task_id {} exit", task_id);
+    }
+
+    fn cfg(&self) -> serde_json::Value {
+        serde_json::json!(CsvDstConfig::default())
+    }
+
+    fn dst_name(&self) -> String {
+        "csv".to_owned()
+    }
+
+    fn check_cfg(&self, conf: &serde_json::Value) -> Result<(), String> {
+        check_csv_dst_cfg(conf).map(|_| ())
+    }
+}
+
+/// what to do with a column that is not in the header
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NewColumnPolicy {
+    // drop the value
+    #[default]
+    Ignore,
+    // append the column to the header and start a new file
+    NewFile,
+    // stop the task
+    Error,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+pub struct CsvDstConfig {
+    #[serde(flatten)]
+    pub file: FileDstConfig,
+    // infer the header from this many first rows when no columns are declared
+    #[serde(default = "default_infer_rows")]
+    pub infer_rows: usize,
+    // infer the header from fewer rows when they wait this many seconds
+    #[serde(default = "default_infer_secs")]
+    pub infer_secs: u64,
+    #[serde(default)]
+    pub new_column: NewColumnPolicy,
+}
+
+fn default_infer_rows() -> usize {
+    100
+}
+
+fn default_infer_secs() -> u64 {
+    10
+}
+
+pub fn check_csv_dst_cfg(conf: &serde_json::Value) -> Result<CsvDstConfig, String> {
+    let cfg = match serde_json::from_value::<CsvDstConfig>(conf.clone()) {
+        Ok(v) => v,
+        Err(err) => return Err(format!("invalid config  {} error {:?}", conf, err)),
+    };
+    if cfg.file.dir.is_empty() {
+        return Err(format!("invalid config  {} dir is required", conf));
+    }
+    if cfg.infer_rows == 0 {
+        return Err(format!(
+            "invalid config  {} infer_rows must be over 0",
+            conf
+        ));
+    }
+    Ok(cfg)
+}
+
+type Row = HashMap<String, serde_json::Value>;
+
+struct CsvTable {
+    rolling: RollingFile,
+    cfg: CsvDstConfig,
+    // empty until declared or inferred
+    header: Vec<String>,
+    // rows and msgs waiting for the header to be inferred
+    sample: Vec<Row>,
+    sample_msgs: Vec<Msg>,
+    sample_at: Option<Instant>,
+}
+
+impl CsvTable {
+    fn new(task_id: String, cfg: CsvDstConfig, columns: Vec<String>) -> Self {
+        let mut table = CsvTable {
+            rolling: RollingFile::new(task_id, cfg.file.clone(), "csv"),
+            cfg,
+            header: vec![],
+            sample: vec![],
+            sample_msgs: vec![],
+            sample_at: None,
+        };
+        if !columns.is_empty() {
+            table.set_header(columns);
+        }
+        table
+    }
+
+    fn set_header(&mut self, header: Vec<String>) {
+        self.rolling.header = Some(csv_line(&header));
+        self.header = header;
+    }
+
+    // columns of rows missing in the header, sorted for a stable header
+    fn new_columns(&self, rows: &[Row]) -> Vec<String> {
+        if self.header.is_empty() {
+            return vec![];
+        }
+        let columns: BTreeSet<&String> = rows
+            .iter()
+            .flat_map(|row| row.keys())
+            .filter(|k| !self.header.contains(k))
+            .collect();
+        columns.into_iter().cloned().collect()
+    }
+
+    fn check_columns(&self, rows: &[Row]) -> Result<(), String> {
+        if self.cfg.new_column != NewColumnPolicy::Error {
+            return Ok(());
+        }
+        let columns = self.new_columns(rows);
+        if !columns.is_empty() {
+            return Err(format!(
+                "columns {:?} are not in header {:?}",
+                columns, self.header
+            ));
+        }
+        Ok(())
+    }
+
+    // the msg is pending until the file of its rows is closed,
+    // on error it is pending with the msgs of the failed file
+    fn write_msg(&mut self, rows: Vec<Row>, msg: Msg) -> Result<(), String> {
+        if self.header.is_empty() {
+            if self.sample_at.is_none() {
+                self.sample_at = Some(Instant::now());
+            }
+            self.sample.extend(rows);
+            self.sample_msgs.push(msg);
+            if self.sample.len() >= self.cfg.infer_rows {
+                self.flush_sample()?;
+            }
+            return Ok(());
+        }
+
+        let columns = self.new_columns(&rows);
+        if !columns.is_empty() && self.cfg.new_column == NewColumnPolicy::NewFile {
+            if let Err(err) = self.rolling.close() {
+                self.rolling.pending.push(msg);
+                return Err(err);
+            }
+            let mut header = self.header.clone();
+            header.extend(columns);
+            self.set_header(header);
+        }
+        self.rolling.pending.push(msg);
+        for row in &rows {
+            self.write_row(row)?;
+        }
+        self.rolling.rotate_if_needed()
+    }
+
+    fn write_row(&mut self, row: &Row) -> Result<(), String> {
+        let fields: Vec<String> = self
+            .header
+            .iter()
+            .map(|column| cell(row.get(column)))
+            .collect();
+        self.rolling.write_line(&csv_line(&fields))
+    }
+
+    // infer the header from the sampled rows and write them
+    fn flush_sample(&mut self) -> Result<(), String> {
+        self.sample_at = None;
+        let rows: Vec<Row> = self.sample.drain(..).collect();
+        let mut msgs: Vec<Msg> = self.sample_msgs.drain(..).collect();
+        if rows.is_empty() {
+            // msgs without rows have nothing to wait for
+            msgs.iter_mut().for_each(|msg| msg.ack());
+            return Ok(());
+        }
+        let columns: BTreeSet<&String> = rows.iter().flat_map(|row| row.keys()).collect();
+        self.set_header(columns.into_iter().cloned().collect());
+        self.rolling.pending.extend(msgs);
+        for row in &rows {
+            self.write_row(row)?;
+        }
+        self.rolling.rotate_if_needed()
+    }
+
+    fn tick(&mut self) -> Result<(), String> {
+        if let Some(at) = self.sample_at {
+            if at.elapsed() >= Duration::from_secs(self.cfg.infer_secs) {
+                self.flush_sample()?;
+            }
+        }
+        self.rolling.rotate_if_needed()
+    }
+
+    fn close(&mut self) -> Result<(), String> {
+        if self.header.is_empty() {
+            self.flush_sample()?;
+        }
+        self.rolling.close()
+    }
+}
+
+// null is an empty cell, folded objects and arrays are json strings
+pub(crate) fn cell(value: Option<&serde_json::Value>) -> String {
+    match value {
+        None | Some(serde_json::Value::Null) => String::new(),
+        Some(serde_json::Value::String(v)) => v.to_owned(),
+        Some(v) => v.to_string(),
+    }
+}
+
+// one csv record without the line terminator
+pub(crate) fn csv_line(fields: &[String]) -> String {
+    let mut writer = csv::WriterBuilder::new().from_writer(vec![]);
+    // writing into a vec never fails
+    writer.write_record(fields).unwrap();
+    let mut line = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+    line.truncate(line.trim_end_matches('\n').len());
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::transform::flatten::{chrysaetos, flatten_msg};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_csv_dst_new_file() {
+        let dir = std::env::temp_dir().join(format!("varbit-csv-dst-{}", uuid::Uuid::new_v4()));
+        let (sender, receive) = mpsc::channel::<Msg>(10);
+        let conf = serde_json::json!({
+            "dir": dir.to_str().unwrap(),
+            "infer_rows": 2,
+            "new_column": "new_file",
+            "tasking_cfg": {"sep": "_", "max_depth": 32, "ignore": [], "fold": ["b"]},
+        });
+        let cry = chrysaetos(&"task".to_owned(), &conf);
+        let handler = tokio::spawn(async move {
+            CsvDst {}.to_dst("task".to_owned(), conf, receive).await;
+        });
+        for (g_id, value) in [
+            ("1", serde_json::json!({"a": 1, "b": {"c": "x,y"}})),
+            ("2", serde_json::json!({"a": 2})),
+            ("3", serde_json::json!({"a": 3, "d": true})),
+        ] {
+            let msg = flatten_msg(&cry, Msg::new(g_id.to_owned(), value));
+            sender.send(msg).await.unwrap();
+        }
+        drop(sender);
+        handler.await.unwrap();
+
+        let mut names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort_by_key(|name| name.rsplit('-').next().unwrap().to_owned());
+        assert_eq!(names.len(), 2);
+        assert_eq!(
+            fs::read_to_string(dir.join(&names[0])).unwrap(),
+            "a,b\n1,\"{\"\"c\"\":\"\"x,y\"\"}\"\n2,\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join(&names[1])).unwrap(),
+            "a,b,d\n3,,true\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_csv_columns_policy() {
+        let cfg =
+            check_csv_dst_cfg(&serde_json::json!({"dir": "/tmp", "new_column": "error"})).unwrap();
+        let table = CsvTable::new("task".to_owned(), cfg, vec!["a".to_owned()]);
+        let mut row = Row::new();
+        row.insert("a".to_owned(), serde_json::json!(1));
+        assert!(table.check_columns(&[row.clone()]).is_ok());
+        row.insert("b".to_owned(), serde_json::json!(1));
+        assert!(table.check_columns(&[row]).is_err());
+    }
+}
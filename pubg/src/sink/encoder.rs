@@ -0,0 +1,630 @@
+/// payload encodings of flattened rows, selected by the dst encoder field
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+
+use super::csv::csv_line;
+
+pub const ENCODERS: [&str; 5] = ["json", "ndjson", "csv", "avro", "msgpack"];
+
+// crc-64-avro of the empty input
+const AVRO_EMPTY_FINGERPRINT: u64 = 0xc15d213aa4d7a795;
+
+// avro single object encoding marker
+const AVRO_SINGLE_OBJECT_MAGIC: [u8; 2] = [0xc3, 0x01];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoder {
+    // one json row per payload
+    Json,
+    // every row of a msg in one payload, a row per line
+    Ndjson,
+    // one csv line per payload, no header
+    Csv,
+    Avro,
+    Msgpack,
+}
+
+impl Encoder {
+    // empty is json, as before encoders were honored
+    fn parse(encoder: &str) -> Result<Encoder, String> {
+        match encoder {
+            "" | "json" => Ok(Encoder::Json),
+            "ndjson" => Ok(Encoder::Ndjson),
+            "csv" => Ok(Encoder::Csv),
+            "avro" => Ok(Encoder::Avro),
+            "msgpack" => Ok(Encoder::Msgpack),
+            _ => Err(format!(
+                "invalid encoder {} expected one of {:?}",
+                encoder, ENCODERS
+            )),
+        }
+    }
+}
+
+/// the avro schema is taken from the first msg a dst encodes, with the declared
+/// columns or the keys of its rows, and stays fixed while the dst runs.
+/// a later msg with a row that does not fit it goes to the dead letter dst
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct AvroConfig {
+    // record name of the generated schema
+    #[serde(default = "default_record_name")]
+    pub name: String,
+    #[serde(default)]
+    pub namespace: String,
+    // register schemas and use the confluent wire format,
+    // without it payloads use the avro single object encoding
+    #[serde(default)]
+    pub registry_url: String,
+    // registry subject, empty is <topic>-value
+    #[serde(default)]
+    pub subject: String,
+}
+
+fn default_record_name() -> String {
+    "Row".to_owned()
+}
+
+impl Default for AvroConfig {
+    fn default() -> Self {
+        AvroConfig {
+            name: default_record_name(),
+            namespace: String::new(),
+            registry_url: String::new(),
+            subject: String::new(),
+        }
+    }
+}
+
+pub fn check_encoder(encoder: &str, avro: &AvroConfig) -> Result<(), String> {
+    Encoder::parse(encoder)?;
+    if !valid_avro_name(&avro.name) {
+        return Err(format!("invalid avro record name {:?}", avro.name));
+    }
+    if !avro.namespace.is_empty() && !avro.namespace.split('.').all(valid_avro_name) {
+        return Err(format!("invalid avro namespace {:?}", avro.namespace));
+    }
+    if !avro.registry_url.is_empty() && reqwest::Url::parse(&avro.registry_url).is_err() {
+        return Err(format!("invalid avro registry_url {:?}", avro.registry_url));
+    }
+    Ok(())
+}
+
+type Row = HashMap<String, serde_json::Value>;
+
+/// encodes the rows of a msg into payloads
+pub struct RowEncoder {
+    encoder: Encoder,
+    // csv column order, empty is the sorted row keys
+    columns: Vec<String>,
+    avro: AvroConfig,
+    subject: String,
+    client: reqwest::Client,
+    // registry id of every registered schema
+    schema_ids: HashMap<String, u32>,
+    // schema of the first msg, fixed so registry and single object readers can follow
+    avro_schema: Option<AvroSchema>,
+}
+
+impl RowEncoder {
+    pub fn new(
+        encoder: &str,
+        columns: Vec<String>,
+        avro: &AvroConfig,
+        topic: &str,
+    ) -> Result<Self, String> {
+        check_encoder(encoder, avro)?;
+        let subject = if avro.subject.is_empty() {
+            format!("{}-value", topic)
+        } else {
+            avro.subject.clone()
+        };
+        let client = match reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+        {
+            Ok(v) => v,
+            Err(err) => return Err(format!("build registry client error {:?}", err)),
+        };
+        Ok(RowEncoder {
+            encoder: Encoder::parse(encoder)?,
+            columns,
+            avro: avro.clone(),
+            subject,
+            client,
+            schema_ids: HashMap::new(),
+            avro_schema: None,
+        })
+    }
+
+    /// payloads of rows, each with the index of the row its key is taken from
+    pub async fn encode(&mut self, rows: &[Row]) -> Result<Vec<(usize, Vec<u8>)>, String> {
+        // the avro schema is not fixed by a msg without rows
+        if rows.is_empty() {
+            return Ok(vec![]);
+        }
+        if self.encoder == Encoder::Ndjson {
+            let mut payload = vec![];
+            for row in rows {
+                payload.extend(serde_json::json!(row).to_string().into_bytes());
+                payload.push(b'\n');
+            }
+            return Ok(vec![(0, payload)]);
+        }
+        // one schema for every row the dst encodes
+        let schema = if self.encoder == Encoder::Avro {
+            let schema = match self.avro_schema.take() {
+                Some(v) => v,
+                None => {
+                    let mut fields = AvroSchema::widen(&[], &self.columns, rows);
+                    // a field only null so far may hold any value later
+                    fields
+                        .iter_mut()
+                        .for_each(|f| f.avro_type = f.avro_type.or(Some(AvroType::String)));
+                    AvroSchema::new(fields, &self.avro)
+                }
+            };
+            let fit = rows
+                .iter()
+                .try_for_each(|row| schema.fits(row, &self.columns));
+            self.avro_schema = Some(schema.clone());
+            fit?;
+            Some(schema)
+        } else {
+            None
+        };
+        let mut payloads = Vec::with_capacity(rows.len());
+        for (i, row) in rows.iter().enumerate() {
+            payloads.push((i, self.encode_row(row, schema.as_ref()).await?));
+        }
+        Ok(payloads)
+    }
+
+    async fn encode_row(
+        &mut self,
+        row: &Row,
+        schema: Option<&AvroSchema>,
+    ) -> Result<Vec<u8>, String> {
+        match self.encoder {
+            Encoder::Json | Encoder::Ndjson => Ok(serde_json::json!(row).to_string().into_bytes()),
+            Encoder::Csv => {
+                let columns: Vec<String> = if self.columns.is_empty() {
+                    let mut keys: Vec<String> = row.keys().cloned().collect();
+                    keys.sort();
+                    keys
+                } else {
+                    self.columns.clone()
+                };
+                let fields: Vec<String> = columns
+                    .iter()
+                    .map(|c| super::csv::cell(row.get(c)))
+                    .collect();
+                Ok(csv_line(&fields).into_bytes())
+            }
+            Encoder::Msgpack => match rmp_serde::to_vec_named(row) {
+                Ok(v) => Ok(v),
+                Err(err) => Err(format!("msgpack encode error {:?}", err)),
+            },
+            Encoder::Avro => {
+                let schema = match schema {
+                    Some(v) => v,
+                    None => return Err("avro schema missing".to_owned()),
+                };
+                let datum = schema.encode(row);
+                if self.avro.registry_url.is_empty() {
+                    let mut payload = AVRO_SINGLE_OBJECT_MAGIC.to_vec();
+                    payload.extend(avro_fingerprint(schema.json.as_bytes()).to_le_bytes());
+                    payload.extend(datum);
+                    return Ok(payload);
+                }
+                let id = self.schema_id(&schema.json).await?;
+                // confluent wire format
+                let mut payload = vec![0u8];
+                payload.extend(id.to_be_bytes());
+                payload.extend(datum);
+                Ok(payload)
+            }
+        }
+    }
+
+    // register schema once, the registry returns the id of an existing schema as well
+    async fn schema_id(&mut self, schema: &String) -> Result<u32, String> {
+        if let Some(id) = self.schema_ids.get(schema) {
+            return Ok(*id);
+        }
+        let url = format!(
+            "{}/subjects/{}/versions",
+            self.avro.registry_url.trim_end_matches('/'),
+            self.subject
+        );
+        let resp = match self
+            .client
+            .post(&url)
+            .header("content-type", "application/vnd.schemaregistry.v1+json")
+            .body(serde_json::json!({ "schema": schema }).to_string())
+            .send()
+            .await
+        {
+            Ok(v) => v,
+            Err(err) => return Err(format!("register schema {} error {:?}", url, err)),
+        };
+        let status = resp.status();
+        let body = match resp.text().await {
+            Ok(v) => v,
+            Err(err) => return Err(format!("register schema {} error {:?}", url, err)),
+        };
+        if !status.is_success() {
+            return Err(format!(
+                "register schema {} status {} {}",
+                url, status, body
+            ));
+        }
+        let id = match serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|v| v["id"].as_u64())
+        {
+            Some(v) => v as u32,
+            None => return Err(format!("register schema {} invalid response {}", url, body)),
+        };
+        info!(
+            "registered avro schema {} id {} {}",
+            self.subject, id, schema
+        );
+        self.schema_ids.insert(schema.clone(), id);
+        Ok(id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AvroType {
+    Boolean,
+    Long,
+    Double,
+    // strings, folded objects and arrays as json
+    String,
+}
+
+impl AvroType {
+    // null tells nothing about the type
+    fn of(value: &serde_json::Value) -> Option<AvroType> {
+        match value {
+            serde_json::Value::Null => None,
+            serde_json::Value::Bool(_) => Some(AvroType::Boolean),
+            serde_json::Value::Number(v) if v.is_i64() => Some(AvroType::Long),
+            serde_json::Value::Number(_) => Some(AvroType::Double),
+            _ => Some(AvroType::String),
+        }
+    }
+
+    // numbers widen to double and anything else to string
+    fn widen(self, other: AvroType) -> AvroType {
+        if self == other {
+            self
+        } else if self != AvroType::Boolean
+            && other != AvroType::Boolean
+            && (self == AvroType::Double || other == AvroType::Double)
+        {
+            AvroType::Double
+        } else {
+            AvroType::String
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            AvroType::Boolean => "boolean",
+            AvroType::Long => "long",
+            AvroType::Double => "double",
+            AvroType::String => "string",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct AvroField {
+    // row key
+    key: String,
+    // key as an avro name unique in the record
+    name: String,
+    // none while only nulls were seen, the field is then of the null type
+    avro_type: Option<AvroType>,
+}
+
+/// record schema of flattened rows, every typed field is a union of null and its type
+#[derive(Clone)]
+struct AvroSchema {
+    fields: Vec<AvroField>,
+    // parsing canonical form, also the fingerprinted form
+    json: String,
+}
+
+impl AvroSchema {
+    fn new(fields: Vec<AvroField>, cfg: &AvroConfig) -> Self {
+        let fullname = if cfg.namespace.is_empty() {
+            cfg.name.clone()
+        } else {
+            format!("{}.{}", cfg.namespace, cfg.name)
+        };
+        let defs: Vec<String> = fields
+            .iter()
+            .map(|f| match f.avro_type {
+                Some(t) => format!(r#"{{"name":"{}","type":["null","{}"]}}"#, f.name, t.name()),
+                None => format!(r#"{{"name":"{}","type":"null"}}"#, f.name),
+            })
+            .collect();
+        let json = format!(
+            r#"{{"name":"{}","type":"record","fields":[{}]}}"#,
+            fullname,
+            defs.join(",")
+        );
+        AvroSchema { fields, json }
+    }
+
+    // fields of the declared columns, or of every key seen sorted by key,
+    // with types widened over rows. names stay as first given
+    fn widen(fields: &[AvroField], columns: &[String], rows: &[Row]) -> Vec<AvroField> {
+        let mut fields = fields.to_vec();
+        let keys: Vec<&String> = if columns.is_empty() {
+            let keys: BTreeSet<&String> = rows.iter().flat_map(|r| r.keys()).collect();
+            keys.into_iter().collect()
+        } else {
+            columns.iter().collect()
+        };
+        for key in keys {
+            if !fields.iter().any(|f| &f.key == key) {
+                let name = unique_avro_name(key, &fields);
+                fields.push(AvroField {
+                    key: key.to_owned(),
+                    name,
+                    avro_type: None,
+                });
+            }
+        }
+        if columns.is_empty() {
+            fields.sort_by(|a, b| a.key.cmp(&b.key));
+        }
+        for f in fields.iter_mut() {
+            for t in rows
+                .iter()
+                .filter_map(|r| r.get(&f.key).and_then(AvroType::of))
+            {
+                f.avro_type = Some(match f.avro_type {
+                    Some(v) => v.widen(t),
+                    None => t,
+                });
+            }
+        }
+        fields
+    }
+
+    // row can be encoded without changing the schema, a key outside the declared
+    // columns is left out and a long fits a double field
+    fn fits(&self, row: &Row, columns: &[String]) -> Result<(), String> {
+        for (key, value) in row {
+            let t = match AvroType::of(value) {
+                Some(v) => v,
+                None => continue,
+            };
+            let field = match self.fields.iter().find(|f| &f.key == key) {
+                Some(v) => v,
+                None if !columns.is_empty() => continue,
+                None => return Err(format!("column {} is not in the avro schema", key)),
+            };
+            let fits = match field.avro_type {
+                Some(AvroType::String) => true,
+                Some(AvroType::Double) => t == AvroType::Long || t == AvroType::Double,
+                Some(v) => v == t,
+                None => false,
+            };
+            if !fits {
+                return Err(format!(
+                    "column {} {} does not fit avro type {}",
+                    key,
+                    value,
+                    field.avro_type.map_or("null", |v| v.name())
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // binary datum of row
+    fn encode(&self, row: &Row) -> Vec<u8> {
+        let mut out = vec![];
+        for f in &self.fields {
+            let t = match f.avro_type {
+                Some(v) => v,
+                // the null type has no bytes
+                None => continue,
+            };
+            let value = match row.get(&f.key) {
+                None | Some(serde_json::Value::Null) => {
+                    write_long(&mut out, 0);
+                    continue;
+                }
+                Some(v) => v,
+            };
+            write_long(&mut out, 1);
+            match t {
+                AvroType::Boolean => out.push(value.as_bool().unwrap_or_default() as u8),
+                AvroType::Long => write_long(&mut out, value.as_i64().unwrap_or_default()),
+                AvroType::Double => out.extend(value.as_f64().unwrap_or_default().to_le_bytes()),
+                AvroType::String => {
+                    let s = match value {
+                        serde_json::Value::String(s) => s.to_owned(),
+                        v => v.to_string(),
+                    };
+                    write_long(&mut out, s.len() as i64);
+                    out.extend(s.as_bytes());
+                }
+            }
+        }
+        out
+    }
+}
+
+// zigzag varint
+fn write_long(out: &mut Vec<u8>, v: i64) {
+    let mut n = ((v << 1) ^ (v >> 63)) as u64;
+    while n >= 0x80 {
+        out.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn valid_avro_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// flattened key as an avro name, invalid chars become _
+fn avro_name(key: &str) -> String {
+    let mut name: String = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !valid_avro_name(&name) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+// avro name of key, suffixed when another field already has it
+fn unique_avro_name(key: &str, fields: &[AvroField]) -> String {
+    let name = avro_name(key);
+    let mut unique = name.clone();
+    let mut n = 1;
+    while fields.iter().any(|f| f.name == unique) {
+        n += 1;
+        unique = format!("{}_{}", name, n);
+    }
+    unique
+}
+
+// crc-64-avro rabin fingerprint
+fn avro_fingerprint(data: &[u8]) -> u64 {
+    let mut table = [0u64; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut fp = i as u64;
+        for _ in 0..8 {
+            fp = (fp >> 1) ^ (AVRO_EMPTY_FINGERPRINT & (fp & 1).wrapping_neg());
+        }
+        *entry = fp;
+    }
+    let mut fp = AVRO_EMPTY_FINGERPRINT;
+    for b in data {
+        fp = (fp >> 8) ^ table[((fp ^ *b as u64) & 0xff) as usize];
+    }
+    fp
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    fn row(value: serde_json::Value) -> Row {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_encoders() {
+        let rows = vec![
+            row(serde_json::json!({"b": "x,y", "a": 1})),
+            row(serde_json::json!({"a": 2})),
+        ];
+        let avro = AvroConfig::default();
+        let mut encoder = RowEncoder::new("csv", vec![], &avro, "t").unwrap();
+        let res = encoder.encode(&rows).await.unwrap();
+        assert_eq!(res[0], (0, b"1,\"x,y\"".to_vec()));
+
+        let mut encoder = RowEncoder::new("ndjson", vec![], &avro, "t").unwrap();
+        let res = encoder.encode(&rows).await.unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(
+            String::from_utf8(res[0].1.clone()).unwrap().lines().count(),
+            2
+        );
+
+        let mut encoder = RowEncoder::new("msgpack", vec![], &avro, "t").unwrap();
+        let res = encoder.encode(&rows[1..]).await.unwrap();
+        let decoded: Row = rmp_serde::from_slice(&res[0].1).unwrap();
+        assert_eq!(decoded, rows[1]);
+
+        assert!(RowEncoder::new("xml", vec![], &avro, "t").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_avro_encode() {
+        assert_eq!(avro_fingerprint(b"\"null\""), 7195948357588979594);
+
+        let row = row(serde_json::json!({"a": -1, "b-c": null, "d": "hi"}));
+        let fields = AvroSchema::widen(&[], &[], std::slice::from_ref(&row));
+        let schema = AvroSchema::new(fields.clone(), &AvroConfig::default());
+        assert_eq!(
+            schema.json,
+            r#"{"name":"Row","type":"record","fields":[{"name":"a","type":["null","long"]},{"name":"b_c","type":"null"},{"name":"d","type":["null","string"]}]}"#
+        );
+        // a: branch 1, zigzag -1; b_c: no bytes; d: branch 1, len 2, "hi"
+        assert_eq!(schema.encode(&row), vec![2, 1, 2, 4, b'h', b'i']);
+
+        // widened across rows, b_c keeps its name and the new key gets a unique one
+        let rows = vec![
+            serde_json::from_value(serde_json::json!({"a": 1.5, "b_c": "x"})).unwrap(),
+            serde_json::from_value(serde_json::json!({"b-c": 2})).unwrap(),
+        ];
+        let widened = AvroSchema::widen(&fields, &[], &rows);
+        let types: Vec<(&str, Option<AvroType>)> = widened
+            .iter()
+            .map(|f| (f.name.as_str(), f.avro_type))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                ("a", Some(AvroType::Double)),
+                ("b_c", Some(AvroType::Long)),
+                ("b_c_2", Some(AvroType::String)),
+                ("d", Some(AvroType::String)),
+            ]
+        );
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/subjects/t-value/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"id":7}"#))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let avro = AvroConfig {
+            registry_url: server.uri(),
+            ..Default::default()
+        };
+        let mut encoder = RowEncoder::new("avro", vec![], &avro, "t").unwrap();
+        let res = encoder.encode(&[row.clone(), row]).await.unwrap();
+        assert_eq!(res[1].1[..5], [0, 0, 0, 0, 7]);
+        // the schema stays as the first msg made it, b_c was only null so it is a string
+        let fits = serde_json::json!({"a": 2, "b-c": 3, "d": "x"});
+        assert!(encoder
+            .encode(&[serde_json::from_value::<Row>(fits).unwrap()])
+            .await
+            .is_ok());
+        let wider = serde_json::json!({"a": "x"});
+        assert!(encoder
+            .encode(&[serde_json::from_value::<Row>(wider).unwrap()])
+            .await
+            .is_err());
+        let new_key = serde_json::json!({"e": 1});
+        assert!(encoder
+            .encode(&[serde_json::from_value::<Row>(new_key).unwrap()])
+            .await
+            .is_err());
+    }
+}
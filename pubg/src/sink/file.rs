@@ -0,0 +1,444 @@
+use async_trait::async_trait;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::core::{redact_secrets, Msg};
+
+use super::{msg_rows, undelivered, Dst};
+
+// rotate at 128MiB by default
+const DEFAULT_MAX_BYTES: u64 = 128 * 1024 * 1024;
+// rows are acked when their file closes, so a slow topic still commits every minute
+const DEFAULT_MAX_SECS: u64 = 60;
+
+/// writes flattened rows as json-lines files.
+/// a file is written as a hidden .tmp file and renamed when it is closed,
+/// rows are acked to the src once their file is closed. when a write fails
+/// the file is dropped and its msgs go to the dead letter dst
+pub struct FileDst {}
+#[async_trait]
+impl Dst for FileDst {
+    async fn to_dst(
+        &self,
+        task_id: String,
+        conf: serde_json::Value,
+        mut receive: mpsc::Receiver<Msg>,
+    ) {
+        info!(
+            "[dst] {} task_id:{} conf {}",
+            self.dst_name(),
+            task_id,
+            redact_secrets(&conf)
+        );
+        let sfc = match check_file_dst_cfg(&conf) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("[dst] file task_id {} {}", task_id, err);
+                return;
+            }
+        };
+        let mut rolling = RollingFile::new(task_id.clone(), sfc, "jsonl");
+
+        // time rotation must happen without new msgs as well
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                res = receive.recv() => {
+                    let mut msg = match res {
+                        Some(v) => v,
+                        None => break,
+                    };
+                    let res = match msg_rows(&task_id, &mut msg).await {
+                        Some(v) => v,
+                        None => continue,
+                    };
+                    debug!(
+                        "[dst] file task_id:{} g_id:{} rows {}",
+                        task_id,
+                        msg.g_id,
+                        res.len()
+                    );
+                    let written = res
+                        .iter()
+                        .try_for_each(|data| rolling.write_line(&serde_json::json!(data).to_string()));
+                    match written {
+                        Ok(_) => rolling.pending.push(msg),
+                        Err(err) => {
+                            let mut msgs = rolling.abandon();
+                            msgs.push(msg);
+                            undelivered(&task_id, msgs, err, "file_write").await;
+                        }
+                    }
+                    if let Err(err) = rolling.rotate_if_needed() {
+                        undelivered(&task_id, rolling.abandon(), err, "file_write").await;
+                    }
+                }
+                _ = tick.tick() => {
+                    if let Err(err) = rolling.rotate_if_needed() {
+                        undelivered(&task_id, rolling.abandon(), err, "file_write").await;
+                    }
+                }
+            }
+        }
+
+        if let Err(err) = rolling.close() {
+            undelivered(&task_id, rolling.abandon(), err, "file_write").await;
+        }
+        info!("[dst] file task_id {} exit", task_id);
+    }
+
+    fn cfg(&self) -> serde_json::Value {
+        serde_json::json!(FileDstConfig::default())
+    }
+
+    fn dst_name(&self) -> String {
+        "file".to_owned()
+    }
+
+    fn check_cfg(&self, conf: &serde_json::Value) -> Result<(), String> {
+        check_file_dst_cfg(conf).map(|_| ())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FileDstConfig {
+    // output directory
+    pub dir: String,
+    // rotate after this many uncompressed bytes, 0 is off
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+    // rotate after a file is open this many seconds, 0 is off
+    #[serde(default = "default_max_secs")]
+    pub max_secs: u64,
+    // gzip the files
+    #[serde(default)]
+    pub gzip: bool,
+}
+
+impl Default for FileDstConfig {
+    fn default() -> Self {
+        FileDstConfig {
+            dir: String::new(),
+            max_bytes: DEFAULT_MAX_BYTES,
+            max_secs: DEFAULT_MAX_SECS,
+            gzip: false,
+        }
+    }
+}
+
+fn default_max_bytes() -> u64 {
+    DEFAULT_MAX_BYTES
+}
+
+pub(crate) fn default_max_secs() -> u64 {
+    DEFAULT_MAX_SECS
+}
+
+pub fn check_file_dst_cfg(conf: &serde_json::Value) -> Result<FileDstConfig, String> {
+    let cfg = match serde_json::from_value::<FileDstConfig>(conf.clone()) {
+        Ok(v) => v,
+        Err(err) => return Err(format!("invalid config  {} error {:?}", conf, err)),
+    };
+    if cfg.dir.is_empty() {
+        return Err(format!("invalid config  {} dir is required", conf));
+    }
+    Ok(cfg)
+}
+
+enum FileWriter {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl FileWriter {
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        match self {
+            FileWriter::Plain(w) => w.write_all(buf),
+            FileWriter::Gzip(w) => w.write_all(buf),
+        }
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        let mut w = match self {
+            FileWriter::Plain(w) => w,
+            FileWriter::Gzip(w) => w.finish()?,
+        };
+        w.flush()?;
+        w.get_ref().sync_all()
+    }
+}
+
+pub(crate) struct OpenFile {
+    writer: FileWriter,
+    tmp_path: PathBuf,
+    path: PathBuf,
+    bytes: u64,
+    opened_at: Instant,
+}
+
+/// line based file of a dst, rotated by size or time
+pub(crate) struct RollingFile {
+    task_id: String,
+    cfg: FileDstConfig,
+    ext: &'static str,
+    // tells apart the files of dsts sharing a dir and of earlier runs
+    id: String,
+    seq: u64,
+    curr: Option<OpenFile>,
+    // first line of every file
+    pub(crate) header: Option<String>,
+    // msgs of the rows in the current file
+    pub(crate) pending: Vec<Msg>,
+}
+
+impl RollingFile {
+    pub(crate) fn new(task_id: String, cfg: FileDstConfig, ext: &'static str) -> Self {
+        RollingFile {
+            task_id,
+            cfg,
+            ext,
+            id: uuid::Uuid::new_v4().simple().to_string()[..8].to_owned(),
+            seq: 0,
+            curr: None,
+            header: None,
+            pending: vec![],
+        }
+    }
+
+    // like <task_id>-20231201083000-1a2b3c4d-0.jsonl.gz
+    fn file_name(&self) -> String {
+        format!(
+            "{}-{}-{}-{}.{}{}",
+            self.task_id,
+            chrono::Local::now().format("%Y%m%d%H%M%S"),
+            self.id,
+            self.seq,
+            self.ext,
+            if self.cfg.gzip { ".gz" } else { "" }
+        )
+    }
+
+    fn open(&mut self) -> Result<(), String> {
+        if let Err(err) = fs::create_dir_all(&self.cfg.dir) {
+            return Err(format!("create dir {} error {:?}", self.cfg.dir, err));
+        }
+        let name = self.file_name();
+        self.seq += 1;
+        let path = PathBuf::from(&self.cfg.dir).join(&name);
+        let tmp_path = PathBuf::from(&self.cfg.dir).join(format!(".{}.tmp", name));
+        let file = match File::create(&tmp_path) {
+            Ok(v) => v,
+            Err(err) => return Err(format!("create file {:?} error {:?}", tmp_path, err)),
+        };
+        let writer = if self.cfg.gzip {
+            FileWriter::Gzip(GzEncoder::new(BufWriter::new(file), Compression::default()))
+        } else {
+            FileWriter::Plain(BufWriter::new(file))
+        };
+        info!("[dst] file task_id {} open {:?}", self.task_id, path);
+        self.curr = Some(OpenFile {
+            writer,
+            tmp_path,
+            path,
+            bytes: 0,
+            opened_at: Instant::now(),
+        });
+        if let Some(header) = self.header.clone() {
+            self.write_raw(&header)?;
+        }
+        Ok(())
+    }
+
+    // write line and a newline, the file is opened on the first line
+    pub(crate) fn write_line(&mut self, line: &String) -> Result<(), String> {
+        if self.curr.is_none() {
+            self.open()?;
+        }
+        self.write_raw(line)
+    }
+
+    fn write_raw(&mut self, line: &String) -> Result<(), String> {
+        let curr = self.curr.as_mut().unwrap();
+        let mut line = line.to_owned();
+        line.push('\n');
+        if let Err(err) = curr.writer.write_all(line.as_bytes()) {
+            return Err(format!("write {:?} error {:?}", curr.tmp_path, err));
+        }
+        curr.bytes += line.len() as u64;
+        Ok(())
+    }
+
+    pub(crate) fn rotate_if_needed(&mut self) -> Result<(), String> {
+        let rotate = match &self.curr {
+            // msgs without rows never open a file
+            None => !self.pending.is_empty(),
+            Some(curr) => {
+                (self.cfg.max_bytes > 0 && curr.bytes >= self.cfg.max_bytes)
+                    || (self.cfg.max_secs > 0
+                        && curr.opened_at.elapsed() >= Duration::from_secs(self.cfg.max_secs))
+            }
+        };
+        if rotate {
+            self.close()?;
+        }
+        Ok(())
+    }
+
+    // finish the current file, rename it to its final name and ack its rows.
+    // on error the msgs stay pending for abandon
+    pub(crate) fn close(&mut self) -> Result<(), String> {
+        let curr = match self.curr.take() {
+            Some(v) => v,
+            None => {
+                self.pending.iter_mut().for_each(|msg| msg.ack());
+                self.pending.clear();
+                return Ok(());
+            }
+        };
+        let tmp_path = curr.tmp_path.clone();
+        if let Err(err) = curr.writer.finish() {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(format!("finish {:?} error {:?}", tmp_path, err));
+        }
+        if let Err(err) = rename_new(&tmp_path, &curr.path) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(format!(
+                "rename {:?} to {:?} error {:?}",
+                tmp_path, curr.path, err
+            ));
+        }
+        info!(
+            "[dst] file task_id {} close {:?} bytes {}",
+            self.task_id, curr.path, curr.bytes
+        );
+        self.pending.iter_mut().for_each(|msg| msg.ack());
+        self.pending.clear();
+        Ok(())
+    }
+
+    // drop the current file after a failed write, its msgs are returned unacked
+    pub(crate) fn abandon(&mut self) -> Vec<Msg> {
+        if let Some(curr) = self.curr.take() {
+            let _ = fs::remove_file(&curr.tmp_path);
+        }
+        self.pending.drain(..).collect()
+    }
+}
+
+/// rename from to a path that must not exist yet, a closed file is never replaced
+pub(crate) fn rename_new(from: &PathBuf, to: &PathBuf) -> std::io::Result<()> {
+    fs::hard_link(from, to)?;
+    fs::remove_file(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use crate::core::{Ack, Offset};
+    use crate::transform::flatten::{chrysaetos, flatten_msg};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_dst_rotate() {
+        let dir = std::env::temp_dir().join(format!("varbit-file-dst-{}", uuid::Uuid::new_v4()));
+        let (sender, receive) = mpsc::channel::<Msg>(10);
+        let conf = serde_json::json!({
+            "dir": dir.to_str().unwrap(),
+            "max_bytes": 1,
+            "gzip": true,
+        });
+        let cry = chrysaetos(&"task".to_owned(), &conf);
+        let handler = tokio::spawn(async move {
+            FileDst {}.to_dst("task".to_owned(), conf, receive).await;
+        });
+        sender
+            .send(flatten_msg(
+                &cry,
+                Msg::new("1".to_owned(), serde_json::json!({"a": {"b": 1}})),
+            ))
+            .await
+            .unwrap();
+        sender
+            .send(flatten_msg(
+                &cry,
+                Msg::new("2".to_owned(), serde_json::json!({"a": {"b": 2}})),
+            ))
+            .await
+            .unwrap();
+        drop(sender);
+        handler.await.unwrap();
+
+        let mut names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 2);
+        assert!(names[0].starts_with("task-") && names[0].ends_with("-0.jsonl.gz"));
+        assert!(rename_new(&dir.join(&names[0]), &dir.join(&names[1])).is_err());
+
+        let mut content = String::new();
+        flate2::read::GzDecoder::new(File::open(dir.join(&names[0])).unwrap())
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "{\"a_b\":1}\n");
+        fs::remove_dir_all(&dir).unwrap();
+
+        // a msg without rows opens no file and is acked on the next check
+        let mut rolling = RollingFile::new("task".to_owned(), FileDstConfig::default(), "jsonl");
+        let (ack_tx, mut ack_rx) = mpsc::unbounded_channel::<Offset>();
+        let ack = Ack::new(Offset::new("t".to_owned(), 0, 1), ack_tx);
+        let msg = Msg::with_ack("3".to_owned(), serde_json::json!({}), ack);
+        rolling.pending.push(msg);
+        rolling.rotate_if_needed().unwrap();
+        assert_eq!(ack_rx.try_recv().unwrap().offset, 1);
+    }
+
+    #[tokio::test]
+    async fn test_file_dst_dead_letter() {
+        let task_id = "file-dead-letter-task".to_owned();
+        let dir = std::env::temp_dir().join(format!("varbit-file-dst-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        // a dir can not be created under a file
+        fs::write(dir.join("blocked"), "").unwrap();
+        let cfg = serde_json::json!({
+            "dead_letter": {"type": "file", "dir": dir.join("dead").to_str().unwrap()}
+        });
+        crate::dead_letter::start_dead_letter(&task_id, &cfg).unwrap();
+
+        let (sender, receive) = mpsc::channel::<Msg>(10);
+        let conf = serde_json::json!({"dir": dir.join("blocked").join("out").to_str().unwrap()});
+        let cry = chrysaetos(&task_id, &conf);
+        let id = task_id.clone();
+        let handler = tokio::spawn(async move {
+            FileDst {}.to_dst(id, conf, receive).await;
+        });
+        let (ack_tx, mut ack_rx) = mpsc::unbounded_channel::<Offset>();
+        let ack = Ack::new(Offset::new("t".to_owned(), 0, 1), ack_tx);
+        sender
+            .send(flatten_msg(
+                &cry,
+                Msg::with_ack("1".to_owned(), serde_json::json!({"a": 1}), ack),
+            ))
+            .await
+            .unwrap();
+        drop(sender);
+        handler.await.unwrap();
+        crate::dead_letter::stop_dead_letter(&task_id);
+
+        // acked once its dead letter is written
+        assert_eq!(ack_rx.recv().await.unwrap().offset, 1);
+        assert_eq!(fs::read_dir(dir.join("dead")).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,385 @@
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+
+use crate::core::{redact_secrets, Msg};
+
+use super::{msg_rows, undelivered, Dst};
+
+// longest wait between retries of a batch
+const MAX_BACKOFF_MS: u64 = 30_000;
+
+/// posts flattened rows to a url in batches, at most concurrency batches in flight.
+/// 5xx, 429 and timeouts are retried with exponential backoff, rows are acked once posted.
+/// a batch that still fails goes to the dead letter dst
+pub struct HttpDst {}
+#[async_trait]
+impl Dst for HttpDst {
+    async fn to_dst(
+        &self,
+        task_id: String,
+        conf: serde_json::Value,
+        mut receive: mpsc::Receiver<Msg>,
+    ) {
+        let sfc = match check_http_dst_cfg(&conf) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("[dst] http task_id {} {}", task_id, err);
+                return;
+            }
+        };
+        // headers carry credentials, only the url and batching are logged
+        info!(
+            "[dst] {} task_id:{} url {} batch_rows {} batch_ms {} concurrency {}",
+            self.dst_name(),
+            task_id,
+            redact_secrets(&serde_json::json!(sfc.url)),
+            sfc.batch_rows,
+            sfc.batch_ms,
+            sfc.concurrency
+        );
+        let client = match http_client(&sfc) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("[dst] http task_id {} {}", task_id, err);
+                return;
+            }
+        };
+        let limit = Arc::new(Semaphore::new(sfc.concurrency));
+
+        let mut rows = vec![];
+        let mut msgs = vec![];
+        let mut tick = tokio::time::interval(Duration::from_millis(sfc.batch_ms));
+        loop {
+            let flush = tokio::select! {
+                res = receive.recv() => {
+                    let mut msg = match res {
+                        Some(v) => v,
+                        None => break,
+                    };
+                    let res = match msg_rows(&task_id, &mut msg).await {
+                        Some(v) => v,
+                        None => continue,
+                    };
+                    debug!(
+                        "[dst] http task_id:{} g_id:{} rows {}",
+                        task_id,
+                        msg.g_id,
+                        res.len()
+                    );
+                    rows.extend(res);
+                    msgs.push(msg);
+                    rows.len() >= sfc.batch_rows
+                }
+                _ = tick.tick() => true,
+            };
+            if flush {
+                let batch = Batch::new(std::mem::take(&mut rows), std::mem::take(&mut msgs));
+                spawn_post(&task_id, &client, &sfc, &limit, batch).await;
+            }
+        }
+
+        let batch = Batch::new(rows, msgs);
+        spawn_post(&task_id, &client, &sfc, &limit, batch).await;
+        // wait for the batches in flight
+        let _ = limit.acquire_many(sfc.concurrency as u32).await;
+        info!("[dst] http task_id {} exit", task_id);
+    }
+
+    fn cfg(&self) -> serde_json::Value {
+        serde_json::json!(HttpDstConfig::default())
+    }
+
+    fn dst_name(&self) -> String {
+        "http".to_owned()
+    }
+
+    fn check_cfg(&self, conf: &serde_json::Value) -> Result<(), String> {
+        check_http_dst_cfg(conf).map(|_| ())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpBody {
+    // [{...}, {...}]
+    #[default]
+    JsonArray,
+    // one json row per line
+    Ndjson,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+pub struct HttpDstConfig {
+    pub url: String,
+    // extra request headers like authorization
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: HttpBody,
+    // post a batch at this many rows
+    #[serde(default = "default_batch_rows")]
+    pub batch_rows: usize,
+    // post a batch at least every batch_ms
+    #[serde(default = "default_batch_ms")]
+    pub batch_ms: u64,
+    // timeout of one request
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    // retries of a failed batch, the wait doubles from retry_backoff_ms
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+    // batches in flight
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+}
+
+fn default_batch_rows() -> usize {
+    100
+}
+
+fn default_batch_ms() -> u64 {
+    1000
+}
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_max_retries() -> u32 {
+    5
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    200
+}
+
+fn default_concurrency() -> usize {
+    4
+}
+
+pub fn check_http_dst_cfg(conf: &serde_json::Value) -> Result<HttpDstConfig, String> {
+    let cfg = match serde_json::from_value::<HttpDstConfig>(conf.clone()) {
+        Ok(v) => v,
+        Err(err) => return Err(format!("invalid config error {:?}", err)),
+    };
+    if reqwest::Url::parse(&cfg.url).is_err()
+        || !(cfg.url.starts_with("http://") || cfg.url.starts_with("https://"))
+    {
+        return Err(format!("invalid config url {:?}", cfg.url));
+    }
+    if cfg.batch_rows == 0 || cfg.batch_ms == 0 {
+        return Err("invalid config batch_rows and batch_ms must be over 0".to_owned());
+    }
+    if cfg.concurrency == 0 {
+        return Err("invalid config concurrency must be over 0".to_owned());
+    }
+    headers(&cfg.headers)?;
+    Ok(cfg)
+}
+
+fn headers(headers: &HashMap<String, String>) -> Result<HeaderMap, String> {
+    let mut map = HeaderMap::new();
+    for (key, value) in headers {
+        let name = match HeaderName::from_bytes(key.as_bytes()) {
+            Ok(v) => v,
+            Err(_) => return Err(format!("invalid header name {:?}", key)),
+        };
+        let value = match HeaderValue::from_str(value) {
+            Ok(v) => v,
+            Err(_) => return Err(format!("invalid value of header {}", key)),
+        };
+        map.insert(name, value);
+    }
+    Ok(map)
+}
+
+fn http_client(cfg: &HttpDstConfig) -> Result<reqwest::Client, String> {
+    let mut default_headers = headers(&cfg.headers)?;
+    let content_type = match cfg.body {
+        HttpBody::JsonArray => "application/json",
+        HttpBody::Ndjson => "application/x-ndjson",
+    };
+    default_headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    match reqwest::Client::builder()
+        .default_headers(default_headers)
+        .timeout(Duration::from_millis(cfg.timeout_ms))
+        .build()
+    {
+        Ok(v) => Ok(v),
+        Err(err) => Err(format!("build http client error {:?}", err)),
+    }
+}
+
+type Row = HashMap<String, serde_json::Value>;
+
+struct Batch {
+    rows: Vec<Row>,
+    msgs: Vec<Msg>,
+}
+
+impl Batch {
+    fn new(rows: Vec<Row>, msgs: Vec<Msg>) -> Self {
+        Batch { rows, msgs }
+    }
+
+    fn ack(self) {
+        self.msgs.into_iter().for_each(|mut msg| msg.ack());
+    }
+}
+
+fn body(rows: &[Row], body: HttpBody) -> Vec<u8> {
+    match body {
+        HttpBody::JsonArray => serde_json::json!(rows).to_string().into_bytes(),
+        HttpBody::Ndjson => {
+            let mut out = vec![];
+            for row in rows {
+                out.extend(serde_json::json!(row).to_string().into_bytes());
+                out.push(b'\n');
+            }
+            out
+        }
+    }
+}
+
+// post batch once a concurrency permit is free
+async fn spawn_post(
+    task_id: &String,
+    client: &reqwest::Client,
+    cfg: &HttpDstConfig,
+    limit: &Arc<Semaphore>,
+    batch: Batch,
+) {
+    if batch.rows.is_empty() {
+        // msgs without rows have nothing to post
+        batch.ack();
+        return;
+    }
+    let permit = match limit.clone().acquire_owned().await {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+    let task_id = task_id.to_owned();
+    let client = client.clone();
+    let cfg = cfg.clone();
+    tokio::spawn(async move {
+        match post(&client, &cfg, &batch.rows).await {
+            Ok(_) => batch.ack(),
+            Err(err) => undelivered(&task_id, batch.msgs, err, "http_post").await,
+        }
+        drop(permit);
+    });
+}
+
+// post rows with retries on 5xx, 429 and request errors like timeouts
+async fn post(client: &reqwest::Client, cfg: &HttpDstConfig, rows: &[Row]) -> Result<(), String> {
+    let payload = body(rows, cfg.body);
+    let mut backoff = cfg.retry_backoff_ms;
+    let mut attempt = 0;
+    loop {
+        let err = match client.post(&cfg.url).body(payload.clone()).send().await {
+            Ok(resp) if resp.status().is_success() => return Ok(()),
+            Ok(resp) => {
+                let status = resp.status();
+                if !status.is_server_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    return Err(format!("post {} status {}", cfg.url, status));
+                }
+                format!("status {}", status)
+            }
+            Err(err) => format!("error {:?}", err),
+        };
+        if attempt >= cfg.max_retries {
+            return Err(format!(
+                "post {} failed after {} retries, last {}",
+                cfg.url, attempt, err
+            ));
+        }
+        warn!("[dst] http post {} attempt {} {}", cfg.url, attempt, err);
+        tokio::time::sleep(Duration::from_millis(backoff)).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF_MS);
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::core::{Ack, Offset};
+    use crate::transform::flatten::{chrysaetos, flatten_msg};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_http_dst_retry() {
+        let server = MockServer::start().await;
+        // the first post fails and is retried
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/rows"))
+            .and(header("x-token", "abc"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let conf = serde_json::json!({
+            "url": format!("{}/rows", server.uri()),
+            "headers": {"x-token": "abc"},
+            "body": "ndjson",
+            "batch_rows": 2,
+            "retry_backoff_ms": 10,
+        });
+        let cry = chrysaetos(&"task".to_owned(), &conf);
+        let (sender, receive) = mpsc::channel::<Msg>(10);
+        let (ack_tx, mut ack_rx) = mpsc::unbounded_channel::<Offset>();
+        let handler = tokio::spawn(async move {
+            HttpDst {}.to_dst("task".to_owned(), conf, receive).await;
+        });
+        for offset in 0..2 {
+            let ack = Ack::new(Offset::new("t".to_owned(), 0, offset), ack_tx.clone());
+            let value = serde_json::json!({"a": {"b": offset}});
+            sender
+                .send(flatten_msg(
+                    &cry,
+                    Msg::with_ack(offset.to_string(), value, ack),
+                ))
+                .await
+                .unwrap();
+        }
+        drop(sender);
+        handler.await.unwrap();
+
+        assert_eq!(ack_rx.recv().await.unwrap().offset, 0);
+        assert_eq!(ack_rx.recv().await.unwrap().offset, 1);
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            String::from_utf8(requests[1].body.clone()).unwrap(),
+            "{\"a_b\":0}\n{\"a_b\":1}\n"
+        );
+    }
+
+    #[test]
+    fn test_check_http_dst_cfg() {
+        assert!(check_http_dst_cfg(&serde_json::json!({"url": "http://localhost/rows"})).is_ok());
+        assert!(check_http_dst_cfg(&serde_json::json!({"url": "ftp://localhost"})).is_err());
+        assert!(check_http_dst_cfg(&serde_json::json!({
+            "url": "http://localhost/rows",
+            "headers": {"bad header": "v"},
+        }))
+        .is_err());
+    }
+}
@@ -1,17 +1,22 @@
 use async_trait::async_trait;
 use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::time::Duration;
+use std::collections::HashMap;
 use tokio::sync::mpsc;
 
-use rdkafka::config::ClientConfig;
+use futures::future::join_all;
+use futures::stream::{FuturesUnordered, StreamExt};
 use rdkafka::message::OwnedHeaders;
 use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::util::Timeout;
+use rdkafka::ClientConfig;
+use sha2::{Digest, Sha256};
 
-use crate::core::Msg;
+use crate::core::{redact_secrets, Msg};
+use crate::kafka::{check_properties, client_config, KafkaSecurity};
 
-use super::Dst;
+use super::encoder::{check_encoder, AvroConfig, RowEncoder};
+use super::{declared_columns, delivered, msg_rows, Dst};
 
 pub struct KafkaDst {}
 #[async_trait]
@@ -24,10 +29,10 @@ impl Dst for KafkaDst {
         mut receive: mpsc::Receiver<Msg>,
     ) {
         info!(
-            "[dst] {} task_id:{} conf {:?}",
+            "[dst] {} task_id:{} conf {}",
             self.dst_name(),
             task_id,
-            serde_json::to_string(&conf.to_owned()).unwrap()
+            redact_secrets(&conf)
         );
 
         let sfc = match serde_json::from_value::<KafkaDstConfig>(conf.to_owned()) {
@@ -48,11 +53,29 @@ impl Dst for KafkaDst {
             task_id.to_owned(),
             sfc
         );
-        let producer = match ClientConfig::new()
-            .set("bootstrap.servers", sfc.broker.as_str())
-            .set("message.timeout.ms", "5000")
-            .create::<FutureProducer>()
-        {
+        let mut client_config = match client_config(&sfc.broker, &sfc.properties, &sfc.security) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("task_id {} invalid kafka client config {}", task_id, err);
+                return;
+            }
+        };
+        if client_config.get("message.timeout.ms").is_none() {
+            client_config.set("message.timeout.ms", "5000");
+        }
+        if let Err(err) = sfc.key.check(&sfc.properties) {
+            error!("task_id {} invalid kafka key config {}", task_id, err);
+            return;
+        }
+        if let Err(err) = sfc.producer.check(&sfc.properties) {
+            error!("task_id {} invalid kafka producer config {}", task_id, err);
+            return;
+        }
+        if !sfc.key.partitioner.is_empty() {
+            client_config.set("partitioner", &sfc.key.partitioner);
+        }
+        sfc.producer.apply(&mut client_config);
+        let producer = match client_config.create::<FutureProducer>() {
             Ok(v) => v,
             Err(err) => {
                 error!("create producer error {:?}", err);
@@ -60,56 +83,75 @@ impl Dst for KafkaDst {
             }
         };
 
-        let mut ignore = HashSet::new();
-        ignore.insert("ts".to_owned());
-        let cry = service::task::json::ChrysaetosBit::new_cfg(
-            task_id.clone(),
-            "_".to_owned(),
-            32,
-            HashSet::new(),
-            ignore,
-        );
+        let columns = declared_columns(&task_id, &conf);
+        let mut encoder = match RowEncoder::new(&sfc.encoder, columns, &sfc.avro, &sfc.topic) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("task_id {} invalid kafka encoder config {}", task_id, err);
+                return;
+            }
+        };
 
-        while let Some(msg) = receive.recv().await {
-            let res = cry.parse(&msg.g_id, &msg.value);
-
-            debug!(
-                "[dst] {} task_id:{} g_id:{} receive  data {:?} res{:?}",
-                self.dst_name(),
-                task_id,
-                msg.g_id,
-                msg.value.to_string(),
-                serde_json::to_string(&serde_json::json!(res)).unwrap(),
-            );
-
-            for data in &res {
-                let producer_status = match producer
-                    .send(
-                        FutureRecord::to(sfc.topic.as_str())
-                            .key(&"".to_owned())
-                            .payload(&format!("{}", serde_json::json!(data).to_string()))
-                            .headers(OwnedHeaders::new()),
-                        Duration::from_secs(0),
-                    )
-                    .await
-                {
-                    Ok(v) => v,
-                    Err(err) => {
-                        error!(
-                            "[dst] task_id {}, g_id {} send data error {:?}",
-                            task_id, msg.g_id, err
-                        );
-                        continue;
-                    }
-                };
-                debug!(
-                    "[dst] task_id {}, g_id {}, producer_status {:?}",
-                    task_id, msg.g_id, producer_status
-                );
+        // msgs with rows in flight, a msg is acked once all of its rows are delivered
+        let mut in_flight = FuturesUnordered::new();
+        let mut in_flight_rows = 0;
+        loop {
+            tokio::select! {
+                Some((rows, msg, res)) = in_flight.next(), if !in_flight.is_empty() => {
+                    in_flight_rows -= rows;
+                    delivered(&task_id, msg, res, "send_failed").await;
+                }
+                res = receive.recv(), if in_flight_rows < sfc.producer.max_in_flight => {
+                    let mut msg = match res {
+                        Some(v) => v,
+                        None => break,
+                    };
+                    let res = match msg_rows(&task_id, &mut msg).await {
+                        Some(v) => v,
+                        None => continue,
+                    };
+                    debug!(
+                        "[dst] {} task_id:{} g_id:{} receive  data {:?} res{:?}",
+                        self.dst_name(),
+                        task_id,
+                        msg.g_id,
+                        msg.value.to_string(),
+                        serde_json::to_string(&serde_json::json!(res)).unwrap(),
+                    );
+                    let payloads = match encoder.encode(&res).await {
+                        Ok(v) => v,
+                        Err(err) => {
+                            delivered(&task_id, msg, Err(err), "encode_failed").await;
+                            continue;
+                        }
+                    };
+                    let deliveries: Vec<_> = payloads
+                        .into_iter()
+                        .map(|(i, payload)| {
+                            send_row(
+                                producer.clone(),
+                                sfc.topic.clone(),
+                                sfc.key.row_key(&msg.g_id, &res[i]),
+                                payload,
+                            )
+                        })
+                        .collect();
+                    let rows = deliveries.len();
+                    in_flight_rows += rows;
+                    in_flight.push(async move {
+                        let res: Result<Vec<()>, String> =
+                            join_all(deliveries).await.into_iter().collect();
+                        (rows, msg, res)
+                    });
+                }
             }
         }
 
-        info!("exit...consumer....{:?}", receive.recv().await)
+        // wait for the rows in flight before the producer is dropped
+        while let Some((_, msg, res)) = in_flight.next().await {
+            delivered(&task_id, msg, res, "send_failed").await;
+        }
+        info!("[dst] kafka task_id {} exit", task_id);
     }
 
     fn cfg(&self) -> serde_json::Value {
@@ -119,13 +161,243 @@ impl Dst for KafkaDst {
     fn dst_name(&self) -> String {
         "kafka".to_owned()
     }
+
+    fn check_cfg(&self, conf: &serde_json::Value) -> Result<(), String> {
+        check_dst_cfg(conf).map(|_| ())
+    }
+}
+// enqueue a row, resolves once the broker acks it
+async fn send_row(
+    producer: FutureProducer,
+    topic: String,
+    key: Option<String>,
+    payload: Vec<u8>,
+) -> Result<(), String> {
+    let mut record = FutureRecord::to(topic.as_str())
+        .payload(&payload)
+        .headers(OwnedHeaders::new());
+    if let Some(key) = &key {
+        record = record.key(key);
+    }
+    // the in flight window bounds the queue, wait for room instead of failing
+    match producer.send(record, Timeout::Never).await {
+        Ok(_) => Ok(()),
+        Err((err, _)) => Err(format!("send to {} error {:?}", topic, err)),
+    }
 }
+
 #[derive(Debug, Deserialize, Serialize, Default)]
 pub struct KafkaDstConfig {
     pub broker: String,
     pub topic: String,
+    // json, ndjson, csv, avro or msgpack
     pub encoder: String,
+    #[serde(default)]
+    pub avro: AvroConfig,
     pub meta: KafkaDstMeta,
+    // pass-through librdkafka properties
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+    #[serde(default)]
+    pub security: KafkaSecurity,
+    #[serde(flatten)]
+    pub key: KafkaKeyConfig,
+    #[serde(flatten)]
+    pub producer: KafkaProducerConfig,
+}
+
+// librdkafka compression codecs
+const COMPRESSIONS: [&str; 5] = ["none", "gzip", "snappy", "lz4", "zstd"];
+
+// properties managed by KafkaProducerConfig
+const PRODUCER_PROPERTIES: [&str; 7] = [
+    "enable.idempotence",
+    "linger.ms",
+    "queue.buffering.max.ms",
+    "batch.size",
+    "compression.type",
+    "compression.codec",
+    "acks",
+];
+
+/// batching and delivery settings of the kafka dst producer
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct KafkaProducerConfig {
+    // rows waiting for delivery before new msgs are read
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+    // no duplicates or reordering on producer retries, implies acks=all
+    #[serde(default = "default_idempotence")]
+    pub idempotence: bool,
+    #[serde(default)]
+    pub linger_ms: Option<u64>,
+    // max bytes of a batch to a partition
+    #[serde(default)]
+    pub batch_size: Option<u64>,
+    // none, gzip, snappy, lz4, zstd, empty is the librdkafka default
+    #[serde(default)]
+    pub compression: String,
+}
+
+fn default_max_in_flight() -> usize {
+    10_000
+}
+
+fn default_idempotence() -> bool {
+    true
+}
+
+impl Default for KafkaProducerConfig {
+    fn default() -> Self {
+        KafkaProducerConfig {
+            max_in_flight: default_max_in_flight(),
+            idempotence: default_idempotence(),
+            linger_ms: None,
+            batch_size: None,
+            compression: String::new(),
+        }
+    }
+}
+
+impl KafkaProducerConfig {
+    pub fn check(&self, properties: &HashMap<String, String>) -> Result<(), String> {
+        if self.max_in_flight == 0 {
+            return Err("max_in_flight must be over 0".to_owned());
+        }
+        if !self.compression.is_empty() && !COMPRESSIONS.contains(&self.compression.as_str()) {
+            return Err(format!(
+                "invalid compression {} expected one of {:?}",
+                self.compression, COMPRESSIONS
+            ));
+        }
+        if let Some(key) = PRODUCER_PROPERTIES
+            .iter()
+            .find(|k| properties.contains_key(**k))
+        {
+            return Err(format!(
+                "kafka property {} is managed by the producer fields",
+                key
+            ));
+        }
+        Ok(())
+    }
+
+    fn apply(&self, cfg: &mut ClientConfig) {
+        cfg.set("enable.idempotence", self.idempotence.to_string());
+        if let Some(v) = self.linger_ms {
+            cfg.set("linger.ms", v.to_string());
+        }
+        if let Some(v) = self.batch_size {
+            cfg.set("batch.size", v.to_string());
+        }
+        if !self.compression.is_empty() {
+            cfg.set("compression.type", &self.compression);
+        }
+    }
+}
+
+// librdkafka partitioners
+const PARTITIONERS: [&str; 7] = [
+    "random",
+    "consistent",
+    "consistent_random",
+    "murmur2",
+    "murmur2_random",
+    "fnv1a",
+    "fnv1a_random",
+];
+
+// separator of joined key fields
+const KEY_SEP: &str = "|";
+
+/// what the message key of a row is made of
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyBy {
+    // no key, the partitioner spreads rows
+    #[default]
+    None,
+    // values of key_fields of the flattened row
+    Fields,
+    // g_id of the msg, rows of a msg share a partition
+    GId,
+}
+
+/// message key and partitioner of the kafka dst
+#[derive(Debug, Deserialize, Serialize, Default, Clone, PartialEq)]
+pub struct KafkaKeyConfig {
+    #[serde(default)]
+    pub key_by: KeyBy,
+    // flattened keys like user_id, joined by |
+    #[serde(default)]
+    pub key_fields: Vec<String>,
+    // send the sha256 hex of the key instead of the key
+    #[serde(default)]
+    pub key_hash: bool,
+    // one of librdkafka partitioners, empty is the librdkafka default
+    #[serde(default)]
+    pub partitioner: String,
+}
+
+impl KafkaKeyConfig {
+    pub fn check(&self, properties: &HashMap<String, String>) -> Result<(), String> {
+        match self.key_by {
+            KeyBy::Fields => {
+                if self.key_fields.is_empty() || self.key_fields.iter().any(|f| f.is_empty()) {
+                    return Err("key_by fields needs non empty key_fields".to_owned());
+                }
+            }
+            _ => {
+                if !self.key_fields.is_empty() {
+                    return Err("key_fields needs key_by fields".to_owned());
+                }
+            }
+        }
+        if self.key_hash && self.key_by == KeyBy::None {
+            return Err("key_hash needs key_by fields or g_id".to_owned());
+        }
+        if self.partitioner.is_empty() {
+            return Ok(());
+        }
+        if !PARTITIONERS.contains(&self.partitioner.as_str()) {
+            return Err(format!(
+                "invalid partitioner {} expected one of {:?}",
+                self.partitioner, PARTITIONERS
+            ));
+        }
+        if properties.contains_key("partitioner") {
+            return Err("partitioner is set twice, drop it from properties".to_owned());
+        }
+        Ok(())
+    }
+
+    /// message key of a flattened row, missing fields are empty
+    pub fn row_key(
+        &self,
+        g_id: &String,
+        row: &HashMap<String, serde_json::Value>,
+    ) -> Option<String> {
+        let key = match self.key_by {
+            KeyBy::None => return None,
+            KeyBy::GId => g_id.to_owned(),
+            KeyBy::Fields => {
+                let values: Vec<String> = self
+                    .key_fields
+                    .iter()
+                    .map(|f| match row.get(f) {
+                        None | Some(serde_json::Value::Null) => String::new(),
+                        Some(serde_json::Value::String(v)) => v.to_owned(),
+                        Some(v) => v.to_string(),
+                    })
+                    .collect();
+                values.join(KEY_SEP)
+            }
+        };
+        if self.key_hash {
+            return Some(format!("{:x}", Sha256::digest(key.as_bytes())));
+        }
+        Some(key)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Default)]
@@ -139,11 +411,152 @@ pub struct DstConfigReq {
     pub broker: String,
     pub topic: String,
     pub encoder: String,
+    #[serde(default)]
+    pub avro: AvroConfig,
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+    #[serde(default)]
+    pub security: KafkaSecurity,
+    #[serde(flatten)]
+    pub key: KafkaKeyConfig,
+    #[serde(flatten)]
+    pub producer: KafkaProducerConfig,
 }
 // check dst/sink config is ok?
 pub fn check_dst_cfg(conf: &serde_json::Value) -> Result<DstConfigReq, String> {
-    match serde_json::from_value::<DstConfigReq>(conf.clone()) {
-        Ok(v) => Ok(v),
-        Err(err) => Err(format!("invalid config  {} error {:?}", conf, err)),
+    let req = match serde_json::from_value::<DstConfigReq>(conf.clone()) {
+        Ok(v) => v,
+        Err(err) => return Err(format!("invalid config  {} error {:?}", conf, err)),
+    };
+    if let Err(err) = check_encoder(&req.encoder, &req.avro) {
+        return Err(format!("invalid config  {} error {}", conf, err));
+    }
+    if let Err(err) = check_properties(&req.properties) {
+        return Err(format!("invalid config  {} error {}", conf, err));
+    }
+    if let Err(err) = req.security.check() {
+        return Err(format!("invalid config  {} error {}", conf, err));
+    }
+    if let Err(err) = req.key.check(&req.properties) {
+        return Err(format!("invalid config  {} error {}", conf, err));
+    }
+    if let Err(err) = req.producer.check(&req.properties) {
+        return Err(format!("invalid config  {} error {}", conf, err));
+    }
+    Ok(req)
+}
+
+/// tasking cfg of a kafka dst saved before tasks had dsts. those flattened with
+/// sep "_", max_depth 32 and ignore "ts" whatever the tasking cfg said, so their
+/// column names stay the same. the other options of the tasking cfg still apply
+pub fn legacy_tasking_cfg(tasking_cfg: &serde_json::Value) -> serde_json::Value {
+    let mut cfg = match tasking_cfg {
+        serde_json::Value::Object(v) => v.clone(),
+        _ => serde_json::Map::new(),
+    };
+    cfg.insert("sep".to_owned(), serde_json::json!("_"));
+    cfg.insert("max_depth".to_owned(), serde_json::json!(32));
+    cfg.insert("ignore".to_owned(), serde_json::json!(["ts"]));
+    cfg.insert("fold".to_owned(), serde_json::json!([]));
+    serde_json::Value::Object(cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_row_key() {
+        let row: HashMap<String, serde_json::Value> =
+            serde_json::from_value(serde_json::json!({"user_id": 7, "region": "eu", "n": null}))
+                .unwrap();
+        let g_id = "g1".to_owned();
+        let key = KafkaKeyConfig {
+            key_by: KeyBy::Fields,
+            key_fields: vec!["region".to_owned(), "user_id".to_owned(), "n".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(key.row_key(&g_id, &row), Some("eu|7|".to_owned()));
+        assert_eq!(KafkaKeyConfig::default().row_key(&g_id, &row), None);
+
+        let key = KafkaKeyConfig {
+            key_by: KeyBy::GId,
+            key_hash: true,
+            ..Default::default()
+        };
+        assert_eq!(key.row_key(&g_id, &row).unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_check_key_cfg() {
+        let conf = serde_json::json!({
+            "broker": "localhost:9092",
+            "topic": "out",
+            "encoder": "json",
+            "key_by": "fields",
+            "key_fields": ["user_id"],
+            "partitioner": "murmur2_random",
+        });
+        let req = check_dst_cfg(&conf).unwrap();
+        assert_eq!(req.key.key_by, KeyBy::Fields);
+
+        let mut bad = conf.clone();
+        bad["key_fields"] = serde_json::json!([]);
+        assert!(check_dst_cfg(&bad).is_err());
+
+        let mut bad = conf.clone();
+        bad["partitioner"] = serde_json::json!("round_robin");
+        assert!(check_dst_cfg(&bad).is_err());
+
+        let mut bad = conf;
+        bad["properties"] = serde_json::json!({"partitioner": "random"});
+        assert!(check_dst_cfg(&bad).is_err());
+    }
+
+    #[test]
+    fn test_producer_cfg() {
+        let conf = serde_json::json!({
+            "broker": "localhost:9092",
+            "topic": "out",
+            "encoder": "json",
+            "linger_ms": 20,
+            "compression": "zstd",
+        });
+        let req = check_dst_cfg(&conf).unwrap();
+        assert!(req.producer.idempotence);
+        assert_eq!(req.producer.max_in_flight, 10_000);
+
+        let mut cfg = ClientConfig::new();
+        req.producer.apply(&mut cfg);
+        assert_eq!(cfg.get("enable.idempotence"), Some("true"));
+        assert_eq!(cfg.get("linger.ms"), Some("20"));
+        assert_eq!(cfg.get("compression.type"), Some("zstd"));
+        assert_eq!(cfg.get("batch.size"), None);
+
+        let mut bad = conf.clone();
+        bad["compression"] = serde_json::json!("brotli");
+        assert!(check_dst_cfg(&bad).is_err());
+
+        let mut bad = conf;
+        bad["properties"] = serde_json::json!({"linger.ms": "5"});
+        assert!(check_dst_cfg(&bad).is_err());
+    }
+
+    #[test]
+    fn test_legacy_tasking_cfg() {
+        let tasking_cfg = serde_json::json!({
+            "sep": ".", "max_depth": 8, "ignore": [], "fold": ["tags"], "filter": "a > 1",
+        });
+        let cfg = legacy_tasking_cfg(&tasking_cfg);
+        assert_eq!(cfg["sep"], "_");
+        assert_eq!(cfg["max_depth"], 32);
+        assert_eq!(cfg["ignore"], serde_json::json!(["ts"]));
+        assert_eq!(cfg["filter"], "a > 1");
+        assert!(
+            service::task::json::check_chrysaetos_bit_cfg(&legacy_tasking_cfg(
+                &serde_json::Value::Null
+            ))
+            .is_ok()
+        );
     }
 }
@@ -1,9 +1,23 @@
 use async_trait::async_trait;
+use log::error;
+use service::task::json::ChrysaetosBitConfig;
 use tokio::sync::mpsc;
 
-use crate::core::Msg;
+use crate::core::{Msg, Row};
+use crate::dead_letter::{self, DeadLetter, Stage};
+use crate::{metrics, DST_PLUGIN};
 
+pub mod csv;
+pub mod encoder;
+pub mod file;
+pub mod http;
 pub mod kafka;
+pub mod mysql;
+pub mod parquet;
+pub mod stdout;
+
+// key of the task tasking cfg in a dst conf
+pub const TASKING_CFG_KEY: &str = "tasking_cfg";
 
 #[async_trait]
 pub trait Dst: Send + Sync {
@@ -15,4 +29,88 @@ pub trait Dst: Send + Sync {
     );
     fn cfg(&self) -> serde_json::Value;
     fn dst_name(&self) -> String;
+    // check dst config of a task before saving it
+    fn check_cfg(&self, conf: &serde_json::Value) -> Result<(), String>;
+}
+
+/// check dst_type is registered and conf is valid for it
+pub fn check_dst_cfg(dst_type: &String, conf: &serde_json::Value) -> Result<(), String> {
+    let dst = match DST_PLUGIN.lock().unwrap().get(dst_type.as_str()) {
+        Some(v) => v.clone(),
+        None => return Err(format!("not support dst type {}", dst_type)),
+    };
+    dst.check_cfg(conf)
+}
+
+/// rows of a msg, set by the flatten transform every task runs in front of its dsts.
+/// a msg without them goes to the dead letter dst
+pub(crate) async fn msg_rows(task_id: &String, msg: &mut Msg) -> Option<Vec<Row>> {
+    if let Some(rows) = msg.rows.take() {
+        return Some(rows);
+    }
+    let err = "msg has no rows, flatten did not run on it".to_owned();
+    dead_letter_msg(task_id, msg, err, "no_rows").await;
+    None
+}
+
+// a msg with a failed row goes to the dead letter dst,
+// without one it stays uncommitted and is read again after restart
+pub(crate) async fn delivered(
+    task_id: &String,
+    mut msg: Msg,
+    res: Result<Vec<()>, String>,
+    reason: &str,
+) {
+    match res {
+        Ok(_) => {
+            msg.ack();
+            metrics::record_delivered(task_id, 1);
+        }
+        Err(err) => dead_letter_msg(task_id, &mut msg, err, reason).await,
+    }
+}
+
+async fn dead_letter_msg(task_id: &String, msg: &mut Msg, err: String, reason: &str) {
+    error!(
+        "[dst] task_id {}, g_id {} delivery error {}",
+        task_id, msg.g_id, err
+    );
+    metrics::record_failure(task_id, &msg.g_id, err.clone());
+    let letter = DeadLetter::new(
+        task_id,
+        &msg.g_id,
+        Stage::Delivery,
+        reason,
+        err,
+        msg.value.to_string().as_bytes(),
+    );
+    // the returned ack is dropped so the msg is not committed
+    let _ = dead_letter::route(letter, msg.ack.take()).await;
+}
+
+// msgs whose rows could not be written go to the dead letter dst
+pub(crate) async fn undelivered(task_id: &String, msgs: Vec<Msg>, err: String, reason: &str) {
+    for msg in msgs {
+        delivered(task_id, msg, Err(err.clone()), reason).await;
+    }
+}
+
+// columns declared in the tasking cfg of conf
+pub(crate) fn declared_columns(task_id: &String, conf: &serde_json::Value) -> Vec<String> {
+    let tasking_cfg = match conf.get(TASKING_CFG_KEY) {
+        Some(v) => v,
+        None => return vec![],
+    };
+    match serde_json::from_value::<ChrysaetosBitConfig>(tasking_cfg.clone()) {
+        Ok(v) => v.columns().clone(),
+        Err(err) => {
+            log::warn!(
+                "task_id {} invalid tasking cfg {} error {:?}",
+                task_id,
+                tasking_cfg,
+                err
+            );
+            vec![]
+        }
+    }
 }
@@ -0,0 +1,649 @@
+use async_trait::async_trait;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use arrow_array::{ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+
+use crate::core::{redact_secrets, Msg};
+
+use super::delivered;
+use super::file::{default_max_secs, rename_new};
+use super::{msg_rows, undelivered, Dst};
+
+// row groups buffered while writes fail, past it no msg is read until a write succeeds
+const MAX_BUFFERED_ROW_GROUPS: usize = 4;
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// writes flattened rows as parquet files, one row group per row_group_rows rows.
+/// the schema is declared or inferred from the first batch, a new column or a wider
+/// type closes the file and starts a new one. rows are acked once their file is closed,
+/// a msg with a value its declared column type can not hold goes to the dead letter dst.
+/// a failed file is dropped and its msgs go there too, the buffer is written again
+pub struct ParquetDst {}
+#[async_trait]
+impl Dst for ParquetDst {
+    async fn to_dst(
+        &self,
+        task_id: String,
+        conf: serde_json::Value,
+        mut receive: mpsc::Receiver<Msg>,
+    ) {
+        info!(
+            "[dst] {} task_id:{} conf {}",
+            self.dst_name(),
+            task_id,
+            redact_secrets(&conf)
+        );
+        let sfc = match check_parquet_dst_cfg(&conf) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("[dst] parquet task_id {} {}", task_id, err);
+                return;
+            }
+        };
+        let mut table = ParquetTable::new(task_id.clone(), sfc);
+
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            if table.buffer_full() {
+                // hold back the src until the buffer is written
+                tokio::time::sleep(RETRY_INTERVAL).await;
+                if let Err(err) = table.flush_buffer() {
+                    undelivered(&task_id, table.abandon(), err, "parquet_write").await;
+                }
+                continue;
+            }
+            tokio::select! {
+                res = receive.recv() => {
+                    let mut msg = match res {
+                        Some(v) => v,
+                        None => break,
+                    };
+                    let rows = match msg_rows(&task_id, &mut msg).await {
+                        Some(v) => v,
+                        None => continue,
+                    };
+                    debug!(
+                        "[dst] parquet task_id:{} g_id:{} rows {}",
+                        task_id,
+                        msg.g_id,
+                        rows.len()
+                    );
+                    if let Err(err) = table.check_rows(&rows) {
+                        delivered(&task_id, msg, Err(err), "schema_mismatch").await;
+                        continue;
+                    }
+                    if let Err(err) = table.write_msg(rows, msg) {
+                        undelivered(&task_id, table.abandon(), err, "parquet_write").await;
+                    }
+                }
+                _ = tick.tick() => {
+                    if let Err(err) = table.tick() {
+                        undelivered(&task_id, table.abandon(), err, "parquet_write").await;
+                    }
+                }
+            }
+        }
+
+        if let Err(err) = table.close() {
+            let mut msgs = table.abandon();
+            msgs.append(&mut table.buffer_msgs);
+            undelivered(&task_id, msgs, err, "parquet_write").await;
+        }
+        info!("[dst] parquet task_id {} exit", task_id);
+    }
+
+    fn cfg(&self) -> serde_json::Value {
+        serde_json::json!(ParquetDstConfig::default())
+    }
+
+    fn dst_name(&self) -> String {
+        "parquet".to_owned()
+    }
+
+    fn check_cfg(&self, conf: &serde_json::Value) -> Result<(), String> {
+        check_parquet_dst_cfg(conf).map(|_| ())
+    }
+}
+
+/// column type of a parquet file
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParquetType {
+    Boolean,
+    Int64,
+    Double,
+    // strings, folded objects and arrays as json
+    String,
+}
+
+impl ParquetType {
+    // null tells nothing about the type
+    fn of(value: &serde_json::Value) -> Option<ParquetType> {
+        match value {
+            serde_json::Value::Null => None,
+            serde_json::Value::Bool(_) => Some(ParquetType::Boolean),
+            serde_json::Value::Number(v) => {
+                if v.is_i64() {
+                    Some(ParquetType::Int64)
+                } else {
+                    Some(ParquetType::Double)
+                }
+            }
+            _ => Some(ParquetType::String),
+        }
+    }
+
+    // numbers widen to double and anything else to string
+    fn widen(self, other: ParquetType) -> ParquetType {
+        if self == other {
+            self
+        } else if self != ParquetType::Boolean
+            && other != ParquetType::Boolean
+            && (self == ParquetType::Double || other == ParquetType::Double)
+        {
+            ParquetType::Double
+        } else {
+            ParquetType::String
+        }
+    }
+
+    // a value written as is, null fits any type
+    fn holds(&self, value: &serde_json::Value) -> bool {
+        match self {
+            _ if value.is_null() => true,
+            ParquetType::Boolean => value.is_boolean(),
+            ParquetType::Int64 => value.as_i64().is_some(),
+            ParquetType::Double => value.is_number(),
+            ParquetType::String => true,
+        }
+    }
+
+    fn data_type(&self) -> DataType {
+        match self {
+            ParquetType::Boolean => DataType::Boolean,
+            ParquetType::Int64 => DataType::Int64,
+            ParquetType::Double => DataType::Float64,
+            ParquetType::String => DataType::Utf8,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ParquetColumn {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub column_type: ParquetType,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParquetCompression {
+    None,
+    #[default]
+    Snappy,
+    Zstd,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+pub struct ParquetDstConfig {
+    // output directory
+    pub dir: String,
+    // declared schema, columns missing in it are dropped
+    #[serde(default)]
+    pub schema: Vec<ParquetColumn>,
+    // rows of a row group, also the rows buffered before writing
+    #[serde(default = "default_row_group_rows")]
+    pub row_group_rows: usize,
+    // rotate after this many rows, 0 is off
+    #[serde(default = "default_max_rows")]
+    pub max_rows: usize,
+    // rotate after a file or buffer is this many seconds old, 0 is off
+    #[serde(default = "default_max_secs")]
+    pub max_secs: u64,
+    #[serde(default)]
+    pub compression: ParquetCompression,
+}
+
+fn default_row_group_rows() -> usize {
+    10_000
+}
+
+fn default_max_rows() -> usize {
+    1_000_000
+}
+
+pub fn check_parquet_dst_cfg(conf: &serde_json::Value) -> Result<ParquetDstConfig, String> {
+    let cfg = match serde_json::from_value::<ParquetDstConfig>(conf.clone()) {
+        Ok(v) => v,
+        Err(err) => return Err(format!("invalid config  {} error {:?}", conf, err)),
+    };
+    if cfg.dir.is_empty() {
+        return Err(format!("invalid config  {} dir is required", conf));
+    }
+    if cfg.row_group_rows == 0 {
+        return Err(format!(
+            "invalid config  {} row_group_rows must be over 0",
+            conf
+        ));
+    }
+    Ok(cfg)
+}
+
+type Row = HashMap<String, serde_json::Value>;
+
+// widest type of every column of rows, none for null only columns
+fn observed_types(rows: &[Row]) -> BTreeMap<String, Option<ParquetType>> {
+    let mut types: BTreeMap<String, Option<ParquetType>> = BTreeMap::new();
+    for row in rows {
+        for (column, value) in row {
+            let t = match (types.get(column).copied().flatten(), ParquetType::of(value)) {
+                (Some(v), Some(t)) => Some(v.widen(t)),
+                (v, t) => v.or(t),
+            };
+            types.insert(column.to_owned(), t);
+        }
+    }
+    types
+}
+
+// schema holding both, the columns of schema keep their order and new ones are appended.
+// new null only columns are strings
+fn merge_schema(
+    schema: &[ParquetColumn],
+    observed: &BTreeMap<String, Option<ParquetType>>,
+) -> Vec<ParquetColumn> {
+    let mut merged: Vec<ParquetColumn> = schema
+        .iter()
+        .map(|c| match observed.get(&c.name) {
+            Some(Some(t)) => ParquetColumn {
+                name: c.name.clone(),
+                column_type: c.column_type.widen(*t),
+            },
+            _ => c.clone(),
+        })
+        .collect();
+    for (name, t) in observed {
+        if !schema.iter().any(|c| &c.name == name) {
+            merged.push(ParquetColumn {
+                name: name.to_owned(),
+                column_type: t.unwrap_or(ParquetType::String),
+            });
+        }
+    }
+    merged
+}
+
+fn record_batch(schema: &[ParquetColumn], rows: &[Row]) -> Result<RecordBatch, String> {
+    let fields: Vec<Field> = schema
+        .iter()
+        .map(|c| Field::new(c.name.as_str(), c.column_type.data_type(), true))
+        .collect();
+    let columns: Vec<ArrayRef> = schema
+        .iter()
+        .map(|c| {
+            let values = rows.iter().map(|row| row.get(&c.name));
+            let array: ArrayRef = match c.column_type {
+                ParquetType::Boolean => Arc::new(BooleanArray::from(
+                    values
+                        .map(|v| v.and_then(|v| v.as_bool()))
+                        .collect::<Vec<_>>(),
+                )),
+                ParquetType::Int64 => Arc::new(Int64Array::from(
+                    values
+                        .map(|v| v.and_then(|v| v.as_i64()))
+                        .collect::<Vec<_>>(),
+                )),
+                ParquetType::Double => Arc::new(Float64Array::from(
+                    values
+                        .map(|v| v.and_then(|v| v.as_f64()))
+                        .collect::<Vec<_>>(),
+                )),
+                ParquetType::String => Arc::new(StringArray::from(
+                    values
+                        .map(|v| match v {
+                            None | Some(serde_json::Value::Null) => None,
+                            Some(serde_json::Value::String(s)) => Some(s.to_owned()),
+                            Some(v) => Some(v.to_string()),
+                        })
+                        .collect::<Vec<_>>(),
+                )),
+            };
+            array
+        })
+        .collect();
+    match RecordBatch::try_new(Arc::new(Schema::new(fields)), columns) {
+        Ok(v) => Ok(v),
+        Err(err) => Err(format!("build record batch error {:?}", err)),
+    }
+}
+
+struct OpenParquet {
+    writer: ArrowWriter<File>,
+    tmp_path: PathBuf,
+    path: PathBuf,
+    rows: usize,
+    opened_at: Instant,
+}
+
+struct ParquetTable {
+    task_id: String,
+    cfg: ParquetDstConfig,
+    // tells apart the files of dsts sharing a dir and of earlier runs
+    id: String,
+    seq: u64,
+    // schema of the current file, none until the first batch
+    schema: Option<Vec<ParquetColumn>>,
+    curr: Option<OpenParquet>,
+    // rows and msgs of the next row group
+    buffer: Vec<Row>,
+    buffer_msgs: Vec<Msg>,
+    buffer_at: Option<Instant>,
+    // msgs of the rows in the current file
+    pending: Vec<Msg>,
+}
+
+impl ParquetTable {
+    fn new(task_id: String, cfg: ParquetDstConfig) -> Self {
+        let schema = if cfg.schema.is_empty() {
+            None
+        } else {
+            Some(cfg.schema.clone())
+        };
+        ParquetTable {
+            task_id,
+            cfg,
+            id: uuid::Uuid::new_v4().simple().to_string()[..8].to_owned(),
+            seq: 0,
+            schema,
+            curr: None,
+            buffer: vec![],
+            buffer_msgs: vec![],
+            buffer_at: None,
+            pending: vec![],
+        }
+    }
+
+    fn buffer_full(&self) -> bool {
+        self.buffer.len()
+            >= self
+                .cfg
+                .row_group_rows
+                .saturating_mul(MAX_BUFFERED_ROW_GROUPS)
+    }
+
+    // values of rows the declared schema can not hold, an inferred schema widens instead
+    fn check_rows(&self, rows: &[Row]) -> Result<(), String> {
+        for c in &self.cfg.schema {
+            for row in rows {
+                match row.get(&c.name) {
+                    Some(v) if !c.column_type.holds(v) => {
+                        return Err(format!(
+                            "column {} value {} is not {:?}",
+                            c.name, v, c.column_type
+                        ))
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_msg(&mut self, rows: Vec<Row>, msg: Msg) -> Result<(), String> {
+        if self.buffer_at.is_none() {
+            self.buffer_at = Some(Instant::now());
+        }
+        self.buffer.extend(rows);
+        self.buffer_msgs.push(msg);
+        if self.buffer.len() >= self.cfg.row_group_rows {
+            self.flush_buffer()?;
+        }
+        Ok(())
+    }
+
+    // write the buffer as a row group, starting a new file when the schema changes
+    fn flush_buffer(&mut self) -> Result<(), String> {
+        self.buffer_at = None;
+        if self.buffer.is_empty() {
+            self.pending.append(&mut self.buffer_msgs);
+            return Ok(());
+        }
+        let observed = observed_types(&self.buffer);
+        let schema = match &self.schema {
+            None => merge_schema(&[], &observed),
+            // a declared schema never changes
+            Some(v) if !self.cfg.schema.is_empty() => v.clone(),
+            Some(v) => merge_schema(v, &observed),
+        };
+        if self.schema.as_ref() != Some(&schema) {
+            self.close_file()?;
+            self.schema = Some(schema.clone());
+        }
+
+        let batch = record_batch(&schema, &self.buffer)?;
+        if self.curr.is_none() {
+            self.open(batch.schema())?;
+        }
+        let curr = self.curr.as_mut().unwrap();
+        if let Err(err) = curr.writer.write(&batch) {
+            return Err(format!("write {:?} error {:?}", curr.tmp_path, err));
+        }
+        // end the row group here, the buffer is a row group
+        if let Err(err) = curr.writer.flush() {
+            return Err(format!("flush {:?} error {:?}", curr.tmp_path, err));
+        }
+        curr.rows += self.buffer.len();
+        self.buffer.clear();
+        self.pending.append(&mut self.buffer_msgs);
+        if self.cfg.max_rows > 0 && curr.rows >= self.cfg.max_rows {
+            self.close_file()?;
+        }
+        Ok(())
+    }
+
+    // like <task_id>-20231201083000-1a2b3c4d-0.parquet
+    fn open(&mut self, schema: Arc<Schema>) -> Result<(), String> {
+        if let Err(err) = fs::create_dir_all(&self.cfg.dir) {
+            return Err(format!("create dir {} error {:?}", self.cfg.dir, err));
+        }
+        let name = format!(
+            "{}-{}-{}-{}.parquet",
+            self.task_id,
+            chrono::Local::now().format("%Y%m%d%H%M%S"),
+            self.id,
+            self.seq
+        );
+        self.seq += 1;
+        let path = PathBuf::from(&self.cfg.dir).join(&name);
+        let tmp_path = PathBuf::from(&self.cfg.dir).join(format!(".{}.tmp", name));
+        let file = match File::create(&tmp_path) {
+            Ok(v) => v,
+            Err(err) => return Err(format!("create file {:?} error {:?}", tmp_path, err)),
+        };
+        let compression = match self.cfg.compression {
+            ParquetCompression::None => Compression::UNCOMPRESSED,
+            ParquetCompression::Snappy => Compression::SNAPPY,
+            ParquetCompression::Zstd => Compression::ZSTD(ZstdLevel::default()),
+        };
+        let props = WriterProperties::builder()
+            .set_compression(compression)
+            .set_max_row_group_size(self.cfg.row_group_rows)
+            .build();
+        let writer = match ArrowWriter::try_new(file, schema, Some(props)) {
+            Ok(v) => v,
+            Err(err) => return Err(format!("create writer {:?} error {:?}", tmp_path, err)),
+        };
+        info!("[dst] parquet task_id {} open {:?}", self.task_id, path);
+        self.curr = Some(OpenParquet {
+            writer,
+            tmp_path,
+            path,
+            rows: 0,
+            opened_at: Instant::now(),
+        });
+        Ok(())
+    }
+
+    // finish the current file, rename it to its final name and ack its rows
+    fn close_file(&mut self) -> Result<(), String> {
+        let curr = match self.curr.take() {
+            Some(v) => v,
+            None => {
+                // msgs without rows have nothing to wait for
+                self.pending.drain(..).for_each(|mut msg| msg.ack());
+                return Ok(());
+            }
+        };
+        let file = match curr.writer.into_inner() {
+            Ok(v) => v,
+            Err(err) => {
+                let _ = fs::remove_file(&curr.tmp_path);
+                return Err(format!("finish {:?} error {:?}", curr.tmp_path, err));
+            }
+        };
+        if let Err(err) = file.sync_all() {
+            let _ = fs::remove_file(&curr.tmp_path);
+            return Err(format!("sync {:?} error {:?}", curr.tmp_path, err));
+        }
+        if let Err(err) = rename_new(&curr.tmp_path, &curr.path) {
+            let _ = fs::remove_file(&curr.tmp_path);
+            return Err(format!(
+                "rename {:?} to {:?} error {:?}",
+                curr.tmp_path, curr.path, err
+            ));
+        }
+        info!(
+            "[dst] parquet task_id {} close {:?} rows {}",
+            self.task_id, curr.path, curr.rows
+        );
+        self.pending.drain(..).for_each(|mut msg| msg.ack());
+        Ok(())
+    }
+
+    // drop the current file after a failed write, its msgs are returned unacked.
+    // the buffer is kept and written to the next file
+    fn abandon(&mut self) -> Vec<Msg> {
+        if let Some(curr) = self.curr.take() {
+            let _ = fs::remove_file(&curr.tmp_path);
+        }
+        self.pending.drain(..).collect()
+    }
+
+    fn tick(&mut self) -> Result<(), String> {
+        if self.cfg.max_secs == 0 {
+            return Ok(());
+        }
+        let max = Duration::from_secs(self.cfg.max_secs);
+        let file_due = matches!(&self.curr, Some(curr) if curr.opened_at.elapsed() >= max);
+        let buffer_due = matches!(self.buffer_at, Some(at) if at.elapsed() >= max);
+        if file_due || buffer_due {
+            self.flush_buffer()?;
+            self.close_file()?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), String> {
+        self.flush_buffer()?;
+        self.close_file()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::Array;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use crate::transform::flatten::{chrysaetos, flatten_msg};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_parquet_dst_widen() {
+        let dir = std::env::temp_dir().join(format!("varbit-parquet-dst-{}", uuid::Uuid::new_v4()));
+        let (sender, receive) = mpsc::channel::<Msg>(10);
+        let conf = serde_json::json!({
+            "dir": dir.to_str().unwrap(),
+            "row_group_rows": 2,
+        });
+        let cry = chrysaetos(&"task".to_owned(), &conf);
+        let handler = tokio::spawn(async move {
+            ParquetDst {}.to_dst("task".to_owned(), conf, receive).await;
+        });
+        for (g_id, value) in [
+            ("1", serde_json::json!({"a": 1, "b": {"c": "x"}})),
+            ("2", serde_json::json!({"a": 2})),
+            ("3", serde_json::json!({"a": 3.5})),
+        ] {
+            let msg = flatten_msg(&cry, Msg::new(g_id.to_owned(), value));
+            sender.send(msg).await.unwrap();
+        }
+        drop(sender);
+        handler.await.unwrap();
+
+        let mut names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort_by_key(|name| name.rsplit('-').next().unwrap().to_owned());
+        assert_eq!(names.len(), 2);
+
+        let read = |name: &String| -> RecordBatch {
+            let file = File::open(dir.join(name)).unwrap();
+            let mut reader = ParquetRecordBatchReaderBuilder::try_new(file)
+                .unwrap()
+                .build()
+                .unwrap();
+            reader.next().unwrap().unwrap()
+        };
+        let batch = read(&names[0]);
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(batch.schema().field(0).data_type(), &DataType::Int64);
+        assert_eq!(batch.schema().field(1).name(), "b_c");
+        assert!(batch.column(1).is_null(1));
+
+        // a double widens the int64 column in a new file
+        let batch = read(&names[1]);
+        assert_eq!(batch.schema().field(0).data_type(), &DataType::Float64);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_schema() {
+        let schema = vec![ParquetColumn {
+            name: "a".to_owned(),
+            column_type: ParquetType::Int64,
+        }];
+        let mut observed = BTreeMap::new();
+        observed.insert("a".to_owned(), Some(ParquetType::Boolean));
+        observed.insert("b".to_owned(), Some(ParquetType::Double));
+        observed.insert("c".to_owned(), None);
+        let merged = merge_schema(&schema, &observed);
+        assert_eq!(merged[0].column_type, ParquetType::String);
+        assert_eq!(merged[1].name, "b");
+        assert_eq!(merged[2].column_type, ParquetType::String);
+        assert_eq!(merge_schema(&merged, &observed), merged);
+
+        let cfg = ParquetDstConfig {
+            schema,
+            row_group_rows: 1,
+            ..Default::default()
+        };
+        let table = ParquetTable::new("task".to_owned(), cfg);
+        let rows: Vec<Row> = vec![serde_json::from_value(serde_json::json!({"a": 1})).unwrap()];
+        assert!(table.check_rows(&rows).is_ok());
+        let rows: Vec<Row> = vec![serde_json::from_value(serde_json::json!({"a": "1"})).unwrap()];
+        assert!(table.check_rows(&rows).is_err());
+    }
+}
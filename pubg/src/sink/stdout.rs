@@ -0,0 +1,166 @@
+use async_trait::async_trait;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::core::{redact_secrets, Msg};
+
+use super::{msg_rows, Dst};
+
+// log target of printed rows, so they can be told apart from varbit logs
+const STDOUT_TARGET: &str = "varbit::dst::stdout";
+
+/// prints flattened rows through the logger, a no-op dst to tune tasking_cfg against a real src.
+/// every msg is acked, rows over the rate cap are counted but not printed
+pub struct StdoutDst {}
+#[async_trait]
+impl Dst for StdoutDst {
+    async fn to_dst(
+        &self,
+        task_id: String,
+        conf: serde_json::Value,
+        mut receive: mpsc::Receiver<Msg>,
+    ) {
+        info!(
+            "[dst] {} task_id:{} conf {}",
+            self.dst_name(),
+            task_id,
+            redact_secrets(&conf)
+        );
+        let sfc = match check_stdout_dst_cfg(&conf) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("[dst] stdout task_id {} {}", task_id, err);
+                return;
+            }
+        };
+        let mut cap = RateCap::new(sfc.max_rows_per_sec);
+
+        while let Some(mut msg) = receive.recv().await {
+            let rows = match msg_rows(&task_id, &mut msg).await {
+                Some(v) => v,
+                None => continue,
+            };
+            for data in rows {
+                let (allow, dropped) = cap.allow(Instant::now());
+                if dropped > 0 {
+                    info!(
+                        target: STDOUT_TARGET,
+                        "task_id {} skipped {} rows over {} rows/s",
+                        task_id,
+                        dropped,
+                        sfc.max_rows_per_sec
+                    );
+                }
+                if !allow {
+                    continue;
+                }
+                let row = if sfc.pretty {
+                    serde_json::to_string_pretty(&data).unwrap_or_default()
+                } else {
+                    serde_json::json!(data).to_string()
+                };
+                info!(
+                    target: STDOUT_TARGET,
+                    "task_id {} g_id {} {}", task_id, msg.g_id, row
+                );
+            }
+            msg.ack();
+        }
+        info!("[dst] stdout task_id {} exit", task_id);
+    }
+
+    fn cfg(&self) -> serde_json::Value {
+        serde_json::json!(StdoutDstConfig::default())
+    }
+
+    fn dst_name(&self) -> String {
+        "stdout".to_owned()
+    }
+
+    fn check_cfg(&self, conf: &serde_json::Value) -> Result<(), String> {
+        check_stdout_dst_cfg(conf).map(|_| ())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+pub struct StdoutDstConfig {
+    // multi-line json rows
+    #[serde(default)]
+    pub pretty: bool,
+    // print at most this many rows a second, 0 is no cap
+    #[serde(default)]
+    pub max_rows_per_sec: u64,
+}
+
+pub fn check_stdout_dst_cfg(conf: &serde_json::Value) -> Result<StdoutDstConfig, String> {
+    match serde_json::from_value::<StdoutDstConfig>(conf.clone()) {
+        Ok(v) => Ok(v),
+        Err(err) => Err(format!("invalid config  {} error {:?}", conf, err)),
+    }
+}
+
+// fixed one second windows
+struct RateCap {
+    max: u64,
+    window_at: Option<Instant>,
+    count: u64,
+    dropped: u64,
+}
+
+impl RateCap {
+    fn new(max: u64) -> Self {
+        RateCap {
+            max,
+            window_at: None,
+            count: 0,
+            dropped: 0,
+        }
+    }
+
+    // whether a row at now is printed, and the rows dropped in the window just ended
+    fn allow(&mut self, now: Instant) -> (bool, u64) {
+        if self.max == 0 {
+            return (true, 0);
+        }
+        let mut dropped = 0;
+        let rolled = match self.window_at {
+            None => true,
+            Some(at) => now.duration_since(at) >= Duration::from_secs(1),
+        };
+        if rolled {
+            dropped = self.dropped;
+            self.window_at = Some(now);
+            self.count = 0;
+            self.dropped = 0;
+        }
+        if self.count < self.max {
+            self.count += 1;
+            (true, dropped)
+        } else {
+            self.dropped += 1;
+            (false, dropped)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_cap() {
+        let now = Instant::now();
+        let mut cap = RateCap::new(2);
+        assert_eq!(cap.allow(now), (true, 0));
+        assert_eq!(cap.allow(now), (true, 0));
+        assert_eq!(cap.allow(now), (false, 0));
+        assert_eq!(cap.allow(now + Duration::from_millis(500)), (false, 0));
+        // the next window reports the rows skipped in the last one
+        assert_eq!(cap.allow(now + Duration::from_secs(1)), (true, 2));
+
+        let mut cap = RateCap::new(0);
+        assert_eq!(cap.allow(now), (true, 0));
+    }
+}
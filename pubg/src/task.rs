@@ -4,14 +4,24 @@ use std::{
     time::Duration,
 };
 
+use futures::future::{join_all, select_all};
 use lazy_static::lazy_static;
 use log::{error, info};
 use schema::task::update_task_heartbeat;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 use tokio_context::context;
 
-use crate::{core::Msg, input::Src, sink::Dst, CloseTask, DST_PLUGIN, SRC_PLUGIN};
+use crate::{
+    core::Msg,
+    dead_letter,
+    input::kafka::topic_subscriptions,
+    input::Src,
+    metrics,
+    sink::{Dst, TASKING_CFG_KEY},
+    transform::{redact, spawn_transforms, task_transforms},
+    CloseTask, DST_PLUGIN, SRC_PLUGIN,
+};
 
 pub struct Tasking {
     pub handle: context::Handle,
@@ -26,42 +36,126 @@ lazy_static! {
 
 }
 
+/// one sink of a task, it flattens with the task tasking cfg unless it has its own
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct TaskDst {
+    pub dst_type: String,
+    pub dst_cfg: serde_json::Value,
+    #[serde(default)]
+    pub tasking_cfg: Option<serde_json::Value>,
+}
+
 pub async fn dispatch_tasking(
     task_id: String,
     src_type: String,
     src_conf: &serde_json::Value,
-    dst_type: String,
-    dst_conf: &serde_json::Value,
+    dsts: &[TaskDst],
+    tasking_cfg: &serde_json::Value,
     after_close_task: Box<dyn CloseTask>,
 ) -> bool {
-    let mut lock = GLOBAL_TASKING.lock().unwrap();
-    if lock.contains_key(task_id.to_owned().as_str()) {
+    if task_running(&task_id).await {
         error!("task {} is running", task_id.clone());
         return false;
     }
+    if dsts.is_empty() {
+        error!("task {} has no dst", task_id);
+        return false;
+    }
 
     let (rx, mut _tx) = mpsc::channel::<Msg>(20);
 
-    let mut _dst: std::sync::MutexGuard<'_, HashMap<String, Arc<Box<dyn Dst + Send + Sync>>>> =
-        DST_PLUGIN.lock().unwrap();
-    if !_dst.contains_key(dst_type.as_str()) {
-        error!("not found dst_type {}", dst_type);
+    let mut sinks = vec![];
+    {
+        let _dst: std::sync::MutexGuard<'_, HashMap<String, Arc<Box<dyn Dst + Send + Sync>>>> =
+            DST_PLUGIN.lock().unwrap();
+        for dst in dsts {
+            match _dst.get(dst.dst_type.as_str()) {
+                Some(v) => sinks.push(v.clone()),
+                None => {
+                    error!("not found dst_type {}", dst.dst_type);
+                    return false;
+                }
+            }
+        }
+    }
+    // transforms in front of each dst, built from its tasking cfg
+    let mut chains = vec![];
+    for dst in dsts {
+        let tasking_cfg = dst.tasking_cfg.as_ref().unwrap_or(tasking_cfg);
+        match task_transforms(tasking_cfg) {
+            Ok(v) => chains.push(v),
+            Err(err) => {
+                error!("task {} dst {} {}", task_id, dst.dst_type, err);
+                return false;
+            }
+        }
+    }
+    // failed msgs of src and dst go to the task dead letter dst,
+    // started before the lock as it may connect to kafka. only a dead letter
+    // started here is stopped on failure, one of a running task is left alone
+    let started = match dead_letter::start_dead_letter(&task_id, tasking_cfg) {
+        Ok(v) => v,
+        Err(err) => {
+            error!("task {} {}", task_id, err);
+            return false;
+        }
+    };
+    let stop_dead_letter = || {
+        if started {
+            dead_letter::stop_dead_letter(&task_id);
+        }
+    };
+    let mut lock = GLOBAL_TASKING.lock().unwrap();
+    if lock.contains_key(task_id.to_owned().as_str()) {
+        error!("task {} is running", task_id.clone());
+        stop_dead_letter();
         return false;
     }
-    let _dst = _dst.get(dst_type.to_owned().as_str()).unwrap().clone();
-    let dst_conf = dst_conf.clone();
-    let task_id_2_dst = task_id.clone();
-    // start dst task
-    let dst_handler = tokio::task::spawn(async move {
-        _dst.to_dst(task_id_2_dst.clone(), dst_conf.clone(), _tx)
-            .await;
-    });
+    metrics::reset_task_metrics(&task_id);
+    redact::reset_pii_report(&task_id);
+    // start dst tasks, each behind its own transforms and channel
+    let mut dst_handlers = vec![];
+    let mut transform_handlers = vec![];
+    let mut dst_senders = vec![];
+    for ((_dst, dst), chain) in sinks.into_iter().zip(dsts).zip(chains) {
+        let mut dst_conf = dst.dst_cfg.clone();
+        // dst reads declared columns from its tasking cfg
+        if let Some(obj) = dst_conf.as_object_mut() {
+            let tasking_cfg = dst.tasking_cfg.as_ref().unwrap_or(tasking_cfg);
+            obj.insert(TASKING_CFG_KEY.to_owned(), tasking_cfg.clone());
+        }
+        let (sender, receive) = mpsc::channel::<Msg>(20);
+        let task_id_2_dst = task_id.clone();
+        dst_handlers.push(tokio::task::spawn(async move {
+            _dst.to_dst(task_id_2_dst.clone(), dst_conf.clone(), receive)
+                .await;
+        }));
+        let sender = match spawn_transforms(&task_id, chain, sender) {
+            Ok((sender, handlers)) => {
+                transform_handlers.extend(handlers);
+                sender
+            }
+            Err(err) => {
+                error!("task {} dst {} {}", task_id, dst.dst_type, err);
+                dst_handlers.iter().for_each(|h| h.abort());
+                transform_handlers.iter().for_each(|h| h.abort());
+                stop_dead_letter();
+                return false;
+            }
+        };
+        dst_senders.push((dst.dst_type.to_owned(), sender));
+    }
+    let dst_handler = tokio::task::spawn(broadcast(task_id.clone(), _tx, dst_senders));
 
     let mut _data: std::sync::MutexGuard<'_, HashMap<String, Arc<Box<dyn Src + Send + Sync>>>> =
         SRC_PLUGIN.lock().unwrap();
     if !_data.contains_key(src_type.as_str()) {
         error!("not found src_type {}", src_type);
-        dst_handler.abort(); // close dst task
+        // close dst tasks
+        dst_handler.abort();
+        dst_handlers.iter().for_each(|h| h.abort());
+        transform_handlers.iter().for_each(|h| h.abort());
+        stop_dead_letter();
         return false;
     }
     let source = _data.get(src_type.to_owned().as_str()).unwrap();
@@ -73,6 +167,7 @@ pub async fn dispatch_tasking(
             .from_src(task_id_2_src.clone(), &src_conf.clone(), rx)
             .await;
     });
+    let src_abort = src_handler.abort_handle();
     let (_, mut handle) = context::Context::new();
     let mut ctx = handle.spawn_ctx();
     let task_id_cp = task_id.clone();
@@ -99,23 +194,70 @@ pub async fn dispatch_tasking(
     //
     let task_id_cp = task_id.clone();
     tokio::task::spawn(async move {
-        tokio::select! {
-            _ = dst_handler => {
-                info!("dst cancel task {}",task_id_cp);
-                remove_tasking(task_id_cp).await;
-            }
+        // a dst exited, stop the task before an offset commits without it
+        if let Ok(true) = dst_handler.await {
+            error!("task {} stops with its closed dst", task_id_cp);
+            src_abort.abort();
+            transform_handlers.iter().for_each(|h| h.abort());
+            dst_handlers.iter().for_each(|h| h.abort());
         }
+        join_all(dst_handlers).await;
+        info!("dst cancel task {}", task_id_cp);
+        remove_tasking(task_id_cp).await;
     });
     lock.insert(task_id.to_owned(), Box::new(Tasking { handle: handle }));
     return true;
 }
 
+// send every msg to each dst, a msg is acked once all the dsts acked it.
+// gives back true when a dst exited, the task has to stop then as later
+// acks would commit offsets that dst never got. the msgs it missed stay uncommitted
+async fn broadcast(
+    task_id: String,
+    mut receive: mpsc::Receiver<Msg>,
+    dsts: Vec<(String, mpsc::Sender<Msg>)>,
+) -> bool {
+    loop {
+        let closed = select_all(dsts.iter().map(|(_, sender)| Box::pin(sender.closed())));
+        let mut msg = tokio::select! {
+            res = receive.recv() => match res {
+                Some(v) => v,
+                None => break,
+            },
+            (_, i, _) = closed => {
+                error!("task_id {} dst {} is closed", task_id, dsts[i].0);
+                return true;
+            }
+        };
+        let mut acks = match msg.ack.take() {
+            Some(ack) => ack.split(dsts.len()).into_iter().map(Some).collect(),
+            None => (0..dsts.len()).map(|_| None).collect::<Vec<_>>(),
+        };
+        for (i, (dst_type, sender)) in dsts.iter().enumerate() {
+            let part = Msg {
+                g_id: msg.g_id.clone(),
+                value: msg.value.clone(),
+                rows: msg.rows.clone(),
+                ack: acks[i].take(),
+            };
+            // a full channel holds back the src until this dst catches up
+            if sender.send(part).await.is_err() {
+                error!("task_id {} dst {} is closed", task_id, dst_type);
+                return true;
+            }
+        }
+    }
+    info!("task_id {} broadcast exit", task_id);
+    false
+}
+
 pub async fn remove_tasking(task_id: String) -> bool {
     let mut lock = GLOBAL_TASKING.lock().unwrap();
     if !lock.contains_key(task_id.to_owned().as_str()) {
         return false;
     }
     lock.remove(task_id.to_owned().as_str());
+    dead_letter::stop_dead_letter(&task_id);
     return true;
 }
 
@@ -130,12 +272,19 @@ struct InputKafkaConfigMeta {
     /// broker
     pub broker: String,
     /// topic
+    #[serde(default)]
     pub topic: String,
+    /// topic list or ^regex subscriptions
+    #[serde(default)]
+    pub topics: Vec<String>,
 }
 
 pub fn check_kafka_src(cfg: &String) -> Result<(), String> {
     match serde_json::from_str::<InputKafkaConfigMeta>(cfg.as_str()) {
-        Ok(_) => Ok(()),
+        Ok(v) => match topic_subscriptions(&v.topic, &v.topics) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("invalid kafka input cfg {}", err)),
+        },
         Err(err) => Err(format!("invalid kafka input cfg {:?}", err)),
     }
 }
@@ -154,3 +303,60 @@ async fn heartbeat_handler(task_id: String) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::core::{Ack, Offset};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_broadcast_acks_after_all_dsts() {
+        let (src, receive) = mpsc::channel::<Msg>(10);
+        let (a_tx, mut a_rx) = mpsc::channel::<Msg>(10);
+        let (b_tx, mut b_rx) = mpsc::channel::<Msg>(10);
+        let (ack_tx, mut ack_rx) = mpsc::unbounded_channel::<Offset>();
+        let handler = tokio::spawn(broadcast(
+            "broadcast-task".to_owned(),
+            receive,
+            vec![("a".to_owned(), a_tx), ("b".to_owned(), b_tx)],
+        ));
+
+        let ack = Ack::new(Offset::new("t".to_owned(), 0, 7), ack_tx.clone());
+        let value = serde_json::json!({"a": 1});
+        src.send(Msg::with_ack("g".to_owned(), value, ack))
+            .await
+            .unwrap();
+        a_rx.recv().await.unwrap().ack();
+        assert!(ack_rx.try_recv().is_err());
+        b_rx.recv().await.unwrap().ack();
+        assert_eq!(ack_rx.recv().await.unwrap().offset, 7);
+
+        drop(src);
+        assert!(!handler.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_stops_with_a_closed_dst() {
+        let (src, receive) = mpsc::channel::<Msg>(10);
+        let (a_tx, mut a_rx) = mpsc::channel::<Msg>(10);
+        let (b_tx, b_rx) = mpsc::channel::<Msg>(10);
+        let (ack_tx, mut ack_rx) = mpsc::unbounded_channel::<Offset>();
+        let handler = tokio::spawn(broadcast(
+            "broadcast-closed-task".to_owned(),
+            receive,
+            vec![("a".to_owned(), a_tx), ("b".to_owned(), b_tx)],
+        ));
+
+        let ack = Ack::new(Offset::new("t".to_owned(), 0, 1), ack_tx);
+        src.send(Msg::with_ack("g".to_owned(), serde_json::json!({}), ack))
+            .await
+            .unwrap();
+        let mut msg = a_rx.recv().await.unwrap();
+        // b exits without acking its part
+        drop(b_rx);
+        msg.ack();
+        assert!(handler.await.unwrap());
+        assert!(ack_rx.try_recv().is_err());
+    }
+}
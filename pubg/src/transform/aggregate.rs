@@ -0,0 +1,640 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+
+use crate::core::{Ack, Msg, Offset, Row};
+use crate::dead_letter::{self, DeadLetter, Stage};
+use crate::metrics;
+
+use super::Transform;
+
+pub const AGGREGATE: &str = "aggregate";
+pub const WINDOW_START: &str = "window_start";
+pub const WINDOW_END: &str = "window_end";
+
+// windows a row may fall in, slide_secs can not be tiny next to window_secs
+const MAX_WINDOWS_PER_ROW: i64 = 1000;
+// registers of a distinct count estimate are 2^HLL_BITS
+const HLL_BITS: u32 = 12;
+
+/// aggregates rows per group over tumbling or sliding windows, one row per group
+/// is emitted when a window closes. the msgs of a window are acked once its rows are delivered
+pub struct AggregateTransform {}
+#[async_trait]
+impl Transform for AggregateTransform {
+    async fn to_transform(
+        &self,
+        task_id: String,
+        conf: Value,
+        mut receive: mpsc::Receiver<Msg>,
+        sender: mpsc::Sender<Msg>,
+    ) {
+        let cfg = match check_aggregate_cfg(&conf) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("[transform] aggregate task_id {} {}", task_id, err);
+                return;
+            }
+        };
+        let mut aggregator = Aggregator::new(cfg.clone());
+        // emitted windows wait here for their rows to be delivered
+        let (delivered, mut acked) = mpsc::unbounded_channel::<Offset>();
+        let mut emitted: HashMap<i64, Vec<Ack>> = HashMap::new();
+        let mut seq = 0;
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                msg = receive.recv() => {
+                    let mut msg = match msg {
+                        Some(v) => v,
+                        None => break,
+                    };
+                    for (row, reason) in aggregator.add(&mut msg, now_ms()) {
+                        late(&task_id, &cfg, &msg.g_id, row, reason).await;
+                    }
+                }
+                _ = ticker.tick() => (),
+                Some(offset) = acked.recv() => {
+                    emitted
+                        .remove(&offset.offset)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .for_each(|ack| ack.ack());
+                    continue;
+                }
+            }
+            for (start, rows, acks) in aggregator.close(now_ms()) {
+                seq += 1;
+                emitted.insert(seq, acks);
+                let offset = Offset::new(AGGREGATE.to_owned(), 0, seq);
+                let mut msg = Msg::with_ack(
+                    format!("window-{}", start),
+                    Value::Array(rows.iter().map(|v| serde_json::json!(v)).collect()),
+                    Ack::new(offset, delivered.clone()),
+                );
+                msg.rows = Some(rows);
+                if sender.send(msg).await.is_err() {
+                    info!("[transform] aggregate task_id {} exit", task_id);
+                    return;
+                }
+            }
+        }
+        // open windows are dropped, their msgs are not acked and are read again
+        info!("[transform] aggregate task_id {} exit", task_id);
+    }
+
+    fn cfg(&self) -> Value {
+        serde_json::json!({
+            "group_by": [],
+            "window_secs": 60,
+            "aggregates": {"count": {"op": "count"}},
+        })
+    }
+
+    fn transform_name(&self) -> String {
+        AGGREGATE.to_owned()
+    }
+
+    fn check_cfg(&self, conf: &Value) -> Result<(), String> {
+        check_aggregate_cfg(conf).map(|_| ())
+    }
+}
+
+async fn late(task_id: &String, cfg: &AggregateConfig, g_id: &String, row: Row, reason: &str) {
+    metrics::record_late(task_id, 1);
+    if cfg.late == LatePolicy::DeadLetter {
+        let payload = serde_json::json!(row).to_string();
+        let error = format!("row is {} for the {} windows", reason, AGGREGATE);
+        let letter = DeadLetter::new(
+            task_id,
+            g_id,
+            Stage::Transform,
+            reason,
+            error,
+            payload.as_bytes(),
+        );
+        // the row has no ack of its own, the msg is acked with its other rows
+        let _ = dead_letter::route(letter, None).await;
+    }
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Op {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+    // hyperloglog estimate
+    DistinctCount,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AggregateSpec {
+    pub op: Op,
+    // column aggregated, count without one counts rows
+    #[serde(default)]
+    pub field: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeUnit {
+    #[default]
+    Ms,
+    S,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LatePolicy {
+    // counted in the task metrics
+    #[default]
+    Drop,
+    // counted and sent to the dead letter dst
+    DeadLetter,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AggregateConfig {
+    #[serde(default)]
+    pub group_by: Vec<String>,
+    pub window_secs: u64,
+    // a window starts every slide_secs, 0 is tumbling windows
+    #[serde(default)]
+    pub slide_secs: u64,
+    // event time column, empty uses processing time
+    #[serde(default)]
+    pub time_field: String,
+    // unit of a numeric event time, strings are rfc3339
+    #[serde(default)]
+    pub time_unit: TimeUnit,
+    // how far event time may run behind the latest event before a window closes
+    #[serde(default)]
+    pub allowed_lateness_secs: u64,
+    // with no rows for idle_secs event time moves on with the clock,
+    // so an idle src still closes its windows. 0 waits for the next row
+    #[serde(default = "default_idle_secs")]
+    pub idle_secs: u64,
+    #[serde(default)]
+    pub late: LatePolicy,
+    // output column to aggregate
+    pub aggregates: BTreeMap<String, AggregateSpec>,
+}
+
+fn default_idle_secs() -> u64 {
+    60
+}
+
+pub fn check_aggregate_cfg(conf: &Value) -> Result<AggregateConfig, String> {
+    let cfg = match serde_json::from_value::<AggregateConfig>(conf.clone()) {
+        Ok(v) => v,
+        Err(err) => return Err(format!("invalid config  {} error {:?}", conf, err)),
+    };
+    if cfg.window_secs == 0 {
+        return Err("window_secs must be greater than 0".to_owned());
+    }
+    if cfg.slide_secs > cfg.window_secs {
+        return Err("slide_secs is over window_secs".to_owned());
+    }
+    if cfg.slide_secs > 0 && (cfg.window_secs / cfg.slide_secs) as i64 > MAX_WINDOWS_PER_ROW {
+        return Err(format!(
+            "a row would fall in over {} windows",
+            MAX_WINDOWS_PER_ROW
+        ));
+    }
+    if cfg.aggregates.is_empty() {
+        return Err("no aggregates".to_owned());
+    }
+    for (column, spec) in &cfg.aggregates {
+        if spec.op != Op::Count && spec.field.is_empty() {
+            return Err(format!("aggregate {} has no field", column));
+        }
+        if cfg.group_by.contains(column) || column == WINDOW_START || column == WINDOW_END {
+            return Err(format!(
+                "aggregate {} is a group_by or window column",
+                column
+            ));
+        }
+    }
+    Ok(cfg)
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(v) => v.as_f64(),
+        Value::String(v) => v.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn value_hash(value: &Value) -> u64 {
+    let digest = Sha256::digest(value.to_string().as_bytes());
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+// running state of one aggregate
+enum State {
+    Count(u64),
+    Sum { int: Option<i64>, float: f64 },
+    Min(Option<(f64, Value)>),
+    Max(Option<(f64, Value)>),
+    Avg { sum: f64, count: u64 },
+    Distinct(Vec<u8>),
+}
+
+impl State {
+    fn new(op: Op) -> State {
+        match op {
+            Op::Count => State::Count(0),
+            Op::Sum => State::Sum {
+                int: Some(0),
+                float: 0.0,
+            },
+            Op::Min => State::Min(None),
+            Op::Max => State::Max(None),
+            Op::Avg => State::Avg { sum: 0.0, count: 0 },
+            Op::DistinctCount => State::Distinct(vec![0; 1 << HLL_BITS]),
+        }
+    }
+
+    fn add(&mut self, value: Option<&Value>) {
+        // count without a field counts rows, else like the rest it skips nulls
+        let value = match (&mut *self, value) {
+            (State::Count(n), None) => {
+                *n += 1;
+                return;
+            }
+            (_, None) | (_, Some(Value::Null)) => return,
+            (_, Some(v)) => v,
+        };
+        match self {
+            State::Count(n) => *n += 1,
+            State::Sum { int, float } => {
+                if let Some(v) = number(value) {
+                    *float += v;
+                    *int = match (*int, value.as_i64()) {
+                        (Some(sum), Some(v)) => sum.checked_add(v),
+                        _ => None,
+                    };
+                }
+            }
+            State::Min(min) => {
+                if let Some(v) = number(value) {
+                    if min.as_ref().is_none_or(|(m, _)| v < *m) {
+                        *min = Some((v, value.clone()));
+                    }
+                }
+            }
+            State::Max(max) => {
+                if let Some(v) = number(value) {
+                    if max.as_ref().is_none_or(|(m, _)| v > *m) {
+                        *max = Some((v, value.clone()));
+                    }
+                }
+            }
+            State::Avg { sum, count } => {
+                if let Some(v) = number(value) {
+                    *sum += v;
+                    *count += 1;
+                }
+            }
+            State::Distinct(registers) => {
+                let hash = value_hash(value);
+                let index = (hash >> (64 - HLL_BITS)) as usize;
+                let rank = ((hash << HLL_BITS) | (1 << (HLL_BITS - 1))).leading_zeros() + 1;
+                registers[index] = registers[index].max(rank as u8);
+            }
+        }
+    }
+
+    fn value(&self) -> Value {
+        match self {
+            State::Count(n) => serde_json::json!(n),
+            State::Sum { int: Some(sum), .. } => serde_json::json!(sum),
+            State::Sum { float, .. } => serde_json::json!(float),
+            State::Min(v) | State::Max(v) => v.as_ref().map_or(Value::Null, |v| v.1.clone()),
+            State::Avg { count: 0, .. } => Value::Null,
+            State::Avg { sum, count } => serde_json::json!(sum / *count as f64),
+            State::Distinct(registers) => serde_json::json!(hll_estimate(registers)),
+        }
+    }
+}
+
+fn hll_estimate(registers: &[u8]) -> u64 {
+    let m = registers.len() as f64;
+    let alpha = 0.7213 / (1.0 + 1.079 / m);
+    let sum: f64 = registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+    let estimate = alpha * m * m / sum;
+    let zeros = registers.iter().filter(|r| **r == 0).count();
+    // small counts are better estimated by the empty registers
+    if estimate <= 2.5 * m && zeros > 0 {
+        return (m * (m / zeros as f64).ln()).round() as u64;
+    }
+    estimate.round() as u64
+}
+
+struct Group {
+    keys: Vec<Value>,
+    states: Vec<State>,
+}
+
+#[derive(Default)]
+struct Window {
+    groups: HashMap<String, Group>,
+    acks: Vec<Ack>,
+}
+
+/// windows of one aggregate transform
+pub struct Aggregator {
+    cfg: AggregateConfig,
+    size: i64,
+    slide: i64,
+    lateness: i64,
+    idle: i64,
+    // latest event time seen
+    max_event: Option<i64>,
+    // processing time of the last row with an event time
+    event_at: i64,
+    windows: BTreeMap<i64, Window>,
+}
+
+impl Aggregator {
+    pub fn new(cfg: AggregateConfig) -> Aggregator {
+        let size = cfg.window_secs as i64 * 1000;
+        let slide = match cfg.slide_secs {
+            0 => size,
+            v => v as i64 * 1000,
+        };
+        let lateness = cfg.allowed_lateness_secs as i64 * 1000;
+        let idle = cfg.idle_secs as i64 * 1000;
+        Aggregator {
+            cfg,
+            size,
+            slide,
+            lateness,
+            idle,
+            max_event: None,
+            event_at: 0,
+            windows: BTreeMap::new(),
+        }
+    }
+
+    fn event_time(&self, row: &Row) -> Option<i64> {
+        match row.get(&self.cfg.time_field)? {
+            Value::String(v) => match chrono::DateTime::parse_from_rfc3339(v) {
+                Ok(v) => Some(v.timestamp_millis()),
+                Err(_) => self.in_ms(v.trim().parse().ok()?),
+            },
+            v => self.in_ms(v.as_f64()?),
+        }
+    }
+
+    fn in_ms(&self, v: f64) -> Option<i64> {
+        let v = match self.cfg.time_unit {
+            TimeUnit::Ms => v,
+            TimeUnit::S => v * 1000.0,
+        };
+        v.is_finite().then_some(v as i64)
+    }
+
+    // windows up to the watermark are closed
+    fn watermark(&self, now: i64) -> Option<i64> {
+        match self.cfg.time_field.is_empty() {
+            true => Some(now),
+            false => self.max_event.map(|v| v - self.lateness),
+        }
+    }
+
+    /// put the rows of msg in their windows, gives back the late rows and why.
+    /// the msg ack is split over the windows it went to
+    pub fn add(&mut self, msg: &mut Msg, now: i64) -> Vec<(Row, &'static str)> {
+        let rows = msg.rows.take().unwrap_or_default();
+        let mut late = vec![];
+        let mut touched = BTreeSet::new();
+        for row in rows {
+            let at = match self.cfg.time_field.is_empty() {
+                true => now,
+                false => match self.event_time(&row) {
+                    Some(v) => v,
+                    None => {
+                        late.push((row, "no_event_time"));
+                        continue;
+                    }
+                },
+            };
+            if !self.cfg.time_field.is_empty() {
+                self.max_event = Some(self.max_event.map_or(at, |v| v.max(at)));
+                self.event_at = now;
+            }
+            let watermark = self.watermark(now).unwrap_or(i64::MIN);
+            let last = at - at.rem_euclid(self.slide);
+            let starts: Vec<i64> = (0..)
+                .map(|i| last - i * self.slide)
+                .take_while(|start| start + self.size > at)
+                .filter(|start| start + self.size > watermark)
+                .collect();
+            if starts.is_empty() {
+                late.push((row, "late"));
+                continue;
+            }
+            let keys: Vec<Value> = self
+                .cfg
+                .group_by
+                .iter()
+                .map(|k| row.get(k).cloned().unwrap_or(Value::Null))
+                .collect();
+            let group_id = serde_json::json!(keys).to_string();
+            for start in starts {
+                touched.insert(start);
+                let window = self.windows.entry(start).or_default();
+                let group = window
+                    .groups
+                    .entry(group_id.clone())
+                    .or_insert_with(|| Group {
+                        keys: keys.clone(),
+                        states: self
+                            .cfg
+                            .aggregates
+                            .values()
+                            .map(|v| State::new(v.op))
+                            .collect(),
+                    });
+                for (state, spec) in group.states.iter_mut().zip(self.cfg.aggregates.values()) {
+                    let value = match spec.field.is_empty() {
+                        true => None,
+                        false => Some(row.get(&spec.field).unwrap_or(&Value::Null)),
+                    };
+                    state.add(value);
+                }
+            }
+        }
+        match msg.ack.take() {
+            Some(ack) if touched.is_empty() => ack.ack(),
+            Some(ack) => {
+                for (start, ack) in touched.iter().zip(ack.split(touched.len())) {
+                    self.windows.get_mut(start).unwrap().acks.push(ack);
+                }
+            }
+            None => (),
+        }
+        late
+    }
+
+    /// the windows the watermark passed, as their start, rows and the acks of their msgs
+    pub fn close(&mut self, now: i64) -> Vec<(i64, Vec<Row>, Vec<Ack>)> {
+        if let Some(max_event) = self.max_event {
+            // moved forward, never back, so closed windows stay closed
+            if self.idle > 0 && now - self.event_at >= self.idle {
+                self.max_event = Some(max_event + now - self.event_at);
+                self.event_at = now;
+            }
+        }
+        let watermark = match self.watermark(now) {
+            Some(v) => v,
+            None => return vec![],
+        };
+        let mut closed = vec![];
+        while let Some(entry) = self.windows.first_entry() {
+            let start = *entry.key();
+            if start + self.size > watermark {
+                break;
+            }
+            let window = entry.remove();
+            let mut rows: Vec<Row> = window
+                .groups
+                .into_values()
+                .map(|group| {
+                    let mut row: Row = self.cfg.group_by.iter().cloned().zip(group.keys).collect();
+                    row.insert(WINDOW_START.to_owned(), serde_json::json!(start));
+                    row.insert(WINDOW_END.to_owned(), serde_json::json!(start + self.size));
+                    for (column, state) in self.cfg.aggregates.keys().zip(&group.states) {
+                        row.insert(column.to_owned(), state.value());
+                    }
+                    row
+                })
+                .collect();
+            // stable order for the dst
+            rows.sort_by_cached_key(|row| {
+                let keys: Vec<_> = self.cfg.group_by.iter().map(|k| &row[k]).collect();
+                serde_json::json!(keys).to_string()
+            });
+            closed.push((start, rows, window.acks));
+        }
+        closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(rows: Value) -> Msg {
+        let mut msg = Msg::new("g".to_owned(), Value::Null);
+        msg.rows = Some(serde_json::from_value(rows).unwrap());
+        msg
+    }
+
+    #[test]
+    fn test_tumbling_event_time() {
+        let cfg = check_aggregate_cfg(&serde_json::json!({
+            "group_by": ["k"],
+            "window_secs": 60,
+            "time_field": "ts",
+            "time_unit": "s",
+            "allowed_lateness_secs": 10,
+            "aggregates": {
+                "n": {"op": "count"},
+                "total": {"op": "sum", "field": "v"},
+                "low": {"op": "min", "field": "v"},
+                "high": {"op": "max", "field": "v"},
+                "mean": {"op": "avg", "field": "v"},
+                "users": {"op": "distinct_count", "field": "u"},
+            },
+        }))
+        .unwrap();
+        let mut agg = Aggregator::new(cfg);
+        let rows = serde_json::json!([
+            {"k": "a", "v": 1, "u": "x", "ts": 5},
+            {"k": "a", "v": 3, "u": "y", "ts": 59},
+            {"k": "b", "v": 2.5, "u": "x", "ts": "1970-01-01T00:00:30Z"},
+            {"k": "a", "v": 7, "u": "x", "ts": 65},
+        ]);
+        assert!(agg.add(&mut msg(rows), 0).is_empty());
+        // the watermark is 55s, the first window is still open
+        assert!(agg.close(0).is_empty());
+        let late = agg.add(&mut msg(serde_json::json!([{"ts": 80}, {"k": "a"}])), 0);
+        assert_eq!(late[0].1, "no_event_time");
+
+        let closed = agg.close(0);
+        assert_eq!(closed.len(), 1);
+        let (start, rows, _) = &closed[0];
+        assert_eq!(*start, 0);
+        assert_eq!(rows[0]["k"], "a");
+        assert_eq!(rows[0][WINDOW_END], 60_000);
+        assert_eq!(rows[0]["n"], 2);
+        assert_eq!(rows[0]["total"], 4);
+        assert_eq!(rows[0]["low"], 1);
+        assert_eq!(rows[0]["high"], 3);
+        assert_eq!(rows[0]["mean"], 2.0);
+        assert_eq!(rows[0]["users"], 2);
+        assert_eq!(rows[1]["total"], 2.5);
+        // the first window is closed
+        let late = agg.add(&mut msg(serde_json::json!([{"k": "a", "ts": 30}])), 0);
+        assert_eq!(late[0].1, "late");
+        // no rows for idle_secs, event time moves from 80s to 140s
+        assert!(agg.close(59_000).is_empty());
+        let closed = agg.close(60_000);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].0, 60_000);
+    }
+
+    #[tokio::test]
+    async fn test_sliding_windows_hold_acks() {
+        let cfg = check_aggregate_cfg(&serde_json::json!({
+            "window_secs": 60,
+            "slide_secs": 30,
+            "aggregates": {"n": {"op": "count"}},
+        }))
+        .unwrap();
+        let mut agg = Aggregator::new(cfg);
+        let (offsets, mut committed) = mpsc::unbounded_channel();
+        let offset = Offset::new("t".to_owned(), 0, 1);
+        let mut input = msg(serde_json::json!([{}, {}]));
+        input.ack = Some(Ack::new(offset, offsets));
+        // processing time 45s is in the windows from 0s and 30s
+        agg.add(&mut input, 45_000);
+        let closed = agg.close(60_000);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].1[0]["n"], 2);
+        closed
+            .into_iter()
+            .for_each(|v| v.2.into_iter().for_each(|ack| ack.ack()));
+        assert!(committed.try_recv().is_err());
+
+        let closed = agg.close(90_000);
+        assert_eq!(closed[0].0, 30_000);
+        closed
+            .into_iter()
+            .for_each(|v| v.2.into_iter().for_each(|ack| ack.ack()));
+        assert_eq!(committed.try_recv().unwrap().offset, 1);
+
+        let registers = {
+            let mut state = State::new(Op::DistinctCount);
+            (0..10_000).for_each(|i| state.add(Some(&serde_json::json!(i))));
+            state.value().as_u64().unwrap()
+        };
+        assert!((9_500..10_500).contains(&registers), "{}", registers);
+    }
+}
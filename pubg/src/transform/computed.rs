@@ -0,0 +1,98 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::core::{Msg, Row};
+
+use super::expr::Expr;
+use super::Transform;
+
+pub const COMPUTED: &str = "computed";
+// key of the computed columns in a tasking cfg
+pub const COMPUTED_KEY: &str = "computed";
+
+/// derives columns from expressions over each flattened row
+pub struct ComputedTransform {}
+#[async_trait]
+impl Transform for ComputedTransform {
+    async fn to_transform(
+        &self,
+        task_id: String,
+        conf: serde_json::Value,
+        mut receive: mpsc::Receiver<Msg>,
+        sender: mpsc::Sender<Msg>,
+    ) {
+        let computed = match check_computed_cfg(&conf) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("[transform] computed task_id {} {}", task_id, err);
+                return;
+            }
+        };
+        while let Some(mut msg) = receive.recv().await {
+            if let Some(rows) = msg.rows.as_mut() {
+                rows.iter_mut().for_each(|row| computed.apply(row));
+            }
+            if sender.send(msg).await.is_err() {
+                break;
+            }
+        }
+        info!("[transform] computed task_id {} exit", task_id);
+    }
+
+    fn cfg(&self) -> serde_json::Value {
+        serde_json::json!(ComputedConfig::default())
+    }
+
+    fn transform_name(&self) -> String {
+        COMPUTED.to_owned()
+    }
+
+    fn check_cfg(&self, conf: &serde_json::Value) -> Result<(), String> {
+        check_computed_cfg(conf).map(|_| ())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+pub struct ComputedConfig {
+    // column to expression like "total": "price * qty"
+    pub fields: BTreeMap<String, String>,
+}
+
+/// parsed computed columns
+pub struct Computed {
+    fields: Vec<(String, Expr)>,
+}
+
+impl Computed {
+    pub fn parse(fields: &BTreeMap<String, String>) -> Result<Computed, String> {
+        let mut parsed = vec![];
+        for (column, src) in fields {
+            match Expr::parse(src) {
+                Ok(v) => parsed.push((column.to_owned(), v)),
+                Err(err) => return Err(format!("invalid computed {} {:?} {}", column, src, err)),
+            }
+        }
+        Ok(Computed { fields: parsed })
+    }
+
+    /// every expression sees the flattened row, not the other computed columns
+    pub fn apply(&self, row: &mut Row) {
+        let values: Vec<_> = self
+            .fields
+            .iter()
+            .map(|(column, expr)| (column.to_owned(), expr.eval(row)))
+            .collect();
+        row.extend(values);
+    }
+}
+
+pub fn check_computed_cfg(conf: &serde_json::Value) -> Result<Computed, String> {
+    match serde_json::from_value::<ComputedConfig>(conf.clone()) {
+        Ok(v) => Computed::parse(&v.fields),
+        Err(err) => Err(format!("invalid config  {} error {:?}", conf, err)),
+    }
+}
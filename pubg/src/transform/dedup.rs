@@ -0,0 +1,551 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use async_trait::async_trait;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+
+use crate::core::{Ack, Msg, Offset, Row};
+use crate::metrics;
+
+use super::Transform;
+
+pub const DEDUP: &str = "dedup";
+
+static SAVES: AtomicU64 = AtomicU64::new(0);
+
+/// drops rows whose key fields were seen within the window, or whole msgs by g_id
+/// when asked for with by g_id. a dropped msg is acked
+pub struct DedupTransform {}
+#[async_trait]
+impl Transform for DedupTransform {
+    async fn to_transform(
+        &self,
+        task_id: String,
+        conf: serde_json::Value,
+        mut receive: mpsc::Receiver<Msg>,
+        sender: mpsc::Sender<Msg>,
+    ) {
+        let cfg = match check_dedup_cfg(&conf) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("[transform] dedup task_id {} {}", task_id, err);
+                return;
+            }
+        };
+        let mut dedup = Dedup::open(&task_id, &cfg);
+        // keys of acked msgs, the only ones saved so a crash never drops an unacked msg
+        let mut committed = Dedup::open(&task_id, &cfg);
+        // msgs sent on with an ack of this transform, by its offset
+        let mut pending: HashMap<i64, (Option<Ack>, Vec<Vec<u8>>)> = HashMap::new();
+        let (acks, mut acked) = mpsc::unbounded_channel::<Offset>();
+        let mut next = 0;
+        let mut persisted_at = now_ms();
+        loop {
+            tokio::select! {
+                Some(offset) = acked.recv() => {
+                    commit(&mut committed, &mut pending, offset.offset);
+                }
+                res = receive.recv() => {
+                    let mut msg = match res {
+                        Some(v) => v,
+                        None => break,
+                    };
+                    let now = now_ms();
+                    let keys = if cfg.by == Some(DedupBy::GId) {
+                        if dedup.seen(msg.g_id.as_bytes(), now) {
+                            metrics::record_deduped(&task_id, 1);
+                            msg.ack();
+                            continue;
+                        }
+                        vec![msg.g_id.as_bytes().to_vec()]
+                    } else if let Some(rows) = msg.rows.as_mut() {
+                        let before = rows.len();
+                        let keys = dedup.retain_rows(&cfg.keys, rows, now);
+                        if rows.len() < before {
+                            metrics::record_deduped(&task_id, (before - rows.len()) as u64);
+                        }
+                        keys
+                    } else {
+                        vec![]
+                    };
+                    next += 1;
+                    pending.insert(next, (msg.ack.take(), keys));
+                    msg.ack = Some(Ack::new(Offset::new(DEDUP.to_owned(), 0, next), acks.clone()));
+                    if sender.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            let now = now_ms();
+            if cfg.persist_secs > 0 && now - persisted_at >= cfg.persist_secs as i64 * 1000 {
+                committed.persist(&task_id).await;
+                persisted_at = now;
+            }
+        }
+        // msgs still on their way to the dsts may yet be acked
+        drop(acks);
+        while let Some(offset) = acked.recv().await {
+            commit(&mut committed, &mut pending, offset.offset);
+        }
+        committed.persist(&task_id).await;
+        info!("[transform] dedup task_id {} exit", task_id);
+    }
+
+    fn cfg(&self) -> serde_json::Value {
+        serde_json::json!(DedupConfig {
+            by: Some(DedupBy::GId),
+            ..Default::default()
+        })
+    }
+
+    fn transform_name(&self) -> String {
+        DEDUP.to_owned()
+    }
+
+    fn check_cfg(&self, conf: &serde_json::Value) -> Result<(), String> {
+        check_dedup_cfg(conf).map(|_| ())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreKind {
+    // exact keys, the least recently seen is evicted
+    #[default]
+    Lru,
+    // two rotating filters, a fixed memory cost with false positives
+    Bloom,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupBy {
+    // the kafka msg key, msgs sharing a key are duplicates. msgs without a key never are
+    GId,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DedupConfig {
+    // flattened fields of the key
+    #[serde(default)]
+    pub keys: Vec<String>,
+    // whole msgs instead of rows by keys
+    #[serde(default)]
+    pub by: Option<DedupBy>,
+    // a key seen within this many secs is a duplicate, 0 is no time window
+    #[serde(default)]
+    pub window_secs: u64,
+    // keys remembered, also the count window
+    #[serde(default = "default_capacity")]
+    pub capacity: usize,
+    #[serde(default)]
+    pub store: StoreKind,
+    #[serde(default = "default_false_positive_rate")]
+    pub false_positive_rate: f64,
+    // keys of acked msgs are saved here so a restart keeps the window. a msg
+    // still in flight at a crash is not saved, its redelivery goes through
+    #[serde(default)]
+    pub state_dir: String,
+    #[serde(default = "default_persist_secs")]
+    pub persist_secs: u64,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig {
+            keys: vec![],
+            by: None,
+            window_secs: 0,
+            capacity: default_capacity(),
+            store: StoreKind::default(),
+            false_positive_rate: default_false_positive_rate(),
+            state_dir: String::new(),
+            persist_secs: default_persist_secs(),
+        }
+    }
+}
+
+fn default_capacity() -> usize {
+    100_000
+}
+
+fn default_false_positive_rate() -> f64 {
+    0.001
+}
+
+fn default_persist_secs() -> u64 {
+    10
+}
+
+pub fn check_dedup_cfg(conf: &serde_json::Value) -> Result<DedupConfig, String> {
+    let cfg = match serde_json::from_value::<DedupConfig>(conf.clone()) {
+        Ok(v) => v,
+        Err(err) => return Err(format!("invalid config  {} error {:?}", conf, err)),
+    };
+    // g_id is the src msg key, so it is only a dedup key when asked for
+    if cfg.keys.is_empty() == cfg.by.is_none() {
+        return Err("dedup needs either keys or by g_id".to_owned());
+    }
+    if cfg.capacity == 0 {
+        return Err("dedup capacity must be greater than 0".to_owned());
+    }
+    if !(cfg.false_positive_rate > 0.0 && cfg.false_positive_rate < 1.0) {
+        return Err(format!(
+            "dedup false_positive_rate {} is not between 0 and 1",
+            cfg.false_positive_rate
+        ));
+    }
+    Ok(cfg)
+}
+
+// a msg was acked by its dsts, its keys can be saved and its src ack sent
+fn commit(
+    committed: &mut Dedup,
+    pending: &mut HashMap<i64, (Option<Ack>, Vec<Vec<u8>>)>,
+    seq: i64,
+) {
+    let (ack, keys) = match pending.remove(&seq) {
+        Some(v) => v,
+        None => return,
+    };
+    let now = now_ms();
+    keys.iter().for_each(|key| {
+        committed.seen(key, now);
+    });
+    if let Some(ack) = ack {
+        ack.ack();
+    }
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+// two independent halves of the key digest
+fn key_hash(key: &[u8]) -> (u64, u64) {
+    let digest = Sha256::digest(key);
+    let h1 = u64::from_le_bytes(digest[..8].try_into().unwrap());
+    let h2 = u64::from_le_bytes(digest[8..16].try_into().unwrap());
+    (h1, h2)
+}
+
+/// json of the key field values, none when a field is missing
+pub fn row_key(keys: &[String], row: &Row) -> Option<Vec<u8>> {
+    let values: Option<Vec<_>> = keys.iter().map(|k| row.get(k)).collect();
+    serde_json::to_vec(&values?).ok()
+}
+
+/// keys seen by a dedup transform
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Dedup {
+    // file the keys are saved to, none when not persisted
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    store: Store,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+enum Store {
+    Lru(Lru),
+    Bloom(Bloom),
+}
+
+impl Dedup {
+    pub fn new(cfg: &DedupConfig) -> Dedup {
+        let window_ms = cfg.window_secs as i64 * 1000;
+        let store = match cfg.store {
+            StoreKind::Lru => Store::Lru(Lru::new(cfg.capacity, window_ms)),
+            StoreKind::Bloom => {
+                Store::Bloom(Bloom::new(cfg.capacity, cfg.false_positive_rate, window_ms))
+            }
+        };
+        Dedup { path: None, store }
+    }
+
+    /// keys saved by an earlier run with the same cfg, or a new window
+    pub fn open(task_id: &String, cfg: &DedupConfig) -> Dedup {
+        if cfg.state_dir.is_empty() {
+            return Dedup::new(cfg);
+        }
+        // the cfg is in the name so a changed cfg starts over,
+        // dsts of a task with the same cfg see the same msgs and share the file
+        let cfg_id = key_hash(serde_json::json!(cfg).to_string().as_bytes()).0;
+        let path =
+            PathBuf::from(&cfg.state_dir).join(format!("{}.{:016x}.dedup.json", task_id, cfg_id));
+        let mut dedup = match std::fs::read(&path) {
+            Ok(v) => match serde_json::from_slice::<Dedup>(&v) {
+                Ok(v) => {
+                    info!("[transform] dedup task_id {} loaded {:?}", task_id, path);
+                    v
+                }
+                Err(err) => {
+                    error!(
+                        "[transform] dedup task_id {} load {:?} error {}",
+                        task_id, path, err
+                    );
+                    Dedup::new(cfg)
+                }
+            },
+            Err(_) => Dedup::new(cfg),
+        };
+        dedup.path = Some(path);
+        dedup
+    }
+
+    /// whether key was seen within the window, it is seen now either way
+    pub fn seen(&mut self, key: &[u8], now: i64) -> bool {
+        let (h1, h2) = key_hash(key);
+        match &mut self.store {
+            Store::Lru(v) => v.seen(h1, now),
+            Store::Bloom(v) => v.seen(h1, h2, now),
+        }
+    }
+
+    /// drop rows with a seen key, a row missing a key field is kept.
+    /// gives back the keys of the kept rows
+    pub fn retain_rows(&mut self, keys: &[String], rows: &mut Vec<Row>, now: i64) -> Vec<Vec<u8>> {
+        let mut kept = vec![];
+        rows.retain(|row| match row_key(keys, row) {
+            Some(key) if self.seen(&key, now) => false,
+            Some(key) => {
+                kept.push(key);
+                true
+            }
+            None => true,
+        });
+        kept
+    }
+
+    /// save a snapshot of the keys, written on the blocking pool as it may be megabytes
+    pub async fn persist(&self, task_id: &String) {
+        let path = match &self.path {
+            Some(v) => v.clone(),
+            None => return,
+        };
+        let data = serde_json::json!(self).to_string();
+        let task_id = task_id.to_owned();
+        let _ = tokio::task::spawn_blocking(move || {
+            // dsts sharing the file must not share the tmp file
+            let tmp = path.with_extension(format!("{}.tmp", SAVES.fetch_add(1, Ordering::Relaxed)));
+            let res = std::fs::create_dir_all(path.parent().unwrap_or(&path))
+                .and_then(|_| std::fs::write(&tmp, data))
+                .and_then(|_| std::fs::rename(&tmp, &path));
+            if let Err(err) = res {
+                error!(
+                    "[transform] dedup task_id {} save {:?} error {}",
+                    task_id, path, err
+                );
+            }
+        })
+        .await;
+    }
+}
+
+// exact keys in the order they were last seen
+#[derive(Debug, Deserialize, Serialize)]
+struct Lru {
+    capacity: usize,
+    window_ms: i64,
+    // key to the generation and time it was last seen
+    seen: HashMap<u64, (u64, i64)>,
+    // keys with the generation they were seen at, stale ones are skipped
+    order: VecDeque<(u64, u64)>,
+    generation: u64,
+}
+
+impl Lru {
+    fn new(capacity: usize, window_ms: i64) -> Lru {
+        Lru {
+            capacity,
+            window_ms,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+            generation: 0,
+        }
+    }
+
+    fn seen(&mut self, key: u64, now: i64) -> bool {
+        self.expire(now);
+        self.generation += 1;
+        let dup = self.seen.insert(key, (self.generation, now)).is_some();
+        self.order.push_back((key, self.generation));
+        while self.seen.len() > self.capacity {
+            if let Some((key, generation)) = self.order.pop_front() {
+                if self.seen.get(&key).map(|v| v.0) == Some(generation) {
+                    self.seen.remove(&key);
+                }
+            }
+        }
+        // repeated keys leave stale entries behind
+        if self.order.len() > self.capacity * 2 {
+            let seen = &self.seen;
+            self.order
+                .retain(|(key, generation)| seen.get(key).map(|v| v.0) == Some(*generation));
+        }
+        dup
+    }
+
+    fn expire(&mut self, now: i64) {
+        if self.window_ms == 0 {
+            return;
+        }
+        while let Some(&(key, generation)) = self.order.front() {
+            if let Some(&(current, at)) = self.seen.get(&key) {
+                if current == generation {
+                    if now - at < self.window_ms {
+                        break;
+                    }
+                    self.seen.remove(&key);
+                }
+            }
+            self.order.pop_front();
+        }
+    }
+}
+
+// a key is looked up in both filters and set in the current one, which
+// becomes the previous one once it holds capacity keys or the window passed.
+// so a key is remembered for one to two windows
+#[derive(Debug, Deserialize, Serialize)]
+struct Bloom {
+    capacity: usize,
+    window_ms: i64,
+    bits: u64,
+    hashes: u64,
+    current: Vec<u64>,
+    previous: Vec<u64>,
+    inserted: usize,
+    started_at: i64,
+}
+
+impl Bloom {
+    fn new(capacity: usize, false_positive_rate: f64, window_ms: i64) -> Bloom {
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-(capacity as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil() as u64;
+        let bits = bits.max(64);
+        let hashes = ((bits as f64 / capacity as f64) * ln2).round().max(1.0) as u64;
+        let words = bits.div_ceil(64) as usize;
+        Bloom {
+            capacity,
+            window_ms,
+            bits,
+            hashes,
+            current: vec![0; words],
+            previous: vec![0; words],
+            inserted: 0,
+            started_at: 0,
+        }
+    }
+
+    fn index(&self, h1: u64, h2: u64, i: u64) -> (usize, u64) {
+        let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.bits;
+        ((bit / 64) as usize, 1 << (bit % 64))
+    }
+
+    fn contains(&self, filter: &[u64], h1: u64, h2: u64) -> bool {
+        (0..self.hashes).all(|i| {
+            let (word, mask) = self.index(h1, h2, i);
+            filter[word] & mask != 0
+        })
+    }
+
+    fn seen(&mut self, h1: u64, h2: u64, now: i64) -> bool {
+        let full = self.inserted >= self.capacity;
+        let passed = self.window_ms > 0 && now - self.started_at >= self.window_ms;
+        if full || passed {
+            let words = self.current.len();
+            self.previous = std::mem::replace(&mut self.current, vec![0; words]);
+            self.inserted = 0;
+            self.started_at = now;
+        }
+        if self.contains(&self.current, h1, h2) {
+            return true;
+        }
+        let dup = self.contains(&self.previous, h1, h2);
+        for i in 0..self.hashes {
+            let (word, mask) = self.index(h1, h2, i);
+            self.current[word] |= mask;
+        }
+        self.inserted += 1;
+        dup
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup_windows() {
+        let cfg = DedupConfig {
+            window_secs: 10,
+            capacity: 2,
+            ..Default::default()
+        };
+        let mut dedup = Dedup::new(&cfg);
+        assert!(!dedup.seen(b"a", 0));
+        assert!(dedup.seen(b"a", 5_000));
+        // seen again at 5s, so still in the window at 14s
+        assert!(dedup.seen(b"a", 14_000));
+        assert!(!dedup.seen(b"a", 30_000));
+        // b and c push a out of the count window
+        assert!(!dedup.seen(b"b", 30_001));
+        assert!(!dedup.seen(b"c", 30_002));
+        assert!(!dedup.seen(b"a", 30_003));
+
+        let cfg = DedupConfig {
+            store: StoreKind::Bloom,
+            capacity: 100,
+            ..Default::default()
+        };
+        let mut dedup = Dedup::new(&cfg);
+        let keys = vec!["id".to_owned()];
+        let mut rows: Vec<Row> = (0..150)
+            .map(|i| serde_json::from_value(serde_json::json!({"id": i % 50})).unwrap())
+            .collect();
+        rows.push(Row::new());
+        assert_eq!(dedup.retain_rows(&keys, &mut rows, 0).len(), 50);
+        assert_eq!(rows.len(), 51);
+
+        // msgs are only deduped by g_id when asked for
+        assert!(check_dedup_cfg(&serde_json::json!({})).is_err());
+        assert!(check_dedup_cfg(&serde_json::json!({"by": "g_id", "keys": ["id"]})).is_err());
+        assert!(check_dedup_cfg(&serde_json::json!({"by": "g_id"})).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_dedup_persist() {
+        let dir = std::env::temp_dir().join(format!("varbit-dedup-{}", std::process::id()));
+        let cfg = DedupConfig {
+            state_dir: dir.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        let task_id = "dedup-task".to_owned();
+        let mut dedup = Dedup::open(&task_id, &cfg);
+        assert!(!dedup.seen(b"g1", 0));
+        dedup.persist(&task_id).await;
+
+        let mut dedup = Dedup::open(&task_id, &cfg);
+        assert!(dedup.seen(b"g1", 1));
+        // only keys of acked msgs are saved
+        let (acks, mut acked) = mpsc::unbounded_channel();
+        let mut pending = HashMap::new();
+        let ack = Ack::new(Offset::new("t".to_owned(), 0, 9), acks);
+        pending.insert(1, (Some(ack), vec![b"g2".to_vec()]));
+        pending.insert(2, (None, vec![b"g3".to_vec()]));
+        commit(&mut dedup, &mut pending, 1);
+        assert_eq!(acked.try_recv().unwrap().offset, 9);
+        dedup.persist(&task_id).await;
+        let mut dedup = Dedup::open(&task_id, &cfg);
+        assert!(dedup.seen(b"g2", 2));
+        assert!(!dedup.seen(b"g3", 2));
+        // another cfg starts a new window
+        let cfg = DedupConfig { capacity: 5, ..cfg };
+        assert!(!Dedup::open(&task_id, &cfg).seen(b"g1", 2));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
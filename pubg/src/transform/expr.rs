@@ -0,0 +1,787 @@
+/// small expression language over flattened rows, e.g.
+/// `event_type == "purchase" && amount > 0`, `country in ["us", "ca"]`,
+/// `email =~ "@example\.com$"`, `coupon is not null`, `exists(user_id)`,
+/// `price * qty`, `lower(concat(first, " ", last))`, `coalesce(nick, name)`.
+/// fields are column names, `quoted` with backticks when they are not identifiers
+use std::fmt::Write;
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use md5::Md5;
+use regex::Regex;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::core::Row;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(serde_json::Number),
+    Str(String),
+    Ident(String),
+    Field(String),
+    Op(&'static str),
+}
+
+// longest first so <= is not read as <
+const OPS: [&str; 21] = [
+    "&&", "||", "==", "!=", "<=", ">=", "=~", "!~", "<", ">", "!", "(", ")", "[", "]", ",", "+",
+    "-", "*", "/", "%",
+];
+
+// functions with their least and most args
+const FUNCS: [(&str, usize, usize); 17] = [
+    ("concat", 1, usize::MAX),
+    ("coalesce", 1, usize::MAX),
+    ("lower", 1, 1),
+    ("upper", 1, 1),
+    ("trim", 1, 1),
+    ("length", 1, 1),
+    ("substr", 2, 3),
+    ("split", 2, 3),
+    ("replace", 3, 3),
+    ("sha256", 1, 1),
+    ("md5", 1, 1),
+    ("parse_date", 2, 2),
+    ("format_date", 2, 2),
+    ("now", 0, 0),
+    ("to_string", 1, 1),
+    ("to_number", 1, 1),
+    ("if", 3, 3),
+];
+
+fn tokenize(src: &str) -> Result<Vec<(usize, Token)>, String> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    'next: while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let at = i;
+        if c == '"' || c == '\'' || c == '`' {
+            let mut out = String::new();
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                if chars[i] == '\\' && i + 1 < chars.len() {
+                    i += 1;
+                    match chars[i] {
+                        'n' => out.push('\n'),
+                        't' => out.push('\t'),
+                        '\\' => out.push('\\'),
+                        q if q == c => out.push(q),
+                        // kept for regex like \. and \d
+                        other => {
+                            out.push('\\');
+                            out.push(other);
+                        }
+                    }
+                } else {
+                    out.push(chars[i]);
+                }
+                i += 1;
+            }
+            if i >= chars.len() {
+                return Err(format!("unterminated quote at {}", at));
+            }
+            i += 1;
+            tokens.push((
+                at,
+                if c == '`' {
+                    Token::Field(out)
+                } else {
+                    Token::Str(out)
+                },
+            ));
+            continue;
+        }
+        if c.is_ascii_digit() {
+            while i < chars.len()
+                && (chars[i].is_ascii_digit()
+                    || chars[i] == '.'
+                    || chars[i] == 'e'
+                    || chars[i] == 'E'
+                    || ((chars[i] == '-' || chars[i] == '+')
+                        && (chars[i - 1] == 'e' || chars[i - 1] == 'E')))
+            {
+                i += 1;
+            }
+            let text: String = chars[at..i].iter().collect();
+            let num = match text.parse::<i64>() {
+                Ok(v) => serde_json::Number::from(v),
+                Err(_) => match text
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(serde_json::Number::from_f64)
+                {
+                    Some(v) => v,
+                    None => return Err(format!("invalid number {} at {}", text, at)),
+                },
+            };
+            tokens.push((at, Token::Num(num)));
+            continue;
+        }
+        if c.is_alphabetic() || c == '_' {
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+            {
+                i += 1;
+            }
+            tokens.push((at, Token::Ident(chars[at..i].iter().collect())));
+            continue;
+        }
+        for op in OPS {
+            let len = op.chars().count();
+            if i + len <= chars.len() && chars[i..i + len].iter().copied().eq(op.chars()) {
+                tokens.push((at, Token::Op(op)));
+                i += len;
+                continue 'next;
+            }
+        }
+        return Err(format!("unexpected {:?} at {}", c, at));
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug)]
+enum Node {
+    Lit(Value),
+    Field(String),
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Cmp(CmpOp, Box<Node>, Box<Node>),
+    // negated when the bool is false
+    In(Box<Node>, Vec<Node>, bool),
+    Match(Box<Node>, Regex, bool),
+    IsNull(Box<Node>, bool),
+    Exists(String),
+    Neg(Box<Node>),
+    Arith(ArithOp, Box<Node>, Box<Node>),
+    Call(&'static str, Vec<Node>),
+}
+
+/// a parsed expression, checked once when the task config is validated
+#[derive(Debug)]
+pub struct Expr {
+    src: String,
+    node: Node,
+}
+
+impl Expr {
+    pub fn parse(src: &str) -> Result<Expr, String> {
+        let tokens = tokenize(src)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let node = parser.or()?;
+        if let Some((at, token)) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected {:?} at {} in {:?}", token, at, src));
+        }
+        Ok(Expr {
+            src: src.to_owned(),
+            node,
+        })
+    }
+
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+
+    /// value of the expression on row, a missing field is null
+    pub fn eval(&self, row: &Row) -> Value {
+        eval(&self.node, row)
+    }
+
+    /// whether row passes the expression
+    pub fn matches(&self, row: &Row) -> bool {
+        truthy(&self.eval(row))
+    }
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(v)) if *v == op) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn eat_word(&mut self, word: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Ident(v)) if v == word) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<(), String> {
+        if self.eat_op(op) {
+            return Ok(());
+        }
+        Err(self.error(&format!("expected {}", op)))
+    }
+
+    fn error(&self, msg: &str) -> String {
+        match self.tokens.get(self.pos) {
+            Some((at, token)) => format!("{} but got {:?} at {}", msg, token, at),
+            None => format!("{} but got the end", msg),
+        }
+    }
+
+    fn or(&mut self) -> Result<Node, String> {
+        let mut left = self.and()?;
+        while self.eat_op("||") {
+            left = Node::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        let mut left = self.not()?;
+        while self.eat_op("&&") {
+            left = Node::And(Box::new(left), Box::new(self.not()?));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Node, String> {
+        if self.eat_op("!") {
+            return Ok(Node::Not(Box::new(self.not()?)));
+        }
+        self.cmp()
+    }
+
+    fn cmp(&mut self) -> Result<Node, String> {
+        let left = self.add()?;
+        for (op, cmp) in [
+            ("==", CmpOp::Eq),
+            ("!=", CmpOp::Ne),
+            ("<=", CmpOp::Le),
+            (">=", CmpOp::Ge),
+            ("<", CmpOp::Lt),
+            (">", CmpOp::Gt),
+        ] {
+            if self.eat_op(op) {
+                return Ok(Node::Cmp(cmp, Box::new(left), Box::new(self.add()?)));
+            }
+        }
+        for (op, positive) in [("=~", true), ("!~", false)] {
+            if self.eat_op(op) {
+                let pattern = match self.tokens.get(self.pos) {
+                    Some((_, Token::Str(v))) => v.to_owned(),
+                    _ => return Err(self.error("expected a regex string")),
+                };
+                self.pos += 1;
+                let re = match Regex::new(&pattern) {
+                    Ok(v) => v,
+                    Err(err) => return Err(format!("invalid regex {:?} {}", pattern, err)),
+                };
+                return Ok(Node::Match(Box::new(left), re, positive));
+            }
+        }
+        if self.eat_word("in") {
+            return Ok(Node::In(Box::new(left), self.list()?, true));
+        }
+        if self.eat_word("not") {
+            if !self.eat_word("in") {
+                return Err(self.error("expected in"));
+            }
+            return Ok(Node::In(Box::new(left), self.list()?, false));
+        }
+        if self.eat_word("is") {
+            let positive = !self.eat_word("not");
+            if !self.eat_word("null") {
+                return Err(self.error("expected null"));
+            }
+            return Ok(Node::IsNull(Box::new(left), positive));
+        }
+        Ok(left)
+    }
+
+    fn add(&mut self) -> Result<Node, String> {
+        let mut left = self.mul()?;
+        loop {
+            let op = if self.eat_op("+") {
+                ArithOp::Add
+            } else if self.eat_op("-") {
+                ArithOp::Sub
+            } else {
+                return Ok(left);
+            };
+            left = Node::Arith(op, Box::new(left), Box::new(self.mul()?));
+        }
+    }
+
+    fn mul(&mut self) -> Result<Node, String> {
+        let mut left = self.unary()?;
+        loop {
+            let op = if self.eat_op("*") {
+                ArithOp::Mul
+            } else if self.eat_op("/") {
+                ArithOp::Div
+            } else if self.eat_op("%") {
+                ArithOp::Rem
+            } else {
+                return Ok(left);
+            };
+            left = Node::Arith(op, Box::new(left), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.eat_op("-") {
+            return Ok(Node::Neg(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn args(&mut self) -> Result<Vec<Node>, String> {
+        let mut args = vec![];
+        if self.eat_op(")") {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.or()?);
+            if self.eat_op(")") {
+                return Ok(args);
+            }
+            self.expect_op(",")?;
+        }
+    }
+
+    fn list(&mut self) -> Result<Vec<Node>, String> {
+        self.expect_op("[")?;
+        let mut items = vec![];
+        if self.eat_op("]") {
+            return Ok(items);
+        }
+        loop {
+            items.push(self.add()?);
+            if self.eat_op("]") {
+                return Ok(items);
+            }
+            self.expect_op(",")?;
+        }
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        let token = match self.tokens.get(self.pos) {
+            Some((_, v)) => v.clone(),
+            None => return Err(self.error("expected a value")),
+        };
+        self.pos += 1;
+        match token {
+            Token::Num(v) => Ok(Node::Lit(Value::Number(v))),
+            Token::Str(v) => Ok(Node::Lit(Value::String(v))),
+            Token::Field(v) => Ok(Node::Field(v)),
+            Token::Op("(") => {
+                let node = self.or()?;
+                self.expect_op(")")?;
+                Ok(node)
+            }
+            Token::Ident(v) => match v.as_str() {
+                "true" => Ok(Node::Lit(Value::Bool(true))),
+                "false" => Ok(Node::Lit(Value::Bool(false))),
+                "null" => Ok(Node::Lit(Value::Null)),
+                "exists" => {
+                    self.expect_op("(")?;
+                    let field = match self.tokens.get(self.pos) {
+                        Some((_, Token::Ident(v))) | Some((_, Token::Field(v))) => v.to_owned(),
+                        _ => return Err(self.error("expected a field")),
+                    };
+                    self.pos += 1;
+                    self.expect_op(")")?;
+                    Ok(Node::Exists(field))
+                }
+                _ if self.eat_op("(") => {
+                    let (name, least, most) = match FUNCS.iter().find(|(name, _, _)| *name == v) {
+                        Some(f) => *f,
+                        None => return Err(format!("unknown function {}", v)),
+                    };
+                    let args = self.args()?;
+                    if args.len() < least || args.len() > most {
+                        return Err(format!("{} got {} args", name, args.len()));
+                    }
+                    if let (true, Some(Node::Lit(Value::String(fmt)))) =
+                        (name == "format_date", args.get(1))
+                    {
+                        if !valid_date_format(fmt) {
+                            return Err(format!("invalid date format {:?}", fmt));
+                        }
+                    }
+                    Ok(Node::Call(name, args))
+                }
+                _ => Ok(Node::Field(v)),
+            },
+            _ => {
+                self.pos -= 1;
+                Err(self.error("expected a value"))
+            }
+        }
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(v) => *v,
+        Value::Number(v) => v.as_f64().unwrap_or_default() != 0.0,
+        Value::String(v) => !v.is_empty(),
+        Value::Array(v) => !v.is_empty(),
+        Value::Object(v) => !v.is_empty(),
+    }
+}
+
+// numbers compare by value, so 1 == 1.0
+fn equal(left: &Value, right: &Value) -> bool {
+    match (left.as_f64(), right.as_f64()) {
+        (Some(l), Some(r)) => l == r,
+        _ => left == right,
+    }
+}
+
+fn compare(op: CmpOp, left: &Value, right: &Value) -> bool {
+    let ord = match (left, right) {
+        (Value::Number(l), Value::Number(r)) => l.as_f64().partial_cmp(&r.as_f64()),
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        _ => None,
+    };
+    match op {
+        CmpOp::Eq => equal(left, right),
+        CmpOp::Ne => !equal(left, right),
+        // values of different types are not ordered
+        CmpOp::Lt => ord.is_some_and(|o| o.is_lt()),
+        CmpOp::Le => ord.is_some_and(|o| o.is_le()),
+        CmpOp::Gt => ord.is_some_and(|o| o.is_gt()),
+        CmpOp::Ge => ord.is_some_and(|o| o.is_ge()),
+    }
+}
+
+// text of a value, strings without quotes
+fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(v) => Some(v.to_owned()),
+        other => Some(other.to_string()),
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(v) => v.as_f64(),
+        Value::String(v) => v.trim().parse::<f64>().ok(),
+        Value::Bool(v) => Some(*v as i64 as f64),
+        _ => None,
+    }
+}
+
+fn float(v: f64) -> Value {
+    serde_json::Number::from_f64(v).map_or(Value::Null, Value::Number)
+}
+
+// ints stay ints unless they overflow, a string on either side of + concatenates
+fn arith(op: ArithOp, left: &Value, right: &Value) -> Value {
+    if op == ArithOp::Add && (left.is_string() || right.is_string()) {
+        return match (text(left), text(right)) {
+            (Some(l), Some(r)) => Value::String(l + r.as_str()),
+            _ => Value::Null,
+        };
+    }
+    if let (Some(l), Some(r)) = (left.as_i64(), right.as_i64()) {
+        let res = match op {
+            ArithOp::Add => l.checked_add(r),
+            ArithOp::Sub => l.checked_sub(r),
+            ArithOp::Mul => l.checked_mul(r),
+            ArithOp::Div if r != 0 && l % r == 0 => l.checked_div(r),
+            ArithOp::Div => None,
+            ArithOp::Rem => l.checked_rem(r),
+        };
+        if let Some(v) = res {
+            return Value::from(v);
+        }
+        if r == 0 {
+            return Value::Null;
+        }
+    }
+    let (l, r) = match (left.as_f64(), right.as_f64()) {
+        (Some(l), Some(r)) => (l, r),
+        _ => return Value::Null,
+    };
+    match op {
+        ArithOp::Add => float(l + r),
+        ArithOp::Sub => float(l - r),
+        ArithOp::Mul => float(l * r),
+        ArithOp::Div if r != 0.0 => float(l / r),
+        ArithOp::Rem if r != 0.0 => float(l % r),
+        _ => Value::Null,
+    }
+}
+
+// chrono panics when it displays a date in an invalid format
+fn valid_date_format(fmt: &str) -> bool {
+    StrftimeItems::new(fmt).all(|item| item != Item::Error)
+}
+
+// utc string of unix ms in fmt, none for an invalid fmt
+fn format_date(ms: i64, fmt: &str) -> Option<String> {
+    if !valid_date_format(fmt) {
+        return None;
+    }
+    let date = Utc.timestamp_millis_opt(ms).single()?;
+    let mut out = String::new();
+    write!(out, "{}", date.format(fmt)).ok()?;
+    Some(out)
+}
+
+// unix ms of a date string in fmt, dates without a zone are utc
+fn parse_date(value: &str, fmt: &str) -> Option<i64> {
+    if let Ok(v) = DateTime::parse_from_str(value, fmt) {
+        return Some(v.timestamp_millis());
+    }
+    if let Ok(v) = NaiveDateTime::parse_from_str(value, fmt) {
+        return Some(v.and_utc().timestamp_millis());
+    }
+    NaiveDate::parse_from_str(value, fmt)
+        .ok()
+        .and_then(|v| v.and_hms_opt(0, 0, 0))
+        .map(|v| v.and_utc().timestamp_millis())
+}
+
+fn call(name: &str, args: &[Value]) -> Value {
+    let arg = |i: usize| args.get(i).cloned().unwrap_or_default();
+    let str_arg = |i: usize| text(&arg(i));
+    match name {
+        "concat" => Value::String(args.iter().filter_map(text).collect()),
+        "coalesce" => args
+            .iter()
+            .find(|v| !v.is_null())
+            .cloned()
+            .unwrap_or_default(),
+        "lower" => str_arg(0).map_or(Value::Null, |v| Value::String(v.to_lowercase())),
+        "upper" => str_arg(0).map_or(Value::Null, |v| Value::String(v.to_uppercase())),
+        "trim" => str_arg(0).map_or(Value::Null, |v| Value::String(v.trim().to_owned())),
+        "length" => match arg(0) {
+            Value::Array(v) => Value::from(v.len()),
+            v => text(&v).map_or(Value::Null, |v| Value::from(v.chars().count())),
+        },
+        // chars from start, to the end without len
+        "substr" => {
+            let (s, start) = match (str_arg(0), arg(1).as_u64()) {
+                (Some(s), Some(start)) => (s, start as usize),
+                _ => return Value::Null,
+            };
+            let len = arg(2).as_u64().map_or(usize::MAX, |v| v as usize);
+            Value::String(s.chars().skip(start).take(len).collect())
+        }
+        // a list, or the part at index
+        "split" => {
+            let (s, sep) = match (str_arg(0), str_arg(1)) {
+                (Some(s), Some(sep)) => (s, sep),
+                _ => return Value::Null,
+            };
+            let parts: Vec<Value> = s.split(sep.as_str()).map(Value::from).collect();
+            match args.get(2) {
+                None => Value::Array(parts),
+                Some(i) => i
+                    .as_u64()
+                    .and_then(|i| parts.get(i as usize).cloned())
+                    .unwrap_or_default(),
+            }
+        }
+        "replace" => match (str_arg(0), str_arg(1), str_arg(2)) {
+            (Some(s), Some(from), Some(to)) => Value::String(s.replace(&from, &to)),
+            _ => Value::Null,
+        },
+        "sha256" => str_arg(0).map_or(Value::Null, |v| {
+            Value::String(format!("{:x}", Sha256::digest(v.as_bytes())))
+        }),
+        "md5" => str_arg(0).map_or(Value::Null, |v| {
+            Value::String(format!("{:x}", Md5::digest(v.as_bytes())))
+        }),
+        "parse_date" => match (str_arg(0), str_arg(1)) {
+            (Some(s), Some(fmt)) => parse_date(&s, &fmt).map_or(Value::Null, Value::from),
+            _ => Value::Null,
+        },
+        // unix ms to a utc string
+        "format_date" => match (number(&arg(0)), str_arg(1)) {
+            (Some(ms), Some(fmt)) => {
+                format_date(ms as i64, &fmt).map_or(Value::Null, Value::String)
+            }
+            _ => Value::Null,
+        },
+        "now" => Value::from(Utc::now().timestamp_millis()),
+        "to_string" => str_arg(0).map_or(Value::Null, Value::String),
+        "to_number" => match arg(0) {
+            Value::Number(v) => Value::Number(v),
+            Value::String(v) => match v.trim().parse::<i64>() {
+                Ok(i) => Value::from(i),
+                Err(_) => number(&Value::String(v)).map_or(Value::Null, float),
+            },
+            v => number(&v).map_or(Value::Null, float),
+        },
+        "if" => {
+            if truthy(&arg(0)) {
+                arg(1)
+            } else {
+                arg(2)
+            }
+        }
+        _ => Value::Null,
+    }
+}
+
+fn eval(node: &Node, row: &Row) -> Value {
+    match node {
+        Node::Lit(v) => v.clone(),
+        Node::Field(name) => row.get(name).cloned().unwrap_or_default(),
+        Node::Not(v) => Value::Bool(!truthy(&eval(v, row))),
+        Node::And(l, r) => Value::Bool(truthy(&eval(l, row)) && truthy(&eval(r, row))),
+        Node::Or(l, r) => Value::Bool(truthy(&eval(l, row)) || truthy(&eval(r, row))),
+        Node::Cmp(op, l, r) => Value::Bool(compare(*op, &eval(l, row), &eval(r, row))),
+        Node::In(v, list, positive) => {
+            let value = eval(v, row);
+            let found = list.iter().any(|item| equal(&value, &eval(item, row)));
+            Value::Bool(found == *positive)
+        }
+        Node::Match(v, re, positive) => {
+            let found = match eval(v, row) {
+                Value::String(s) => re.is_match(&s),
+                Value::Null => false,
+                other => re.is_match(&other.to_string()),
+            };
+            Value::Bool(found == *positive)
+        }
+        Node::IsNull(v, positive) => Value::Bool(eval(v, row).is_null() == *positive),
+        Node::Exists(name) => Value::Bool(row.contains_key(name)),
+        Node::Neg(v) => arith(ArithOp::Sub, &Value::from(0), &eval(v, row)),
+        Node::Arith(op, l, r) => arith(*op, &eval(l, row), &eval(r, row)),
+        Node::Call(name, args) => {
+            let args: Vec<Value> = args.iter().map(|v| eval(v, row)).collect();
+            call(name, &args)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(value: Value) -> Row {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_filter_expr() {
+        let r = row(serde_json::json!({
+            "event_type": "purchase",
+            "amount": 12.5,
+            "country": "ca",
+            "email": "a@example.com",
+            "coupon": null,
+        }));
+        for (src, want) in [
+            (r#"event_type == "purchase" && amount > 0"#, true),
+            ("amount >= 12.5 && amount < 13", true),
+            ("!(amount > 0) || country == 'us'", false),
+            (r#"country in ["us", "ca"]"#, true),
+            ("country not in ['us']", true),
+            (r#"email =~ "@example\.com$""#, true),
+            (r#"email !~ "^a@""#, false),
+            ("coupon is null && amount is not null", true),
+            ("missing is null", true),
+            ("exists(coupon) && !exists(missing)", true),
+            ("`event_type` != 'refund'", true),
+            ("amount > 'x'", false),
+        ] {
+            assert_eq!(Expr::parse(src).unwrap().matches(&r), want, "{}", src);
+        }
+        for src in ["amount >", "a == 1 b", "a =~ '('", "exists(1)", "'open"] {
+            assert!(Expr::parse(src).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_computed_expr() {
+        let r = row(serde_json::json!({
+            "first": "Ada",
+            "last": "Lovelace",
+            "price": 2.5,
+            "qty": 4,
+            "path": "a/b/c",
+            "nick": null,
+            "ts": "2024-03-01 12:30:00",
+        }));
+        for (src, want) in [
+            ("price * qty", serde_json::json!(10.0)),
+            ("qty * 2 + 1", serde_json::json!(9)),
+            ("qty / 3", serde_json::json!(4.0 / 3.0)),
+            ("-qty % 3", serde_json::json!(-1)),
+            ("qty / 0", Value::Null),
+            (
+                "lower(concat(first, ' ', last))",
+                serde_json::json!("ada lovelace"),
+            ),
+            ("first + '-' + qty", serde_json::json!("Ada-4")),
+            ("upper(substr(last, 0, 4))", serde_json::json!("LOVE")),
+            ("split(path, '/', 1)", serde_json::json!("b")),
+            ("split(path, '/')", serde_json::json!(["a", "b", "c"])),
+            ("coalesce(nick, missing, first)", serde_json::json!("Ada")),
+            (
+                "md5('abc')",
+                serde_json::json!("900150983cd24fb0d6963f7d28e17f72"),
+            ),
+            (
+                "sha256('abc')",
+                serde_json::json!(
+                    "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+                ),
+            ),
+            (
+                "parse_date(ts, '%Y-%m-%d %H:%M:%S')",
+                serde_json::json!(1709296200000i64),
+            ),
+            (
+                "format_date(parse_date(ts, '%Y-%m-%d %H:%M:%S'), '%Y/%m/%d')",
+                serde_json::json!("2024/03/01"),
+            ),
+            ("format_date(0, concat('%', 'Q'))", serde_json::Value::Null),
+            ("to_number('42') + 1", serde_json::json!(43)),
+            ("if(qty > 3, 'many', 'few')", serde_json::json!("many")),
+        ] {
+            assert_eq!(Expr::parse(src).unwrap().eval(&r), want, "{}", src);
+        }
+        assert!(Expr::parse("nope(1)").is_err());
+        assert!(Expr::parse("lower(a, b)").is_err());
+        assert!(Expr::parse("format_date(0, '%Q')").is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use async_trait::async_trait;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::core::Msg;
+use crate::metrics;
+
+use super::expr::Expr;
+use super::Transform;
+
+pub const FILTER: &str = "filter";
+// key of the filter expression in a tasking cfg
+pub const FILTER_KEY: &str = "filter";
+
+/// keeps the flattened rows an expression is true for, dropped rows are counted per task.
+/// a msg left without rows still goes on so it is acked by the dst
+pub struct FilterTransform {}
+#[async_trait]
+impl Transform for FilterTransform {
+    async fn to_transform(
+        &self,
+        task_id: String,
+        conf: serde_json::Value,
+        mut receive: mpsc::Receiver<Msg>,
+        sender: mpsc::Sender<Msg>,
+    ) {
+        let expr = match check_filter_cfg(&conf) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("[transform] filter task_id {} {}", task_id, err);
+                return;
+            }
+        };
+        while let Some(mut msg) = receive.recv().await {
+            if let Some(rows) = msg.rows.as_mut() {
+                let before = rows.len();
+                rows.retain(|row| expr.matches(row));
+                let dropped = before - rows.len();
+                if dropped > 0 {
+                    metrics::record_filtered(&task_id, dropped as u64);
+                }
+            }
+            if sender.send(msg).await.is_err() {
+                break;
+            }
+        }
+        info!("[transform] filter task_id {} exit", task_id);
+    }
+
+    fn cfg(&self) -> serde_json::Value {
+        serde_json::json!(FilterConfig::default())
+    }
+
+    fn transform_name(&self) -> String {
+        FILTER.to_owned()
+    }
+
+    fn check_cfg(&self, conf: &serde_json::Value) -> Result<(), String> {
+        check_filter_cfg(conf).map(|_| ())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+pub struct FilterConfig {
+    // like event_type == "purchase" && amount > 0
+    pub expr: String,
+}
+
+pub fn check_filter_cfg(conf: &serde_json::Value) -> Result<Expr, String> {
+    let cfg = match serde_json::from_value::<FilterConfig>(conf.clone()) {
+        Ok(v) => v,
+        Err(err) => return Err(format!("invalid config  {} error {:?}", conf, err)),
+    };
+    match Expr::parse(&cfg.expr) {
+        Ok(v) => Ok(v),
+        Err(err) => Err(format!("invalid filter {:?} {}", cfg.expr, err)),
+    }
+}
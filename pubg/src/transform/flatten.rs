@@ -0,0 +1,107 @@
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+use log::info;
+use service::task::json::{check_chrysaetos_bit_cfg, ChrysaetosBit, ChrysaetosBitConfig};
+use tokio::sync::mpsc;
+
+use crate::core::Msg;
+use crate::dead_letter::{self, DeadLetter, Stage};
+use crate::sink::TASKING_CFG_KEY;
+
+use super::Transform;
+
+pub const FLATTEN: &str = "flatten";
+
+/// flattens msg values into rows with the task tasking cfg.
+/// a value that is not an object or array goes to the dead letter dst
+pub struct FlattenTransform {}
+#[async_trait]
+impl Transform for FlattenTransform {
+    async fn to_transform(
+        &self,
+        task_id: String,
+        conf: serde_json::Value,
+        mut receive: mpsc::Receiver<Msg>,
+        sender: mpsc::Sender<Msg>,
+    ) {
+        let cry = chrysaetos(&task_id, &conf);
+        while let Some(mut msg) = receive.recv().await {
+            // flattened by an earlier transform
+            if msg.rows.is_some() {
+                if sender.send(msg).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            let rows = cry.parse(&msg.g_id, &msg.value);
+            if rows.is_empty() && !(msg.value.is_object() || msg.value.is_array()) {
+                let letter = DeadLetter::new(
+                    &task_id,
+                    &msg.g_id,
+                    Stage::Flatten,
+                    "not_object",
+                    "root value is not an object or array".to_owned(),
+                    msg.value.to_string().as_bytes(),
+                );
+                // without a dead letter dst the msg has no rows to deliver
+                if let Some(ack) = dead_letter::route(letter, msg.ack.take()).await {
+                    ack.ack();
+                }
+                continue;
+            }
+            msg.rows = Some(rows);
+            if sender.send(msg).await.is_err() {
+                break;
+            }
+        }
+        info!("[transform] flatten task_id {} exit", task_id);
+    }
+
+    fn cfg(&self) -> serde_json::Value {
+        serde_json::json!({"type": FLATTEN})
+    }
+
+    fn transform_name(&self) -> String {
+        FLATTEN.to_owned()
+    }
+
+    fn check_cfg(&self, conf: &serde_json::Value) -> Result<(), String> {
+        match conf.get(TASKING_CFG_KEY) {
+            Some(tasking_cfg) => check_chrysaetos_bit_cfg(tasking_cfg),
+            None => Ok(()),
+        }
+    }
+}
+
+/// flattener of a task, built from the tasking_cfg in conf.
+/// without tasking_cfg it keeps the old defaults: sep "_", max_depth 32, ignore "ts"
+pub fn chrysaetos(task_id: &String, conf: &serde_json::Value) -> ChrysaetosBit {
+    if let Some(tasking_cfg) = conf.get(TASKING_CFG_KEY) {
+        match serde_json::from_value::<ChrysaetosBitConfig>(tasking_cfg.clone()) {
+            Ok(cfg) => return ChrysaetosBit::from_cfg(task_id.to_owned(), &cfg),
+            Err(err) => log::warn!(
+                "task_id {} invalid tasking cfg {} error {:?}, use default",
+                task_id,
+                tasking_cfg,
+                err
+            ),
+        }
+    }
+    let mut ignore = HashSet::new();
+    ignore.insert("ts".to_owned());
+    ChrysaetosBit::new_cfg(
+        task_id.to_owned(),
+        "_".to_owned(),
+        32,
+        HashSet::new(),
+        ignore,
+    )
+}
+
+// msg with the rows the flatten transform sets, for dst tests
+#[cfg(test)]
+pub(crate) fn flatten_msg(cry: &ChrysaetosBit, mut msg: Msg) -> Msg {
+    msg.rows = Some(cry.parse(&msg.g_id, &msg.value));
+    msg
+}
@@ -0,0 +1,248 @@
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::core::Msg;
+use crate::sink::TASKING_CFG_KEY;
+use crate::TRANSFORM_PLUGIN;
+
+pub mod aggregate;
+pub mod computed;
+pub mod dedup;
+pub mod expr;
+pub mod filter;
+pub mod flatten;
+pub mod record;
+pub mod redact;
+pub mod script;
+pub mod wasm;
+
+// key of the transform chain in a tasking cfg
+pub const TRANSFORMS_KEY: &str = "transforms";
+// key of the transform name in a transform conf
+pub const TRANSFORM_TYPE_KEY: &str = "type";
+
+/// a stage between src and dst, msgs of the task come in on receive and go on through sender.
+/// a msg a transform drops must be acked so the src can commit past it
+#[async_trait]
+pub trait Transform: Send + Sync {
+    async fn to_transform(
+        &self,
+        task_id: String,
+        conf: serde_json::Value,
+        mut receive: mpsc::Receiver<Msg>,
+        sender: mpsc::Sender<Msg>,
+    );
+    fn cfg(&self) -> serde_json::Value;
+    fn transform_name(&self) -> String;
+    // check transform config of a task before saving it
+    fn check_cfg(&self, conf: &serde_json::Value) -> Result<(), String>;
+    // runs on msg values ahead of flatten instead of on rows
+    fn before_flatten(&self, _conf: &serde_json::Value) -> bool {
+        false
+    }
+}
+
+/// check transform name is registered and conf is valid for it
+pub fn check_transform_cfg(name: &String, conf: &serde_json::Value) -> Result<(), String> {
+    let transform = match TRANSFORM_PLUGIN.lock().unwrap().get(name.as_str()) {
+        Some(v) => v.clone(),
+        None => return Err(format!("not support transform type {}", name)),
+    };
+    transform.check_cfg(conf)
+}
+
+fn transform_before_flatten(name: &str, conf: &serde_json::Value) -> bool {
+    match TRANSFORM_PLUGIN.lock().unwrap().get(name) {
+        Some(v) => v.before_flatten(conf),
+        None => false,
+    }
+}
+
+/// transform names and confs of a tasking cfg in run order, each conf gets the tasking cfg.
+/// flatten runs after the transforms on msg values unless the chain places it
+pub fn task_transforms(
+    tasking_cfg: &serde_json::Value,
+) -> Result<Vec<(String, serde_json::Value)>, String> {
+    let list = match tasking_cfg.get(TRANSFORMS_KEY) {
+        None | Some(serde_json::Value::Null) => vec![],
+        Some(serde_json::Value::Array(v)) => v.clone(),
+        Some(v) => return Err(format!("invalid transforms {}, expected a list", v)),
+    };
+    let mut chain = vec![];
+    for mut conf in list {
+        let name = match conf.get(TRANSFORM_TYPE_KEY).and_then(|v| v.as_str()) {
+            Some(v) => v.to_owned(),
+            None => return Err(format!("transform {} has no type", conf)),
+        };
+        if let Some(obj) = conf.as_object_mut() {
+            obj.insert(TASKING_CFG_KEY.to_owned(), tasking_cfg.clone());
+        }
+        check_transform_cfg(&name, &conf)?;
+        chain.push((name, conf));
+    }
+    let at = match chain.iter().position(|(name, _)| name == flatten::FLATTEN) {
+        Some(at) => at,
+        None => {
+            let at = chain
+                .iter()
+                .take_while(|(name, conf)| transform_before_flatten(name, conf))
+                .count();
+            let conf = serde_json::json!({
+                TRANSFORM_TYPE_KEY: flatten::FLATTEN,
+                TASKING_CFG_KEY: tasking_cfg,
+            });
+            chain.insert(at, (flatten::FLATTEN.to_owned(), conf));
+            at
+        }
+    };
+    // msgs after flatten carry rows, a transform on msg values would drop them
+    if let Some((name, _)) = chain[at..]
+        .iter()
+        .find(|(name, conf)| transform_before_flatten(name, conf))
+    {
+        return Err(format!("transform {} has to run before flatten", name));
+    }
+    // computed then filter options run right after flatten unless the chain places them
+    let mut options = vec![];
+    match tasking_cfg.get(computed::COMPUTED_KEY) {
+        None | Some(serde_json::Value::Null) => (),
+        Some(fields @ serde_json::Value::Object(_)) => options.push((
+            computed::COMPUTED,
+            serde_json::json!({TRANSFORM_TYPE_KEY: computed::COMPUTED, "fields": fields}),
+        )),
+        Some(v) => {
+            return Err(format!(
+                "invalid computed {}, expected column expressions",
+                v
+            ))
+        }
+    }
+    match tasking_cfg.get(filter::FILTER_KEY) {
+        None | Some(serde_json::Value::Null) => (),
+        Some(serde_json::Value::String(expr)) => options.push((
+            filter::FILTER,
+            serde_json::json!({TRANSFORM_TYPE_KEY: filter::FILTER, "expr": expr}),
+        )),
+        Some(v) => return Err(format!("invalid filter {}, expected an expression", v)),
+    }
+    let mut at = chain
+        .iter()
+        .position(|(name, _)| name == flatten::FLATTEN)
+        .unwrap_or_default();
+    for (name, mut conf) in options {
+        if chain.iter().any(|(placed, _)| placed == name) {
+            continue;
+        }
+        if let Some(obj) = conf.as_object_mut() {
+            obj.insert(TASKING_CFG_KEY.to_owned(), tasking_cfg.clone());
+        }
+        check_transform_cfg(&name.to_owned(), &conf)?;
+        at += 1;
+        chain.insert(at, (name.to_owned(), conf));
+    }
+    Ok(chain)
+}
+
+/// check every transform of a tasking cfg
+pub fn check_transforms(tasking_cfg: &serde_json::Value) -> Result<(), String> {
+    task_transforms(tasking_cfg).map(|_| ())
+}
+
+/// start the transforms of a task in front of dst,
+/// msgs sent to the returned sender reach dst after every transform
+pub fn spawn_transforms(
+    task_id: &String,
+    chain: Vec<(String, serde_json::Value)>,
+    dst: mpsc::Sender<Msg>,
+) -> Result<(mpsc::Sender<Msg>, Vec<JoinHandle<()>>), String> {
+    let mut transforms = vec![];
+    {
+        let plugin = TRANSFORM_PLUGIN.lock().unwrap();
+        for (name, conf) in chain {
+            match plugin.get(name.as_str()) {
+                Some(v) => transforms.push((v.clone(), conf)),
+                None => return Err(format!("not found transform type {}", name)),
+            }
+        }
+    }
+    let mut sender = dst;
+    let mut handlers = vec![];
+    // the last transform feeds dst, build the chain from there
+    for (transform, conf) in transforms.into_iter().rev() {
+        let (tx, receive) = mpsc::channel::<Msg>(20);
+        let task_id = task_id.to_owned();
+        handlers.push(tokio::task::spawn(async move {
+            transform.to_transform(task_id, conf, receive, sender).await;
+        }));
+        sender = tx;
+    }
+    Ok((sender, handlers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_transform_chain() {
+        let tasking_cfg = serde_json::json!({"sep": ".", "max_depth": 8, "ignore": [], "fold": []});
+        let chain = task_transforms(&tasking_cfg).unwrap();
+        assert_eq!(chain.len(), 1);
+        assert_eq!(chain[0].0, flatten::FLATTEN);
+        assert_eq!(chain[0].1[TASKING_CFG_KEY], tasking_cfg);
+
+        let (dst, mut receive) = mpsc::channel::<Msg>(10);
+        let (sender, _) = spawn_transforms(&"chain-task".to_owned(), chain, dst).unwrap();
+        let value = serde_json::json!({"a": {"b": 1}});
+        sender.send(Msg::new("g".to_owned(), value)).await.unwrap();
+        let msg = receive.recv().await.unwrap();
+        assert_eq!(msg.rows.unwrap()[0]["a.b"], 1);
+
+        let tasking_cfg = serde_json::json!({"transforms": [{"type": "nope"}]});
+        assert!(task_transforms(&tasking_cfg).is_err());
+
+        // scripts on msg values go ahead of flatten
+        let script = serde_json::json!({"type": "rhai", "mode": "message", "script": "record"});
+        let tasking_cfg = serde_json::json!({"transforms": [script]});
+        let chain = task_transforms(&tasking_cfg).unwrap();
+        assert_eq!(chain[0].0, script::RHAI);
+        assert_eq!(chain[1].0, flatten::FLATTEN);
+        let tasking_cfg = serde_json::json!({"transforms": [{"type": "flatten"}, script]});
+        assert!(task_transforms(&tasking_cfg).is_err());
+        // a row transform puts flatten ahead of a later one on msg values
+        let rows = serde_json::json!({"type": "rhai", "script": "record"});
+        let tasking_cfg = serde_json::json!({"transforms": [rows, script]});
+        assert!(task_transforms(&tasking_cfg).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_computed_and_filter_options() {
+        let task_id = "filter-task".to_owned();
+        let tasking_cfg = serde_json::json!({
+            "sep": ".", "max_depth": 8, "ignore": [], "fold": [],
+            "filter": "double > 2", "computed": {"double": "a.b * 2"},
+        });
+        let chain = task_transforms(&tasking_cfg).unwrap();
+        assert_eq!(chain[1].0, computed::COMPUTED);
+        assert_eq!(chain[2].0, filter::FILTER);
+
+        let (dst, mut receive) = mpsc::channel::<Msg>(10);
+        let (sender, _) = spawn_transforms(&task_id, chain, dst).unwrap();
+        for b in 1..3 {
+            let value = serde_json::json!({"a": {"b": b}});
+            sender.send(Msg::new(b.to_string(), value)).await.unwrap();
+        }
+        // the msg without rows still reaches the dst
+        assert!(receive.recv().await.unwrap().rows.unwrap().is_empty());
+        let rows = receive.recv().await.unwrap().rows.unwrap();
+        assert_eq!(rows[0]["a.b"], 2);
+        assert_eq!(rows[0]["double"], 4);
+        assert_eq!(crate::metrics::task_metrics(&task_id).unwrap().filtered, 1);
+
+        let tasking_cfg = serde_json::json!({"filter": "a.b >"});
+        assert!(check_transforms(&tasking_cfg).is_err());
+        let tasking_cfg = serde_json::json!({"computed": {"c": "lower(a, b)"}});
+        assert!(check_transforms(&tasking_cfg).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use std::sync::Arc;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::core::{Ack, Msg, Row};
+use crate::dead_letter::{self, DeadLetter, Stage};
+use crate::metrics;
+
+/// what a user transform runs on
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordMode {
+    // on the msg value before flatten
+    Message,
+    // on each flattened row
+    #[default]
+    Row,
+}
+
+// key of the record mode in a transform conf
+pub const MODE_KEY: &str = "mode";
+
+/// record mode of a transform conf is message
+pub fn on_message(conf: &serde_json::Value) -> bool {
+    conf.get(MODE_KEY).and_then(|v| v.as_str()) == Some("message")
+}
+
+/// rows run gave back for the rows of a msg, each has to be a map
+pub fn run_rows<F>(rows: Vec<Row>, run: F) -> Result<Vec<Row>, String>
+where
+    F: Fn(Vec<serde_json::Value>) -> Result<Vec<serde_json::Value>, String>,
+{
+    let records = rows
+        .into_iter()
+        .map(|row| serde_json::Value::Object(row.into_iter().collect()))
+        .collect();
+    let mut out = vec![];
+    for value in run(records)? {
+        match value {
+            serde_json::Value::Object(v) => out.push(v.into_iter().collect()),
+            v => return Err(format!("row {} is not a map", v)),
+        }
+    }
+    Ok(out)
+}
+
+/// feeds the msg values or rows of a task through run, which gives back zero or more
+/// records for the records of one msg. run is user code, it runs on the blocking pool.
+/// a msg run fails on goes to the dead letter dst with reason
+pub async fn run_records<F>(
+    task_id: &String,
+    mode: RecordMode,
+    reason: &'static str,
+    run: Arc<F>,
+    mut receive: mpsc::Receiver<Msg>,
+    sender: mpsc::Sender<Msg>,
+) where
+    F: Fn(Vec<serde_json::Value>) -> Result<Vec<serde_json::Value>, String> + Send + Sync + 'static,
+{
+    while let Some(mut msg) = receive.recv().await {
+        let run = run.clone();
+        let msgs = match mode {
+            RecordMode::Message => {
+                let value = msg.value.clone();
+                match blocking(move || run(vec![value])).await {
+                    Ok(values) => split_msg(msg, values),
+                    Err(err) => {
+                        run_failed(task_id, reason, msg, err).await;
+                        continue;
+                    }
+                }
+            }
+            RecordMode::Row => {
+                if let Some(rows) = msg.rows.take() {
+                    let before = rows.len();
+                    match blocking(move || run_rows(rows, &*run)).await {
+                        Ok(rows) => {
+                            if rows.len() < before {
+                                metrics::record_filtered(task_id, (before - rows.len()) as u64);
+                            }
+                            msg.rows = Some(rows);
+                        }
+                        Err(err) => {
+                            run_failed(task_id, reason, msg, err).await;
+                            continue;
+                        }
+                    }
+                }
+                vec![msg]
+            }
+        };
+        for msg in msgs {
+            if sender.send(msg).await.is_err() {
+                info!("[transform] {} task_id {} sender closed", reason, task_id);
+                return;
+            }
+        }
+    }
+}
+
+// run f on the blocking pool so user code does not hold up a runtime worker
+async fn blocking<T, F>(f: F) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, String> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(v) => v,
+        Err(err) => Err(format!("run error {}", err)),
+    }
+}
+
+/// one msg per value, they share the ack of the source msg
+fn split_msg(mut msg: Msg, values: Vec<serde_json::Value>) -> Vec<Msg> {
+    let acks: Vec<Option<Ack>> = match msg.ack.take() {
+        Some(ack) if values.is_empty() => {
+            ack.ack();
+            return vec![];
+        }
+        Some(ack) => ack.split(values.len()).into_iter().map(Some).collect(),
+        None => values.iter().map(|_| None).collect(),
+    };
+    values
+        .into_iter()
+        .zip(acks)
+        .map(|(value, ack)| Msg {
+            g_id: msg.g_id.clone(),
+            value,
+            rows: None,
+            ack,
+        })
+        .collect()
+}
+
+async fn run_failed(task_id: &String, reason: &'static str, mut msg: Msg, err: String) {
+    let letter = DeadLetter::new(
+        task_id,
+        &msg.g_id,
+        Stage::Transform,
+        reason,
+        err,
+        msg.value.to_string().as_bytes(),
+    );
+    if let Some(ack) = dead_letter::route(letter, msg.ack.take()).await {
+        ack.ack();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Offset;
+
+    #[tokio::test]
+    async fn test_split_msg_acks_once() {
+        let (offsets, mut committed) = mpsc::unbounded_channel();
+        let offset = Offset::new("t".to_owned(), 0, 7);
+        let mut msg = Msg::new("g".to_owned(), serde_json::json!([{"a": 1}, {"a": 2}]));
+        msg.ack = Some(Ack::new(offset, offsets));
+        let (tx, receive) = mpsc::channel(10);
+        let (sender, mut dst) = mpsc::channel(10);
+        tx.send(msg).await.unwrap();
+        drop(tx);
+        let run = |v: Vec<serde_json::Value>| Ok(v[0].as_array().cloned().unwrap_or_default());
+        let task_id = "split-task".to_owned();
+        let run = Arc::new(run);
+        run_records(&task_id, RecordMode::Message, "split", run, receive, sender).await;
+
+        let first = dst.recv().await.unwrap();
+        let second = dst.recv().await.unwrap();
+        assert_eq!(second.value["a"], 2);
+        first.ack.unwrap().ack();
+        assert!(committed.try_recv().is_err());
+        second.ack.unwrap().ack();
+        assert_eq!(committed.try_recv().unwrap().offset, 7);
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use log::{error, info};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+
+use crate::core::{Msg, Row};
+
+use super::Transform;
+
+pub const REDACT: &str = "redact";
+
+/// flattened path to pii kind to values found
+pub type PiiReport = BTreeMap<String, BTreeMap<String, u64>>;
+
+lazy_static! {
+    static ref EMAIL: Regex =
+        Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}").unwrap();
+    static ref PHONE: Regex =
+        Regex::new(r"^\+?\(?\d{1,4}\)?[\s.-]?\d{2,4}[\s.-]?\d{3,4}[\s.-]?\d{3,4}$").unwrap();
+    static ref IPV4: Regex = Regex::new(r"\b(?:\d{1,3}\.){3}\d{1,3}\b").unwrap();
+    static ref PII_REPORT: Mutex<HashMap<String, PiiReport>> = Mutex::new(HashMap::new());
+}
+
+/// redacts flattened columns by path rules, and pii found in the other string columns
+/// when detection is on. what was found is counted per task in the pii report
+pub struct RedactTransform {}
+#[async_trait]
+impl Transform for RedactTransform {
+    async fn to_transform(
+        &self,
+        task_id: String,
+        conf: Value,
+        mut receive: mpsc::Receiver<Msg>,
+        sender: mpsc::Sender<Msg>,
+    ) {
+        let redactor = match check_redact_cfg(&conf) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("[transform] redact task_id {} {}", task_id, err);
+                return;
+            }
+        };
+        while let Some(mut msg) = receive.recv().await {
+            if let Some(rows) = msg.rows.as_mut() {
+                let mut found = vec![];
+                rows.iter_mut()
+                    .for_each(|row| found.extend(redactor.redact(row)));
+                record_pii(&task_id, found);
+                // the raw value would carry the pii on to dead letters and logs
+                msg.value = serde_json::json!(rows);
+            }
+            if sender.send(msg).await.is_err() {
+                break;
+            }
+        }
+        info!("[transform] redact task_id {} exit", task_id);
+    }
+
+    fn cfg(&self) -> Value {
+        serde_json::json!(RedactConfig::default())
+    }
+
+    fn transform_name(&self) -> String {
+        REDACT.to_owned()
+    }
+
+    fn check_cfg(&self, conf: &Value) -> Result<(), String> {
+        check_redact_cfg(conf).map(|_| ())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Action {
+    Drop,
+    // stars all but the last keep_last chars
+    Mask {
+        #[serde(default)]
+        keep_last: usize,
+    },
+    // hex sha256 of salt and value
+    Hash,
+    // same length and char classes, the same value always gets the same token
+    Tokenize,
+    // zeroes the host bits, a value that is not an ip is dropped
+    TruncateIp {
+        #[serde(default = "default_v4_prefix")]
+        v4_prefix: u8,
+        #[serde(default = "default_v6_prefix")]
+        v6_prefix: u8,
+    },
+}
+
+fn default_v4_prefix() -> u8 {
+    24
+}
+
+fn default_v6_prefix() -> u8 {
+    48
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PiiKind {
+    Email,
+    Phone,
+    Ip,
+}
+
+impl PiiKind {
+    fn name(&self) -> &'static str {
+        match self {
+            PiiKind::Email => "email",
+            PiiKind::Phone => "phone",
+            PiiKind::Ip => "ip",
+        }
+    }
+
+    fn found_in(&self, s: &str) -> bool {
+        match self {
+            PiiKind::Email => EMAIL.is_match(s),
+            PiiKind::Phone => PHONE.is_match(s.trim()),
+            PiiKind::Ip => {
+                s.trim().parse::<IpAddr>().is_ok()
+                    || IPV4
+                        .find_iter(s)
+                        .any(|v| v.as_str().parse::<IpAddr>().is_ok())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RedactRule {
+    // flattened path, * matches any chars like user.*.email
+    pub path: String,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DetectConfig {
+    #[serde(default = "default_kinds")]
+    pub kinds: Vec<PiiKind>,
+    // count what is found without changing it
+    #[serde(default)]
+    pub report_only: bool,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+fn default_kinds() -> Vec<PiiKind> {
+    vec![PiiKind::Email, PiiKind::Phone, PiiKind::Ip]
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+pub struct RedactConfig {
+    #[serde(default)]
+    pub rules: Vec<RedactRule>,
+    // salt of hash and tokenize
+    #[serde(default)]
+    pub salt: String,
+    #[serde(default)]
+    pub detect: Option<DetectConfig>,
+}
+
+/// compiled redact rules
+pub struct Redactor {
+    rules: Vec<(Regex, Action)>,
+    salt: String,
+    detect: Option<DetectConfig>,
+}
+
+impl Redactor {
+    pub fn new(cfg: &RedactConfig) -> Result<Redactor, String> {
+        let mut rules = vec![];
+        for rule in &cfg.rules {
+            if rule.path.is_empty() {
+                return Err("redact rule has no path".to_owned());
+            }
+            let pattern = format!("^{}$", regex::escape(&rule.path).replace(r"\*", ".*"));
+            match Regex::new(&pattern) {
+                Ok(v) => rules.push((v, rule.action.clone())),
+                Err(err) => return Err(format!("invalid redact path {} {}", rule.path, err)),
+            }
+        }
+        for action in cfg
+            .rules
+            .iter()
+            .map(|v| &v.action)
+            .chain(cfg.detect.iter().map(|v| &v.action))
+        {
+            match action {
+                Action::TruncateIp {
+                    v4_prefix,
+                    v6_prefix,
+                } if *v4_prefix > 32 || *v6_prefix > 128 => {
+                    return Err(format!("invalid ip prefix {}/{}", v4_prefix, v6_prefix));
+                }
+                // unsalted hashes and tokens of short values are easy to reverse
+                Action::Hash | Action::Tokenize if cfg.salt.is_empty() => {
+                    return Err("hash and tokenize need a salt".to_owned());
+                }
+                _ => {}
+            }
+        }
+        Ok(Redactor {
+            rules,
+            salt: cfg.salt.to_owned(),
+            detect: cfg.detect.clone(),
+        })
+    }
+
+    /// redact a row in place, gives back the paths and kinds of pii found
+    pub fn redact(&self, row: &mut Row) -> Vec<(String, PiiKind)> {
+        let mut found = vec![];
+        let paths: Vec<String> = row.keys().cloned().collect();
+        for path in paths {
+            let action = match self.rules.iter().find(|(re, _)| re.is_match(&path)) {
+                Some((_, action)) => action,
+                None => match self.detected(&path, &row[&path], &mut found) {
+                    Some(v) => v,
+                    None => continue,
+                },
+            };
+            match apply(action, &self.salt, &row[&path]) {
+                Some(v) => row.insert(path, v),
+                None => row.remove(&path),
+            };
+        }
+        found
+    }
+
+    // the detect action when a string column holds pii
+    fn detected(
+        &self,
+        path: &str,
+        value: &Value,
+        found: &mut Vec<(String, PiiKind)>,
+    ) -> Option<&Action> {
+        let detect = self.detect.as_ref()?;
+        let s = value.as_str()?;
+        let kind = detect.kinds.iter().find(|kind| kind.found_in(s))?;
+        found.push((path.to_owned(), *kind));
+        match detect.report_only {
+            true => None,
+            false => Some(&detect.action),
+        }
+    }
+}
+
+fn value_str(value: &Value) -> String {
+    match value {
+        Value::String(v) => v.to_owned(),
+        v => v.to_string(),
+    }
+}
+
+/// the redacted value, none when it is dropped
+pub fn apply(action: &Action, salt: &str, value: &Value) -> Option<Value> {
+    if value.is_null() {
+        return Some(Value::Null);
+    }
+    let s = value_str(value);
+    match action {
+        Action::Drop => None,
+        Action::Mask { keep_last } => {
+            let len = s.chars().count();
+            let keep = len.saturating_sub(*keep_last);
+            let masked: String = s
+                .chars()
+                .enumerate()
+                .map(|(i, c)| if i < keep { '*' } else { c })
+                .collect();
+            Some(Value::String(masked))
+        }
+        Action::Hash => Some(Value::String(format!(
+            "{:x}",
+            Sha256::digest(format!("{}{}", salt, s).as_bytes())
+        ))),
+        Action::Tokenize => Some(Value::String(tokenize(salt, &s))),
+        Action::TruncateIp {
+            v4_prefix,
+            v6_prefix,
+        } => match s.trim().parse::<IpAddr>().ok()? {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - *v4_prefix as u32).unwrap_or(0);
+                let ip = std::net::Ipv4Addr::from(u32::from(ip) & mask);
+                Some(Value::String(ip.to_string()))
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX.checked_shl(128 - *v6_prefix as u32).unwrap_or(0);
+                let ip = std::net::Ipv6Addr::from(u128::from(ip) & mask);
+                Some(Value::String(ip.to_string()))
+            }
+        },
+    }
+}
+
+// digits stay digits and letters stay letters of the same case, anything else is kept
+fn tokenize(salt: &str, s: &str) -> String {
+    let mut stream = vec![];
+    let mut block = 0u64;
+    s.chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if i >= stream.len() {
+                let seed = format!("{}\u{0}{}\u{0}{}", salt, s, block);
+                stream.extend_from_slice(&Sha256::digest(seed.as_bytes()));
+                block += 1;
+            }
+            let k = stream[i];
+            match c {
+                '0'..='9' => (b'0' + k % 10) as char,
+                'a'..='z' => (b'a' + k % 26) as char,
+                'A'..='Z' => (b'A' + k % 26) as char,
+                c => c,
+            }
+        })
+        .collect()
+}
+
+fn record_pii(task_id: &String, found: Vec<(String, PiiKind)>) {
+    if found.is_empty() {
+        return;
+    }
+    let mut lock = PII_REPORT.lock().unwrap();
+    let report = lock.entry(task_id.to_owned()).or_default();
+    for (path, kind) in found {
+        *report
+            .entry(path)
+            .or_default()
+            .entry(kind.name().to_owned())
+            .or_default() += 1;
+    }
+}
+
+/// clear the pii report of a task when it starts
+pub fn reset_pii_report(task_id: &String) {
+    PII_REPORT.lock().unwrap().remove(task_id);
+}
+
+/// pii found per flattened path and kind since the task started
+pub fn pii_report(task_id: &String) -> Option<PiiReport> {
+    PII_REPORT.lock().unwrap().get(task_id).cloned()
+}
+
+pub fn check_redact_cfg(conf: &Value) -> Result<Redactor, String> {
+    match serde_json::from_value::<RedactConfig>(conf.clone()) {
+        Ok(v) => Redactor::new(&v),
+        Err(err) => Err(format!("invalid config  {} error {:?}", conf, err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_row() {
+        let conf = serde_json::json!({
+            "salt": "s",
+            "rules": [
+                {"path": "user.email", "mode": "hash"},
+                {"path": "user.*.phone", "mode": "mask", "keep_last": 4},
+                {"path": "card", "mode": "tokenize"},
+                {"path": "client_ip", "mode": "truncate_ip"},
+                {"path": "password", "mode": "drop"},
+            ],
+            "detect": {"mode": "mask"},
+        });
+        let redactor = check_redact_cfg(&conf).unwrap();
+        let mut row: Row = serde_json::from_value(serde_json::json!({
+            "user.email": "a@b.com",
+            "user.home.phone": "13812345678",
+            "card": "4111-1111-Ab",
+            "client_ip": "10.1.2.3",
+            "password": "x",
+            "note": "mail me at c@d.io",
+            "id": 7,
+        }))
+        .unwrap();
+        let found = redactor.redact(&mut row);
+
+        assert_eq!(row["user.email"].as_str().unwrap().len(), 64);
+        assert_eq!(row["user.home.phone"], "*******5678");
+        let card = row["card"].as_str().unwrap();
+        assert_eq!(card.len(), 12);
+        assert_ne!(card, "4111-1111-Ab");
+        assert!(card[..4].chars().all(|c| c.is_ascii_digit()) && &card[4..5] == "-");
+        assert!(card[10..11].chars().all(|c| c.is_ascii_uppercase()));
+        assert_eq!(
+            apply(&Action::Tokenize, "s", &"4111-1111-Ab".into()).unwrap(),
+            card
+        );
+        assert_eq!(row["client_ip"], "10.1.2.0");
+        assert!(!row.contains_key("password"));
+        assert_eq!(row["note"], "*****************");
+        assert_eq!(row["id"], 7);
+        assert_eq!(found, vec![("note".to_owned(), PiiKind::Email)]);
+
+        let ip = Action::TruncateIp {
+            v4_prefix: 0,
+            v6_prefix: 32,
+        };
+        assert_eq!(
+            apply(&ip, "", &"2001:db8:1::1".into()).unwrap(),
+            "2001:db8::"
+        );
+        assert_eq!(apply(&ip, "", &"1.2.3.4".into()).unwrap(), "0.0.0.0");
+        assert!(apply(&ip, "", &"nope".into()).is_none());
+        assert!(PiiKind::Phone.found_in("+86 138-1234-5678"));
+        assert!(!PiiKind::Phone.found_in("2024-01-01"));
+        assert!(check_redact_cfg(&serde_json::json!({"rules": [{"path": "a"}]})).is_err());
+        assert!(check_redact_cfg(&serde_json::json!({"detect": {"mode": "hash"}})).is_err());
+    }
+}
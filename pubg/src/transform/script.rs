@@ -0,0 +1,258 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use log::{debug, error, info};
+use rhai::serde::{from_dynamic, to_dynamic};
+use rhai::{Dynamic, Engine, EvalAltResult, Scope, AST};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::core::{Msg, Row};
+
+use super::record::{on_message, run_records, run_rows, RecordMode};
+use super::Transform;
+
+pub const RHAI: &str = "rhai";
+// scope variable holding the msg value or the row a script runs on
+pub const RECORD: &str = "record";
+
+/// runs a rhai script on each msg before flatten or on each row after it.
+/// the script changes `record` in place or evaluates to a new record,
+/// an array of records splits it and an empty array drops it
+pub struct ScriptTransform {}
+#[async_trait]
+impl Transform for ScriptTransform {
+    async fn to_transform(
+        &self,
+        task_id: String,
+        conf: serde_json::Value,
+        receive: mpsc::Receiver<Msg>,
+        sender: mpsc::Sender<Msg>,
+    ) {
+        let script = match check_script_cfg(&conf) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("[transform] rhai task_id {} {}", task_id, err);
+                return;
+            }
+        };
+        let mode = script.mode;
+        let run = Arc::new(move |records| script.run_batch(records));
+        run_records(&task_id, mode, "script_error", run, receive, sender).await;
+        info!("[transform] rhai task_id {} exit", task_id);
+    }
+
+    fn cfg(&self) -> serde_json::Value {
+        serde_json::json!(ScriptConfig {
+            script: String::new(),
+            mode: RecordMode::default(),
+            max_operations: default_max_operations(),
+            timeout_ms: default_timeout_ms(),
+        })
+    }
+
+    fn transform_name(&self) -> String {
+        RHAI.to_owned()
+    }
+
+    fn check_cfg(&self, conf: &serde_json::Value) -> Result<(), String> {
+        check_script_cfg(conf).map(|_| ())
+    }
+
+    fn before_flatten(&self, conf: &serde_json::Value) -> bool {
+        on_message(conf)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ScriptConfig {
+    pub script: String,
+    #[serde(default)]
+    pub mode: RecordMode,
+    // rhai operations a single run may take
+    #[serde(default = "default_max_operations")]
+    pub max_operations: u64,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_max_operations() -> u64 {
+    100_000
+}
+
+fn default_timeout_ms() -> u64 {
+    50
+}
+
+// a script holds up the whole task while it runs
+const MAX_TIMEOUT_MS: u64 = 10_000;
+// sizes a script may build, records larger than them fail to load
+const MAX_STRING_SIZE: usize = 4 * 1024 * 1024;
+const MAX_ARRAY_SIZE: usize = 100_000;
+const MAX_MAP_SIZE: usize = 100_000;
+const MAX_CALL_LEVELS: usize = 32;
+
+/// a compiled script with its limits
+pub struct Script {
+    pub mode: RecordMode,
+    engine: Engine,
+    ast: AST,
+    timeout: Duration,
+    deadline: Arc<Mutex<Instant>>,
+}
+
+impl Script {
+    pub fn compile(cfg: &ScriptConfig) -> Result<Script, String> {
+        if cfg.max_operations == 0 || cfg.timeout_ms == 0 {
+            return Err("max_operations and timeout_ms must be greater than 0".to_owned());
+        }
+        if cfg.timeout_ms > MAX_TIMEOUT_MS {
+            return Err(format!("timeout_ms is over {}", MAX_TIMEOUT_MS));
+        }
+        let timeout = Duration::from_millis(cfg.timeout_ms);
+        let deadline = Arc::new(Mutex::new(Instant::now()));
+        let mut engine = Engine::new();
+        engine.set_max_operations(cfg.max_operations);
+        engine.set_max_string_size(MAX_STRING_SIZE);
+        engine.set_max_array_size(MAX_ARRAY_SIZE);
+        engine.set_max_map_size(MAX_MAP_SIZE);
+        engine.set_max_call_levels(MAX_CALL_LEVELS);
+        let at = deadline.clone();
+        engine.on_progress(move |ops| {
+            // checking the clock on every operation is too slow
+            if ops % 256 == 0 && Instant::now() > *at.lock().unwrap() {
+                return Some(Dynamic::UNIT);
+            }
+            None
+        });
+        engine.on_print(|s| debug!("[transform] rhai print {}", s));
+        engine.on_debug(|s, _, pos| debug!("[transform] rhai debug {} {}", pos, s));
+        let ast = match engine.compile(&cfg.script) {
+            Ok(v) => v,
+            Err(err) => return Err(format!("invalid script {}", err)),
+        };
+        Ok(Script {
+            mode: cfg.mode,
+            engine,
+            ast,
+            timeout,
+            deadline,
+        })
+    }
+
+    /// records the script gave back for one record
+    pub fn run(&self, record: serde_json::Value) -> Result<Vec<serde_json::Value>, String> {
+        let record = to_dynamic(&record).map_err(|err| err.to_string())?;
+        let mut scope = Scope::new();
+        scope.push_dynamic(RECORD, record);
+        *self.deadline.lock().unwrap() = Instant::now() + self.timeout;
+        let out = match self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast)
+        {
+            Ok(v) => v,
+            Err(err) => {
+                return Err(match *err {
+                    EvalAltResult::ErrorTerminated(..) => {
+                        format!("script timed out after {:?}", self.timeout)
+                    }
+                    err => err.to_string(),
+                })
+            }
+        };
+        // no value means the record was changed in place
+        let out = match out.is_unit() {
+            true => scope.get_value::<Dynamic>(RECORD).unwrap_or_default(),
+            false => out,
+        };
+        let res = match out.is_array() {
+            true => from_dynamic::<Vec<serde_json::Value>>(&out),
+            false => from_dynamic::<serde_json::Value>(&out).map(|v| vec![v]),
+        };
+        res.map_err(|err| err.to_string())
+    }
+
+    /// records the script gave back for the records of one msg
+    pub fn run_batch(
+        &self,
+        records: Vec<serde_json::Value>,
+    ) -> Result<Vec<serde_json::Value>, String> {
+        let mut out = vec![];
+        for record in records {
+            out.extend(self.run(record)?);
+        }
+        Ok(out)
+    }
+
+    /// rows the script gave back for the rows of a msg, each has to be a map
+    pub fn run_rows(&self, rows: Vec<Row>) -> Result<Vec<Row>, String> {
+        run_rows(rows, |records| self.run_batch(records))
+    }
+}
+
+pub fn check_script_cfg(conf: &serde_json::Value) -> Result<Script, String> {
+    match serde_json::from_value::<ScriptConfig>(conf.clone()) {
+        Ok(v) => Script::compile(&v),
+        Err(err) => Err(format!("invalid config  {} error {:?}", conf, err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_modes() {
+        let conf = serde_json::json!({"script": "record.total = record.price * record.qty;"});
+        let script = check_script_cfg(&conf).unwrap();
+        assert_eq!(script.mode, RecordMode::Row);
+        let row: Row = serde_json::from_value(serde_json::json!({"price": 2, "qty": 3})).unwrap();
+        let rows = script.run_rows(vec![row]).unwrap();
+        assert_eq!(rows[0]["total"], 6);
+
+        let conf = serde_json::json!({
+            "mode": "message",
+            "script": "if record.skip { [] } else { record.items }",
+        });
+        let script = check_script_cfg(&conf).unwrap();
+        let values = script
+            .run(serde_json::json!({"skip": false, "items": [{"a": 1}, {"a": 2}]}))
+            .unwrap();
+        assert_eq!(
+            values,
+            vec![serde_json::json!({"a": 1}), serde_json::json!({"a": 2})]
+        );
+        assert!(script
+            .run(serde_json::json!({"skip": true}))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_script_limits() {
+        let conf = serde_json::json!({"script": "loop {}", "max_operations": 1000});
+        let err = check_script_cfg(&conf)
+            .unwrap()
+            .run(serde_json::json!({}))
+            .unwrap_err();
+        assert!(err.contains("operations"), "{}", err);
+
+        let conf =
+            serde_json::json!({"script": "loop {}", "max_operations": u64::MAX, "timeout_ms": 20});
+        let err = check_script_cfg(&conf)
+            .unwrap()
+            .run(serde_json::json!({}))
+            .unwrap_err();
+        assert!(err.contains("timed out"), "{}", err);
+
+        let conf = serde_json::json!({"script": "let s = \"x\"; loop { s += s; }"});
+        let err = check_script_cfg(&conf)
+            .unwrap()
+            .run(serde_json::json!({}))
+            .unwrap_err();
+        assert!(err.contains("string"), "{}", err);
+
+        assert!(check_script_cfg(&serde_json::json!({"script": "record."})).is_err());
+    }
+}
@@ -0,0 +1,321 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use wasmi::core::TrapCode;
+use wasmi::{Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::core::{Msg, Row};
+
+use super::record::{on_message, run_records, run_rows, RecordMode};
+use super::Transform;
+
+pub const WASM: &str = "wasm";
+
+// exports a module has to give the host
+const MEMORY_EXPORT: &str = "memory";
+const ALLOC_EXPORT: &str = "alloc";
+const TRANSFORM_EXPORT: &str = "transform";
+
+lazy_static! {
+    // fuel metering is an engine option, every module shares the engine
+    static ref ENGINE: Engine = {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        Engine::new(&config)
+    };
+    // where modules named in a transform chain are loaded from
+    static ref MODULE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// set the directory wasm modules are loaded from
+pub fn set_module_dir(dir: &str) {
+    let dir = match dir.is_empty() {
+        true => None,
+        false => Some(PathBuf::from(dir)),
+    };
+    *MODULE_DIR.lock().unwrap() = dir;
+}
+
+/// runs a wasm module on each msg before flatten or on each row after it.
+/// the module gets a record as json through `alloc` and `transform(ptr, len)`,
+/// and answers with the ptr and len of a json array of records packed in an i64
+/// as `ptr << 32 | len`. an empty array drops the record
+pub struct WasmTransform {}
+#[async_trait]
+impl Transform for WasmTransform {
+    async fn to_transform(
+        &self,
+        task_id: String,
+        conf: serde_json::Value,
+        receive: mpsc::Receiver<Msg>,
+        sender: mpsc::Sender<Msg>,
+    ) {
+        let wasm = match check_wasm_cfg(&conf) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("[transform] wasm task_id {} {}", task_id, err);
+                return;
+            }
+        };
+        let mode = wasm.mode;
+        let run = Arc::new(move |records| wasm.run_batch(records));
+        run_records(&task_id, mode, "wasm_error", run, receive, sender).await;
+        info!("[transform] wasm task_id {} exit", task_id);
+    }
+
+    fn cfg(&self) -> serde_json::Value {
+        serde_json::json!(WasmConfig {
+            module: String::new(),
+            mode: RecordMode::default(),
+            fuel: default_fuel(),
+            max_memory_mb: default_max_memory_mb(),
+        })
+    }
+
+    fn transform_name(&self) -> String {
+        WASM.to_owned()
+    }
+
+    fn check_cfg(&self, conf: &serde_json::Value) -> Result<(), String> {
+        check_wasm_cfg(conf).map(|_| ())
+    }
+
+    fn before_flatten(&self, conf: &serde_json::Value) -> bool {
+        on_message(conf)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct WasmConfig {
+    // module file name in the module dir without .wasm
+    pub module: String,
+    #[serde(default)]
+    pub mode: RecordMode,
+    // wasm instructions a single run may take
+    #[serde(default = "default_fuel")]
+    pub fuel: u64,
+    #[serde(default = "default_max_memory_mb")]
+    pub max_memory_mb: usize,
+}
+
+fn default_fuel() -> u64 {
+    10_000_000
+}
+
+fn default_max_memory_mb() -> usize {
+    16
+}
+
+/// a compiled module with its limits
+pub struct Wasm {
+    pub mode: RecordMode,
+    module: Module,
+    fuel: u64,
+    max_memory: usize,
+}
+
+impl Wasm {
+    pub fn load(cfg: &WasmConfig) -> Result<Wasm, String> {
+        if cfg.fuel == 0 || cfg.max_memory_mb == 0 {
+            return Err("fuel and max_memory_mb must be greater than 0".to_owned());
+        }
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+        if cfg.module.is_empty() || !cfg.module.chars().all(valid) {
+            return Err(format!("invalid wasm module name {:?}", cfg.module));
+        }
+        let dir = match MODULE_DIR.lock().unwrap().clone() {
+            Some(v) => v,
+            None => return Err("wasm module dir is not configured".to_owned()),
+        };
+        let path = dir.join(format!("{}.wasm", cfg.module));
+        let bytes = match std::fs::read(&path) {
+            Ok(v) => v,
+            Err(err) => return Err(format!("read wasm module {:?} error {}", path, err)),
+        };
+        Wasm::from_bytes(cfg, &bytes)
+    }
+
+    pub fn from_bytes(cfg: &WasmConfig, bytes: &[u8]) -> Result<Wasm, String> {
+        let module = match Module::new(&ENGINE, bytes) {
+            Ok(v) => v,
+            Err(err) => return Err(format!("invalid wasm module {} {}", cfg.module, err)),
+        };
+        let wasm = Wasm {
+            mode: cfg.mode,
+            module,
+            fuel: cfg.fuel,
+            max_memory: cfg.max_memory_mb * 1024 * 1024,
+        };
+        // a module without the exports fails here instead of on the first msg
+        wasm.instantiate()?;
+        Ok(wasm)
+    }
+
+    // a fresh instance per msg, nothing a msg leaves in memory reaches the next one
+    fn instantiate(&self) -> Result<(Store<StoreLimits>, wasmi::Instance), String> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.max_memory)
+            .instances(1)
+            .build();
+        let mut store = Store::new(&ENGINE, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(self.fuel).map_err(|err| err.to_string())?;
+        let linker = Linker::<StoreLimits>::new(&ENGINE);
+        let instance = linker
+            .instantiate(&mut store, &self.module)
+            .and_then(|v| v.start(&mut store))
+            .map_err(|err| self.error(err))?;
+        if instance.get_memory(&store, MEMORY_EXPORT).is_none() {
+            return Err(format!("wasm module exports no {}", MEMORY_EXPORT));
+        }
+        for export in [ALLOC_EXPORT, TRANSFORM_EXPORT] {
+            if instance.get_func(&store, export).is_none() {
+                return Err(format!("wasm module exports no {}", export));
+            }
+        }
+        Ok((store, instance))
+    }
+
+    fn error(&self, err: wasmi::Error) -> String {
+        match err.as_trap_code() {
+            Some(TrapCode::OutOfFuel) => format!("wasm ran out of {} fuel", self.fuel),
+            _ => err.to_string(),
+        }
+    }
+
+    /// records the module gave back for one record
+    pub fn run(&self, record: &serde_json::Value) -> Result<Vec<serde_json::Value>, String> {
+        self.run_batch(vec![record.clone()])
+    }
+
+    /// records the module gave back for the records of one msg, they share an instance
+    /// and each gets the whole fuel
+    pub fn run_batch(
+        &self,
+        records: Vec<serde_json::Value>,
+    ) -> Result<Vec<serde_json::Value>, String> {
+        let (mut store, instance) = self.instantiate()?;
+        let mut out = vec![];
+        for record in &records {
+            store.set_fuel(self.fuel).map_err(|err| err.to_string())?;
+            out.extend(self.call(&mut store, &instance, record)?);
+        }
+        Ok(out)
+    }
+
+    fn call(
+        &self,
+        store: &mut Store<StoreLimits>,
+        instance: &wasmi::Instance,
+        record: &serde_json::Value,
+    ) -> Result<Vec<serde_json::Value>, String> {
+        let memory = instance.get_memory(&*store, MEMORY_EXPORT).unwrap();
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&*store, ALLOC_EXPORT)
+            .map_err(|err| err.to_string())?;
+        let transform = instance
+            .get_typed_func::<(i32, i32), i64>(&*store, TRANSFORM_EXPORT)
+            .map_err(|err| err.to_string())?;
+
+        let input = record.to_string().into_bytes();
+        let len = i32::try_from(input.len()).map_err(|err| err.to_string())?;
+        let ptr = alloc
+            .call(&mut *store, len)
+            .map_err(|err| self.error(err))?;
+        memory
+            .write(&mut *store, ptr as u32 as usize, &input)
+            .map_err(|err| format!("write record to wasm memory error {}", err))?;
+        let packed = transform
+            .call(&mut *store, (ptr, len))
+            .map_err(|err| self.error(err))?;
+
+        let (ptr, len) = ((packed as u64 >> 32) as usize, packed as u32 as usize);
+        if ptr.saturating_add(len) > memory.data(&*store).len() {
+            return Err(format!("wasm output {}+{} is out of memory", ptr, len));
+        }
+        let mut output = vec![0u8; len];
+        memory
+            .read(&*store, ptr, &mut output)
+            .map_err(|err| format!("read records from wasm memory error {}", err))?;
+        match serde_json::from_slice::<serde_json::Value>(&output) {
+            Ok(serde_json::Value::Array(v)) => Ok(v),
+            Ok(v) => Err(format!("wasm output {} is not an array", v)),
+            Err(err) => Err(format!("wasm output is not json {}", err)),
+        }
+    }
+
+    /// rows the module gave back for the rows of a msg, each has to be a map
+    pub fn run_rows(&self, rows: Vec<Row>) -> Result<Vec<Row>, String> {
+        run_rows(rows, |records| self.run_batch(records))
+    }
+}
+
+pub fn check_wasm_cfg(conf: &serde_json::Value) -> Result<Wasm, String> {
+    match serde_json::from_value::<WasmConfig>(conf.clone()) {
+        Ok(v) => Wasm::load(&v),
+        Err(err) => Err(format!("invalid config  {} error {:?}", conf, err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // echoes the record back twice as [record,record], or spins when it starts with "{}"
+    const ECHO: &str = r#"
+    (module
+      (memory (export "memory") 1)
+      (global $next (mut i32) (i32.const 1024))
+      (func (export "alloc") (param $len i32) (result i32)
+        (local $ptr i32)
+        (local.set $ptr (global.get $next))
+        (global.set $next (i32.add (global.get $next) (local.get $len)))
+        (local.get $ptr))
+      (func (export "transform") (param $ptr i32) (param $len i32) (result i64)
+        (local $out i32)
+        (if (i32.eq (i32.load16_u (local.get $ptr)) (i32.const 0x7d7b))
+          (then (loop $spin (br $spin))))
+        (local.set $out (global.get $next))
+        (i32.store8 (local.get $out) (i32.const 91))
+        (memory.copy (i32.add (local.get $out) (i32.const 1)) (local.get $ptr) (local.get $len))
+        (i32.store8 (i32.add (local.get $out) (i32.add (local.get $len) (i32.const 1))) (i32.const 44))
+        (memory.copy (i32.add (local.get $out) (i32.add (local.get $len) (i32.const 2))) (local.get $ptr) (local.get $len))
+        (i32.store8 (i32.add (local.get $out) (i32.add (i32.mul (local.get $len) (i32.const 2)) (i32.const 2))) (i32.const 93))
+        (i64.or
+          (i64.shl (i64.extend_i32_u (local.get $out)) (i64.const 32))
+          (i64.extend_i32_u (i32.add (i32.mul (local.get $len) (i32.const 2)) (i32.const 3))))))
+    "#;
+
+    #[test]
+    fn test_wasm_records() {
+        let bytes = wat::parse_str(ECHO).unwrap();
+        let cfg: WasmConfig =
+            serde_json::from_value(serde_json::json!({"module": "echo"})).unwrap();
+        let wasm = Wasm::from_bytes(&cfg, &bytes).unwrap();
+        let row: Row = serde_json::from_value(serde_json::json!({"a": 1})).unwrap();
+        let rows = wasm.run_rows(vec![row]).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1]["a"], 1);
+        // the rows of a msg share an instance
+        let rows = wasm.run_rows(rows).unwrap();
+        assert_eq!(rows.len(), 4);
+
+        let err = wasm.run(&serde_json::json!({})).unwrap_err();
+        assert!(err.contains("fuel"), "{}", err);
+
+        // 17 pages of 64KiB are over 1MiB
+        let bytes = wat::parse_str(ECHO.replace("\"memory\") 1)", "\"memory\") 17)")).unwrap();
+        let cfg = WasmConfig {
+            max_memory_mb: 1,
+            ..cfg
+        };
+        assert!(Wasm::from_bytes(&cfg, &bytes).is_err());
+        let cfg = serde_json::json!({"module": "../echo"});
+        assert!(check_wasm_cfg(&cfg).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+//! crash recovery of the kafka src, needs a running broker (see example/etc/docker-compose.yaml)
+//!
+//! VARBIT_TEST_BROKER=localhost:19092 cargo test -p pubg --test kafka_at_least_once -- --ignored
+use std::{collections::BTreeSet, time::Duration};
+
+use pubg::{core::Msg, input::kafka::KafkaSrc, input::Src};
+use rdkafka::{
+    producer::{FutureProducer, FutureRecord},
+    ClientConfig,
+};
+use tokio::sync::mpsc;
+
+fn broker() -> String {
+    std::env::var("VARBIT_TEST_BROKER").unwrap_or("localhost:19092".to_owned())
+}
+
+fn src_cfg(topic: &String, group_id: &String) -> serde_json::Value {
+    serde_json::json!({
+        "broker": broker(),
+        "topic": topic,
+        "group_id": group_id,
+        "decoder": "json",
+        "meta": {"task_id": group_id},
+        "start_from": "earliest",
+    })
+}
+
+async fn produce(topic: &String, count: i64) {
+    let producer = ClientConfig::new()
+        .set("bootstrap.servers", broker())
+        .set("message.timeout.ms", "5000")
+        .create::<FutureProducer>()
+        .unwrap();
+    for n in 0..count {
+        producer
+            .send(
+                FutureRecord::to(topic)
+                    .key("")
+                    .payload(&serde_json::json!({ "n": n }).to_string()),
+                Duration::from_secs(5),
+            )
+            .await
+            .unwrap();
+    }
+}
+
+// receive msgs until the src is quiet for a while
+async fn collect(receive: &mut mpsc::Receiver<Msg>, ack_below: i64) -> Vec<i64> {
+    let mut res = vec![];
+    while let Ok(Some(mut msg)) =
+        tokio::time::timeout(Duration::from_secs(15), receive.recv()).await
+    {
+        let n = msg.value["n"].as_i64().unwrap();
+        if n < ack_below {
+            msg.ack();
+        }
+        res.push(n);
+    }
+    res
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_uncommitted_msgs_are_redelivered_after_crash() {
+    let id = uuid::Uuid::new_v4().to_string();
+    let topic = format!("varbit-at-least-once-{}", id);
+    let group_id = format!("verb-test-{}", id);
+
+    produce(&topic, 10).await;
+
+    // first run acks the first half then crashes
+    let (sender, mut receive) = mpsc::channel::<Msg>(20);
+    let cfg = src_cfg(&topic, &group_id);
+    let src = tokio::spawn(async move {
+        KafkaSrc {}.from_src("first".to_owned(), &cfg, sender).await;
+    });
+    let first = collect(&mut receive, 5).await;
+    assert_eq!(first, (0..10).collect::<Vec<i64>>());
+    src.abort();
+    let _ = src.await;
+    drop(receive);
+
+    // second run of the same group starts from the first unacked msg
+    let (sender, mut receive) = mpsc::channel::<Msg>(20);
+    let cfg = src_cfg(&topic, &group_id);
+    let src = tokio::spawn(async move {
+        KafkaSrc {}
+            .from_src("second".to_owned(), &cfg, sender)
+            .await;
+    });
+    let second = collect(&mut receive, 10).await;
+    src.abort();
+
+    assert_eq!(
+        second.into_iter().collect::<BTreeSet<i64>>(),
+        (5..10).collect::<BTreeSet<i64>>()
+    );
+}
@@ -52,6 +52,9 @@ pub struct Task {
     pub deleted_at: i64,
     // tasking config
     pub tasking_cfg: String,
+    // json list of sinks, empty means the single dst_type sink
+    #[sqlx(default)]
+    pub dsts: String,
 }
 
 pub async fn fetch_task_list(
@@ -95,6 +98,7 @@ pub async fn update_task(conn: &Pool<MySql>, task: &mut Task) -> Result<i32, Str
          dst_type=?,
          dst_cfg=?,
          tasking_cfg=?,
+         dsts=?,
          updated_at = ?
          WHERE id =?"#,
     )
@@ -104,6 +108,7 @@ pub async fn update_task(conn: &Pool<MySql>, task: &mut Task) -> Result<i32, Str
     .bind(&task.dst_type)
     .bind(&task.dst_cfg)
     .bind(&task.tasking_cfg)
+    .bind(&task.dsts)
     .bind(
         SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -240,7 +245,8 @@ pub async fn create_task(conn: &Pool<MySql>, task: &mut Task) -> Result<(), Stri
         created_at,
         updated_at,
         deleted_at,
-        tasking_cfg) VALUES(?,?,?,?,?,?,?,?,?,?,?,?)"###,
+        tasking_cfg,
+        dsts) VALUES(?,?,?,?,?,?,?,?,?,?,?,?,?)"###,
     )
     .bind(&task.id)
     .bind(&task.name)
@@ -254,6 +260,7 @@ pub async fn create_task(conn: &Pool<MySql>, task: &mut Task) -> Result<(), Stri
     .bind(&task.updated_at)
     .bind(&task.deleted_at)
     .bind(&task.tasking_cfg)
+    .bind(&task.dsts)
     .execute(conn)
     .await
     {
@@ -275,6 +282,7 @@ impl Task {
         src_cfg: &serde_json::Value,
         dst_cfg: &serde_json::Value,
         tasking_cfg: &serde_json::Value,
+        dsts: &serde_json::Value,
     ) -> Self {
         Self {
             id: mongodb::bson::oid::ObjectId::new().to_hex(),
@@ -295,6 +303,7 @@ impl Task {
             src_cfg: src_cfg.to_string(),
             dst_cfg: dst_cfg.to_string(),
             tasking_cfg: tasking_cfg.to_string(),
+            dsts: dsts.to_string(),
         }
     }
 }
@@ -25,7 +25,18 @@ pub mod json {
 
         // fold
         fold: HashSet<String>,
+
+        // declared output columns of table like dsts, e.g. csv
+        #[serde(default)]
+        columns: Vec<String>,
+    }
+
+    impl ChrysaetosBitConfig {
+        pub fn columns(&self) -> &Vec<String> {
+            &self.columns
+        }
     }
+
     // check chrysaetos config
     pub fn check_chrysaetos_bit_cfg(conf: &serde_json::Value) -> Result<(), String> {
         match serde_json::from_value::<ChrysaetosBitConfig>(conf.clone()) {
@@ -67,6 +78,17 @@ pub mod json {
             }
         }
 
+        /// flattener of a validated tasking config
+        pub fn from_cfg(task_id: String, cfg: &ChrysaetosBitConfig) -> Self {
+            Self::new_cfg(
+                task_id,
+                cfg.sep.clone(),
+                cfg.max_depth,
+                cfg.fold.clone(),
+                cfg.ignore.clone(),
+            )
+        }
+
         pub fn new_cfg(
             task_id: String,
             sep: String,